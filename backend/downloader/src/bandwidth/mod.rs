@@ -0,0 +1,128 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket that limits throughput to a number of bytes per second.
+/// The bucket holds at most one second worth of tokens, consumers may take more tokens than are
+/// available and then wait until the debt is paid off. This keeps the average rate exact even
+/// for chunks bigger than the bucket.
+/// Clones share the same bucket, without a limit `acquire` returns immediately.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Option<Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_second: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            tokens: bytes_per_second as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_second as f64)
+            .min(self.bytes_per_second as f64);
+        self.last_refill = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        let limiter = Self::default();
+        limiter.set_limit(bytes_per_second);
+        limiter
+    }
+
+    /// None or 0 removes the limit, the change applies to everyone sharing the limiter right away
+    pub fn set_limit(&self, bytes_per_second: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        *bucket = match (bytes_per_second, bucket.take()) {
+            (None | Some(0), _) => None,
+            (Some(bytes_per_second), Some(mut current)) => {
+                current.refill();
+                current.bytes_per_second = bytes_per_second;
+                // Debt accumulated under the old limit is forgiven
+                current.tokens = current.tokens.clamp(0.0, bytes_per_second as f64);
+                Some(current)
+            }
+            (Some(bytes_per_second), None) => Some(Bucket::new(bytes_per_second)),
+        };
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.bucket
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|bucket| bucket.bytes_per_second)
+    }
+
+    /// Takes `bytes` tokens from the bucket and waits until they are covered
+    pub async fn acquire(&self, bytes: u64) {
+        if let Some(wait) = self.take(bytes) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn take(&self, bytes: u64) -> Option<Duration> {
+        let mut guard = self.bucket.lock().unwrap();
+        let bucket = guard.as_mut()?;
+        bucket.refill();
+        bucket.tokens -= bytes as f64;
+        (bucket.tokens < 0.0)
+            .then(|| Duration::from_secs_f64(-bucket.tokens / bucket.bytes_per_second as f64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn unlimited_limiter_does_not_wait() {
+        let limiter = RateLimiter::new(None);
+        let start = Instant::now();
+        limiter.acquire(u64::MAX).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(limiter.limit(), None);
+    }
+
+    #[test(tokio::test)]
+    async fn limiter_keeps_the_rate() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.clone().acquire(50_000).await;
+        }
+        // The first 100_000 bytes are covered by the full bucket
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(1400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    }
+
+    #[test(tokio::test)]
+    async fn limit_can_be_changed() {
+        let limiter = RateLimiter::new(Some(1000));
+        let wait = limiter.take(100_000).unwrap();
+        assert!(wait > Duration::from_secs(90));
+        limiter.set_limit(Some(10_000_000));
+        assert_eq!(limiter.limit(), Some(10_000_000));
+        let start = Instant::now();
+        limiter.acquire(100_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        limiter.set_limit(None);
+        assert_eq!(limiter.limit(), None);
+    }
+}
@@ -0,0 +1,583 @@
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, COOKIE};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+const FILE_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("File IO operation failed, error: '{0}'")]
+    Io(#[from] std::io::Error),
+    #[error("Credential store is locked")]
+    Locked,
+    #[error("Wrong password or corrupted credentials file")]
+    WrongPassword,
+    #[error("Invalid credentials file: {0}")]
+    InvalidFile(String),
+    #[error("Credential with id {0} does not exist")]
+    NotFound(Uuid),
+    #[error("Invalid credential: {0}")]
+    Invalid(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Secret part of an entry, never serialized outside of the encrypted file
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credential {
+    Basic {
+        username: String,
+        password: String,
+    },
+    Bearer {
+        token: String,
+    },
+    /// `name=value` pairs separated by `; ` like in a Cookie header
+    Cookies {
+        cookies: String,
+    },
+    /// Account at a premium hoster, sent as basic auth. Once `used` reaches `quota` (in bytes)
+    /// the account is skipped.
+    Premium {
+        username: String,
+        password: String,
+        #[serde(default)]
+        quota: Option<u64>,
+        #[serde(default)]
+        used: u64,
+    },
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}(<redacted>)", self.kind())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialKind {
+    Basic,
+    Bearer,
+    Cookies,
+    Premium,
+}
+
+impl Credential {
+    pub fn kind(&self) -> CredentialKind {
+        match self {
+            Credential::Basic { .. } => CredentialKind::Basic,
+            Credential::Bearer { .. } => CredentialKind::Bearer,
+            Credential::Cookies { .. } => CredentialKind::Cookies,
+            Credential::Premium { .. } => CredentialKind::Premium,
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        matches!(self, Credential::Premium { quota: Some(quota), used, .. } if used >= quota)
+    }
+
+    fn header(&self) -> Result<(HeaderName, HeaderValue)> {
+        let basic = |username: &str, password: &str| {
+            let encoded = base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", username, password));
+            format!("Basic {}", encoded)
+        };
+        let (name, value) = match self {
+            Credential::Basic { username, password }
+            | Credential::Premium {
+                username, password, ..
+            } => (AUTHORIZATION, basic(username, password)),
+            Credential::Bearer { token } => (AUTHORIZATION, format!("Bearer {}", token)),
+            Credential::Cookies { cookies } => (COOKIE, cookies.clone()),
+        };
+        let mut value = HeaderValue::from_str(&value).map_err(|e| Error::Invalid(e.to_string()))?;
+        value.set_sensitive(true);
+        Ok((name, value))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialEntry {
+    pub id: Uuid,
+    /// Applies to the host and its subdomains
+    pub host: String,
+    pub credential: Credential,
+}
+
+/// What may leave the store: an entry without its secrets
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CredentialInfo {
+    pub id: Uuid,
+    pub host: String,
+    pub kind: CredentialKind,
+    pub username: Option<String>,
+    pub quota: Option<u64>,
+    pub used: Option<u64>,
+}
+
+impl From<&CredentialEntry> for CredentialInfo {
+    fn from(entry: &CredentialEntry) -> Self {
+        let (username, quota, used) = match &entry.credential {
+            Credential::Basic { username, .. } => (Some(username.clone()), None, None),
+            Credential::Premium {
+                username,
+                quota,
+                used,
+                ..
+            } => (Some(username.clone()), *quota, Some(*used)),
+            Credential::Bearer { .. } | Credential::Cookies { .. } => (None, None, None),
+        };
+        CredentialInfo {
+            id: entry.id,
+            host: entry.host.clone(),
+            kind: entry.credential.kind(),
+            username,
+            quota,
+            used,
+        }
+    }
+}
+
+/// Layout of the credentials file, the entries are encrypted with XChaCha20-Poly1305 under a
+/// key derived from the password with Argon2id
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    version: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+struct Unlocked {
+    key: Key,
+    salt: [u8; SALT_LEN],
+    entries: Vec<CredentialEntry>,
+}
+
+/// Password protected store of credentials per host, kept in an encrypted file.
+/// The store starts out locked, nothing can be read or changed before `unlock`.
+/// Clones share the same store.
+#[derive(Clone)]
+pub struct CredentialStore {
+    path: PathBuf,
+    inner: Arc<RwLock<Option<Unlocked>>>,
+}
+
+impl std::fmt::Debug for CredentialStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CredentialStore")
+            .field("path", &self.path)
+            .field("unlocked", &self.is_unlocked())
+            .finish()
+    }
+}
+
+async fn derive_key(password: String, salt: [u8; SALT_LEN]) -> Result<Key> {
+    tokio::task::spawn_blocking(move || {
+        let mut key = Key::default();
+        argon2::Argon2::default()
+            .hash_password_into(password.as_bytes(), &salt, &mut key)
+            .map_err(|e| Error::Invalid(e.to_string()))?;
+        Ok(key)
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Result<[u8; N]> {
+    hex::decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidFile(format!("'{}' is not {} hex encoded bytes", value, N)))
+}
+
+fn host_matches(host: &str, entry_host: &str) -> bool {
+    host == entry_host
+        || host
+            .strip_suffix(entry_host)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+impl CredentialStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            inner: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.inner.read().unwrap().is_some()
+    }
+
+    /// Decrypts the credentials file with the password, the file is created if it doesn't exist
+    /// yet and is protected by this password from then on
+    pub async fn unlock(&self, password: &str) -> Result<()> {
+        if !tokio::fs::try_exists(&self.path).await? {
+            log::info!(
+                "Creating credentials file at {}",
+                self.path.to_string_lossy()
+            );
+            let mut salt = [0u8; SALT_LEN];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let key = derive_key(password.to_string(), salt).await?;
+            *self.inner.write().unwrap() = Some(Unlocked {
+                key,
+                salt,
+                entries: Vec::new(),
+            });
+            return self.save().await;
+        }
+        let file: EncryptedFile = serde_json::from_slice(&tokio::fs::read(&self.path).await?)
+            .map_err(|e| Error::InvalidFile(e.to_string()))?;
+        if file.version != FILE_VERSION {
+            return Err(Error::InvalidFile(format!(
+                "Unsupported version {}",
+                file.version
+            )));
+        }
+        let salt = decode_hex::<SALT_LEN>(&file.salt)?;
+        let nonce = decode_hex::<NONCE_LEN>(&file.nonce)?;
+        let ciphertext =
+            hex::decode(&file.ciphertext).map_err(|e| Error::InvalidFile(e.to_string()))?;
+        let key = derive_key(password.to_string(), salt).await?;
+        let plaintext = XChaCha20Poly1305::new(&key)
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| Error::WrongPassword)?;
+        let entries: Vec<CredentialEntry> =
+            serde_json::from_slice(&plaintext).map_err(|e| Error::InvalidFile(e.to_string()))?;
+        log::info!("Unlocked {} credentials", entries.len());
+        *self.inner.write().unwrap() = Some(Unlocked { key, salt, entries });
+        Ok(())
+    }
+
+    /// Forgets the key and the decrypted entries
+    pub fn lock(&self) {
+        *self.inner.write().unwrap() = None;
+    }
+
+    async fn save(&self) -> Result<()> {
+        let file = {
+            let guard = self.inner.read().unwrap();
+            let unlocked = guard.as_ref().ok_or(Error::Locked)?;
+            let plaintext =
+                serde_json::to_vec(&unlocked.entries).expect("Entries always serialize");
+            let mut nonce = [0u8; NONCE_LEN];
+            rand::thread_rng().fill_bytes(&mut nonce);
+            let ciphertext = XChaCha20Poly1305::new(&unlocked.key)
+                .encrypt(XNonce::from_slice(&nonce), plaintext.as_ref())
+                .map_err(|e| Error::Invalid(e.to_string()))?;
+            EncryptedFile {
+                version: FILE_VERSION,
+                salt: hex::encode(unlocked.salt),
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+            }
+        };
+        let bytes = serde_json::to_vec(&file).expect("Encrypted file always serializes");
+        // Write to a temporary file first, a crash mid-write must not destroy the credentials
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, bytes).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<CredentialInfo>> {
+        let guard = self.inner.read().unwrap();
+        let unlocked = guard.as_ref().ok_or(Error::Locked)?;
+        Ok(unlocked.entries.iter().map(CredentialInfo::from).collect())
+    }
+
+    pub async fn add(&self, host: &str, credential: Credential) -> Result<CredentialInfo> {
+        let host = host.trim().trim_start_matches('.').to_lowercase();
+        if host.is_empty() {
+            return Err(Error::Invalid("Host must not be empty".to_string()));
+        }
+        credential.header()?;
+        let entry = CredentialEntry {
+            id: Uuid::new_v4(),
+            host,
+            credential,
+        };
+        let info = CredentialInfo::from(&entry);
+        {
+            let mut guard = self.inner.write().unwrap();
+            let unlocked = guard.as_mut().ok_or(Error::Locked)?;
+            unlocked.entries.push(entry);
+        }
+        self.save().await?;
+        Ok(info)
+    }
+
+    pub async fn remove(&self, id: &Uuid) -> Result<()> {
+        {
+            let mut guard = self.inner.write().unwrap();
+            let unlocked = guard.as_mut().ok_or(Error::Locked)?;
+            let index = unlocked
+                .entries
+                .iter()
+                .position(|entry| entry.id == *id)
+                .ok_or(Error::NotFound(*id))?;
+            unlocked.entries.remove(index);
+        }
+        self.save().await
+    }
+
+    /// Counts downloaded bytes against the quota of a premium account
+    pub async fn record_usage(&self, id: &Uuid, bytes: u64) -> Result<()> {
+        {
+            let mut guard = self.inner.write().unwrap();
+            let unlocked = guard.as_mut().ok_or(Error::Locked)?;
+            let entry = unlocked
+                .entries
+                .iter_mut()
+                .find(|entry| entry.id == *id)
+                .ok_or(Error::NotFound(*id))?;
+            let Credential::Premium { used, .. } = &mut entry.credential else {
+                return Ok(());
+            };
+            *used += bytes;
+        }
+        self.save().await
+    }
+
+    /// Username and password of the most specific matching basic or premium credential, for
+    /// resolvers that log in on their own. Exhausted premium accounts are skipped.
+    pub fn account(&self, url: &Url) -> Option<(String, String)> {
+        let host = url.host_str()?.to_lowercase();
+        let guard = self.inner.read().unwrap();
+        guard
+            .as_ref()?
+            .entries
+            .iter()
+            .filter(|entry| host_matches(&host, &entry.host))
+            .filter(|entry| !entry.credential.is_exhausted())
+            .filter_map(|entry| match &entry.credential {
+                Credential::Basic { username, password }
+                | Credential::Premium {
+                    username, password, ..
+                } => Some((entry.host.len(), username, password)),
+                _ => None,
+            })
+            .max_by_key(|(len, ..)| *len)
+            .map(|(_, username, password)| (username.clone(), password.clone()))
+    }
+
+    /// Puts the credential of the most specific matching host into `headers` and returns the id
+    /// of the entry. Credentials injected by an earlier call are removed first, so a deleted or
+    /// exhausted entry or a locked store leaves none behind. Headers set explicitly (not marked
+    /// sensitive) are left alone, exhausted premium accounts are skipped.
+    pub fn apply(&self, url: &Url, headers: &mut HeaderMap) -> Option<Uuid> {
+        strip_injected(headers);
+        let host = url.host_str()?.to_lowercase();
+        let guard = self.inner.read().unwrap();
+        let entry = guard
+            .as_ref()?
+            .entries
+            .iter()
+            .filter(|entry| host_matches(&host, &entry.host))
+            .filter(|entry| !entry.credential.is_exhausted())
+            .max_by_key(|entry| entry.host.len())?;
+        let (name, value) = match entry.credential.header() {
+            Ok(header) => header,
+            Err(e) => {
+                log::error!("Skipping credential {}: {}", entry.id, e);
+                return None;
+            }
+        };
+        if headers
+            .get(&name)
+            .is_some_and(|value| !value.is_sensitive())
+        {
+            log::info!("Keeping explicit {} header for {}", name, host);
+            return None;
+        }
+        headers.insert(name, value);
+        Some(entry.id)
+    }
+}
+
+/// Removes the sensitive values `CredentialStore::apply` injected, explicit headers are kept
+fn strip_injected(headers: &mut HeaderMap) {
+    let injected: Vec<HeaderName> = headers
+        .iter()
+        .filter(|(_, value)| value.is_sensitive())
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in injected {
+        let explicit: Vec<HeaderValue> = headers
+            .get_all(&name)
+            .iter()
+            .filter(|value| !value.is_sensitive())
+            .cloned()
+            .collect();
+        headers.remove(&name);
+        for value in explicit {
+            headers.append(name.clone(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::httpdownload::download::config::HttpDownloadConfig;
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use reqwest::header::USER_AGENT;
+    use tempfile::TempDir;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn credentials_are_stored_encrypted() -> TestResult<()> {
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("credentials.enc");
+        let store = CredentialStore::new(path.clone());
+        assert!(matches!(store.list(), Err(Error::Locked)));
+        store.unlock("hunter2").await?;
+        let info = store
+            .add(
+                "Example.com",
+                Credential::Basic {
+                    username: "user".to_string(),
+                    password: "s3cr3t-password".to_string(),
+                },
+            )
+            .await?;
+        assert_eq!(info.host, "example.com");
+        assert_eq!(info.username.as_deref(), Some("user"));
+        let file = tokio::fs::read_to_string(&path).await?;
+        assert!(!file.contains("s3cr3t-password"));
+        assert!(!format!(
+            "{:?}",
+            store.inner.read().unwrap().as_ref().unwrap().entries
+        )
+        .contains("s3cr3t-password"));
+
+        let reopened = CredentialStore::new(path.clone());
+        assert!(matches!(
+            reopened.unlock("wrong").await,
+            Err(Error::WrongPassword)
+        ));
+        reopened.unlock("hunter2").await?;
+        assert_eq!(reopened.list()?, vec![info.clone()]);
+        reopened.remove(&info.id).await?;
+        assert!(reopened.list()?.is_empty());
+        reopened.lock();
+        assert!(!reopened.is_unlocked());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn matching_credentials_are_applied() -> TestResult<()> {
+        let tmp_dir = TempDir::new()?;
+        let store = CredentialStore::new(tmp_dir.path().join("credentials.enc"));
+        let url = Url::parse("https://dl.example.com/file.bin")?;
+        let mut headers = HeaderMap::new();
+        assert_eq!(store.apply(&url, &mut headers), None);
+        store.unlock("hunter2").await?;
+        store
+            .add(
+                "example.com",
+                Credential::Bearer {
+                    token: "t0k3n".to_string(),
+                },
+            )
+            .await?;
+        let premium = store
+            .add(
+                "dl.example.com",
+                Credential::Premium {
+                    username: "user".to_string(),
+                    password: "pass".to_string(),
+                    quota: Some(100),
+                    used: 0,
+                },
+            )
+            .await?;
+        assert_eq!(store.apply(&url, &mut headers), Some(premium.id));
+        assert_eq!(headers[AUTHORIZATION], "Basic dXNlcjpwYXNz");
+        assert!(headers[AUTHORIZATION].is_sensitive());
+
+        // Exhausted premium accounts fall back to the less specific entry
+        store.record_usage(&premium.id, 100).await?;
+        store.apply(&url, &mut headers);
+        assert_eq!(headers[AUTHORIZATION], "Bearer t0k3n");
+        // Injected credentials don't end up in persisted configs
+        let mut config = HttpDownloadConfig::default();
+        store.apply(&url, &mut config.headers);
+        assert!(!serde_json::to_string(&config)?.contains("t0k3n"));
+
+        let mut explicit = HeaderMap::new();
+        explicit.insert(AUTHORIZATION, HeaderValue::from_static("Bearer mine"));
+        assert_eq!(store.apply(&url, &mut explicit), None);
+        assert_eq!(explicit[AUTHORIZATION], "Bearer mine");
+        let other = Url::parse("https://notexample.com/file.bin")?;
+        assert_eq!(store.apply(&other, &mut HeaderMap::new()), None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn stale_credentials_are_removed_from_headers() -> TestResult<()> {
+        let tmp_dir = TempDir::new()?;
+        let store = CredentialStore::new(tmp_dir.path().join("credentials.enc"));
+        let url = Url::parse("https://example.com/file.bin")?;
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("ludownloader"));
+        store.unlock("hunter2").await?;
+        let basic = Credential::Basic {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        };
+
+        // deleted
+        let entry = store.add("example.com", basic.clone()).await?;
+        assert_eq!(store.apply(&url, &mut headers), Some(entry.id));
+        store.remove(&entry.id).await?;
+        assert_eq!(store.apply(&url, &mut headers), None);
+        assert!(!headers.contains_key(AUTHORIZATION));
+
+        // locked
+        let entry = store.add("example.com", basic).await?;
+        assert_eq!(store.apply(&url, &mut headers), Some(entry.id));
+        store.lock();
+        assert_eq!(store.apply(&url, &mut headers), None);
+        assert!(!headers.contains_key(AUTHORIZATION));
+
+        // exhausted without a fallback
+        store.unlock("hunter2").await?;
+        store.remove(&entry.id).await?;
+        let premium = store
+            .add(
+                "example.com",
+                Credential::Premium {
+                    username: "user".to_string(),
+                    password: "pass".to_string(),
+                    quota: Some(100),
+                    used: 0,
+                },
+            )
+            .await?;
+        assert_eq!(store.apply(&url, &mut headers), Some(premium.id));
+        store.record_usage(&premium.id, 100).await?;
+        assert_eq!(store.apply(&url, &mut headers), None);
+        assert!(!headers.contains_key(AUTHORIZATION));
+        assert_eq!(headers[USER_AGENT], "ludownloader");
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+pub mod token;
+
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use crate::credentials::CredentialStore;
+use crate::httpdownload::download::{self, config::HttpDownloadConfig, HttpDownload};
+
+use self::token::{TokenHoster, TokenHosterConfig};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Request error: '{0}'")]
+    Request(#[from] reqwest::Error),
+    #[error(transparent)]
+    Download(#[from] download::Error),
+    #[error("Login at '{0}' failed: {1}")]
+    Login(String, String),
+    #[error("Couldn't resolve '{0}': {1}")]
+    Resolve(Url, String),
+    #[error("Invalid hoster config: {0}")]
+    InvalidConfig(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Turns the page of a file hoster into the direct downloads behind it.
+/// Implementations take care of logging in and exchanging tokens, whatever is needed to fetch
+/// the files (e.g. session cookies) goes into the headers of the created downloads.
+#[async_trait]
+pub trait HosterResolver: Send + Sync {
+    /// Hosts served by the resolver, subdomains are matched as well
+    fn hosts(&self) -> Vec<String>;
+
+    /// Creates the direct downloads for `page` in `directory`, a folder page can yield several.
+    /// `config` is the config of the page download and serves as base for every created download.
+    async fn resolve(
+        &self,
+        page: &Url,
+        directory: &Path,
+        config: &HttpDownloadConfig,
+        client: &Client,
+    ) -> Result<Vec<HttpDownload>>;
+}
+
+/// Resolvers by host, consulted by `DownloadManager::add`.
+/// Clones share the same registry.
+#[derive(Clone, Default)]
+pub struct HosterRegistry {
+    resolvers: Arc<RwLock<HashMap<String, Arc<dyn HosterResolver>>>>,
+}
+
+impl std::fmt::Debug for HosterRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hosts: Vec<String> = self.resolvers.read().unwrap().keys().cloned().collect();
+        f.debug_struct("HosterRegistry")
+            .field("hosts", &hosts)
+            .finish()
+    }
+}
+
+impl HosterRegistry {
+    /// Registers the resolver for all of its hosts, replacing resolvers registered before
+    pub fn register(&self, resolver: impl HosterResolver + 'static) {
+        let resolver: Arc<dyn HosterResolver> = Arc::new(resolver);
+        let mut resolvers = self.resolvers.write().unwrap();
+        for host in resolver.hosts() {
+            log::info!("Registering hoster resolver for {}", host);
+            resolvers.insert(host.to_lowercase(), resolver.clone());
+        }
+    }
+
+    pub fn unregister(&self, host: &str) {
+        self.resolvers.write().unwrap().remove(&host.to_lowercase());
+    }
+
+    /// Replaces all resolvers with TokenHosters logging in with the accounts of `credentials`.
+    /// Nothing is changed if one of the configs is invalid.
+    pub fn configure(
+        &self,
+        configs: &[TokenHosterConfig],
+        credentials: &CredentialStore,
+    ) -> Result<()> {
+        let hosters = configs
+            .iter()
+            .map(|config| {
+                if config.hosts.is_empty() {
+                    return Err(Error::InvalidConfig(format!(
+                        "hoster at {} has no hosts",
+                        config.api
+                    )));
+                }
+                let api = Url::parse(&config.api)
+                    .map_err(|e| Error::InvalidConfig(format!("'{}': {}", config.api, e)))?;
+                if api.cannot_be_a_base() {
+                    return Err(Error::InvalidConfig(format!(
+                        "API url '{}' has no path",
+                        config.api
+                    )));
+                }
+                Ok(TokenHoster::with_credentials(
+                    config.hosts.clone(),
+                    api,
+                    credentials.clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.resolvers.write().unwrap().clear();
+        for hoster in hosters {
+            self.register(hoster);
+        }
+        Ok(())
+    }
+
+    /// Resolver for the host of `url`, `www.host.com` is served by a resolver of `host.com`
+    pub fn resolver_for(&self, url: &Url) -> Option<Arc<dyn HosterResolver>> {
+        let host = url.host_str()?.to_lowercase();
+        let resolvers = self.resolvers.read().unwrap();
+        let mut candidate = host.as_str();
+        loop {
+            if let Some(resolver) = resolvers.get(candidate) {
+                return Some(resolver.clone());
+            }
+            candidate = candidate.split_once('.')?.1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    struct NoopResolver;
+
+    #[async_trait]
+    impl HosterResolver for NoopResolver {
+        fn hosts(&self) -> Vec<String> {
+            vec!["Hoster.example".to_string()]
+        }
+
+        async fn resolve(
+            &self,
+            _page: &Url,
+            _directory: &Path,
+            _config: &HttpDownloadConfig,
+            _client: &Client,
+        ) -> Result<Vec<HttpDownload>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn resolvers_are_found_by_host() {
+        let registry = HosterRegistry::default();
+        registry.register(NoopResolver);
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert!(registry
+            .resolver_for(&url("https://hoster.example/file/1"))
+            .is_some());
+        assert!(registry
+            .resolver_for(&url("https://www.hoster.example/file/1"))
+            .is_some());
+        assert!(registry
+            .resolver_for(&url("https://otherhoster.example/file/1"))
+            .is_none());
+        assert!(registry
+            .resolver_for(&url("https://example/file/1"))
+            .is_none());
+        registry.unregister("hoster.example");
+        assert!(registry
+            .resolver_for(&url("https://hoster.example/file/1"))
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let registry = HosterRegistry::default();
+        registry.register(NoopResolver);
+        let credentials = CredentialStore::new("credentials.enc".into());
+        let config = |hosts: &[&str], api: &str| TokenHosterConfig {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            api: api.to_string(),
+        };
+        for invalid in [
+            config(&[], "https://hoster.example/api"),
+            config(&["hoster.example"], "not a url"),
+            config(&["hoster.example"], "mailto:api@hoster.example"),
+        ] {
+            assert!(matches!(
+                registry.configure(&[invalid], &credentials),
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+        let page = Url::parse("https://hoster.example/file/1").unwrap();
+        assert!(registry.resolver_for(&page).is_some());
+    }
+}
@@ -0,0 +1,318 @@
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, COOKIE, SET_COOKIE};
+use reqwest::{Client, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::sync::Mutex;
+
+use super::{Error, HosterResolver, Result};
+use crate::credentials::CredentialStore;
+use crate::httpdownload::download::{config::HttpDownloadConfig, HttpDownload};
+
+#[derive(Debug, Serialize)]
+struct LoginRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Link {
+    url: String,
+    #[serde(default)]
+    filename: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LinksResponse {
+    links: Vec<Link>,
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    token: String,
+    /// `name=value` pairs of the cookies set on login, joined for a Cookie header
+    cookies: Option<String>,
+}
+
+/// A TokenHoster as configured in the settings, it logs in with the basic or premium credential
+/// stored for the host of the page
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenHosterConfig {
+    pub hosts: Vec<String>,
+    /// Base URL of the JSON API
+    pub api: String,
+}
+
+/// Where the username and password for the login come from
+#[derive(Debug)]
+enum Account {
+    Fixed { username: String, password: String },
+    Stored(CredentialStore),
+}
+
+/// Reference resolver for hosters with a JSON API and no captchas:
+/// - `POST {api}/login` with `{"username", "password"}` answers `{"token"}` and sets the session
+///   cookies
+/// - `GET {api}/links?url={page}` with the token as bearer answers
+///   `{"links": [{"url", "filename"}]}`
+///
+/// The session is reused until the hoster rejects the token, the direct downloads carry the
+/// session cookies.
+#[derive(Debug)]
+pub struct TokenHoster {
+    hosts: Vec<String>,
+    api: Url,
+    account: Account,
+    session: Mutex<Option<Session>>,
+}
+
+impl TokenHoster {
+    pub fn new(hosts: Vec<String>, api: Url, username: String, password: String) -> Self {
+        Self::with_account(hosts, api, Account::Fixed { username, password })
+    }
+
+    /// Logs in with the account the store holds for the host of the page, looked up on every
+    /// login so credentials added or unlocked later are picked up
+    pub fn with_credentials(hosts: Vec<String>, api: Url, credentials: CredentialStore) -> Self {
+        Self::with_account(hosts, api, Account::Stored(credentials))
+    }
+
+    fn with_account(hosts: Vec<String>, api: Url, account: Account) -> Self {
+        Self {
+            hosts,
+            api,
+            account,
+            session: Mutex::new(None),
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> Result<Url> {
+        let mut url = self.api.clone();
+        url.path_segments_mut()
+            .map_err(|_| Error::InvalidConfig(format!("API url '{}' has no path", self.api)))?
+            .pop_if_empty()
+            .push(path);
+        Ok(url)
+    }
+
+    async fn login(&self, page: &Url, client: &Client) -> Result<Session> {
+        let (username, password) = match &self.account {
+            Account::Fixed { username, password } => (username.clone(), password.clone()),
+            Account::Stored(credentials) => credentials.account(page).ok_or_else(|| {
+                Error::Login(
+                    self.api.to_string(),
+                    format!(
+                        "no account stored for {}",
+                        page.host_str().unwrap_or_default()
+                    ),
+                )
+            })?,
+        };
+        log::info!("Logging in at {} as {}", self.api, username);
+        let body = serde_json::to_vec(&LoginRequest {
+            username: &username,
+            password: &password,
+        })
+        .expect("Login request serializes");
+        let resp = client
+            .post(self.endpoint("login")?)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(Error::Login(
+                self.api.to_string(),
+                format!("status {}", resp.status()),
+            ));
+        }
+        let cookies: Vec<&str> = resp
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|cookie| cookie.split(';').next())
+            .map(str::trim)
+            .collect();
+        let cookies = (!cookies.is_empty()).then(|| cookies.join("; "));
+        let login: LoginResponse = serde_json::from_slice(&resp.bytes().await?)
+            .map_err(|e| Error::Login(self.api.to_string(), e.to_string()))?;
+        Ok(Session {
+            token: login.token,
+            cookies,
+        })
+    }
+
+    async fn request_links(
+        &self,
+        page: &Url,
+        session: &Session,
+        client: &Client,
+    ) -> Result<Response> {
+        let mut url = self.endpoint("links")?;
+        url.query_pairs_mut().append_pair("url", page.as_str());
+        let mut request = client
+            .get(url)
+            .header(AUTHORIZATION, format!("Bearer {}", session.token));
+        if let Some(cookies) = session.cookies.as_ref() {
+            request = request.header(COOKIE, cookies);
+        }
+        Ok(request.send().await?)
+    }
+
+    /// Asks for the direct links of the page, logs in again once if the session expired
+    async fn links(&self, page: &Url, client: &Client) -> Result<(Vec<Link>, Session)> {
+        let mut guard = self.session.lock().await;
+        let mut fresh = false;
+        let session = match guard.as_ref() {
+            Some(session) => session.clone(),
+            None => {
+                fresh = true;
+                self.login(page, client).await?
+            }
+        };
+        let mut resp = self.request_links(page, &session, client).await?;
+        let session = if resp.status() == StatusCode::UNAUTHORIZED && !fresh {
+            log::info!("Session at {} expired, logging in again", self.api);
+            let session = self.login(page, client).await?;
+            resp = self.request_links(page, &session, client).await?;
+            session
+        } else {
+            session
+        };
+        let status = resp.status();
+        if !status.is_success() {
+            *guard = None;
+            return Err(Error::Resolve(page.clone(), format!("status {}", status)));
+        }
+        *guard = Some(session.clone());
+        let links: LinksResponse = serde_json::from_slice(&resp.bytes().await?)
+            .map_err(|e| Error::Resolve(page.clone(), e.to_string()))?;
+        Ok((links.links, session))
+    }
+}
+
+#[async_trait]
+impl HosterResolver for TokenHoster {
+    fn hosts(&self) -> Vec<String> {
+        self.hosts.clone()
+    }
+
+    async fn resolve(
+        &self,
+        page: &Url,
+        directory: &Path,
+        config: &HttpDownloadConfig,
+        client: &Client,
+    ) -> Result<Vec<HttpDownload>> {
+        let (links, session) = self.links(page, client).await?;
+        log::info!("Resolved {} into {} links", page, links.len());
+        let mut config = config.clone();
+        if let Some(cookies) = session.cookies.as_ref() {
+            let value = HeaderValue::from_str(cookies)
+                .map_err(|e| Error::Resolve(page.clone(), e.to_string()))?;
+            config.headers.insert(COOKIE, value);
+        }
+        let mut downloads = Vec::with_capacity(links.len());
+        for link in links {
+            let url = page
+                .join(&link.url)
+                .map_err(|e| Error::Resolve(page.clone(), e.to_string()))?;
+            let download = HttpDownload::create(
+                url,
+                directory.to_owned(),
+                link.filename,
+                client.clone(),
+                Some(config.clone()),
+            )
+            .await?;
+            downloads.push(download);
+        }
+        Ok(downloads)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::mock_hoster::MockHoster;
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+    use test_log::test;
+
+    fn hoster(mock: &MockHoster, password: &str) -> TokenHoster {
+        TokenHoster::new(
+            vec![mock.host()],
+            mock.api_url(),
+            "user".to_string(),
+            password.to_string(),
+        )
+    }
+
+    #[test(tokio::test)]
+    async fn page_is_resolved_into_direct_downloads() -> TestResult<()> {
+        let mock = MockHoster::spawn();
+        let hoster = hoster(&mock, "secret");
+        let tmp_dir = TempDir::new()?;
+        let downloads = hoster
+            .resolve(
+                &mock.page_url("abc"),
+                tmp_dir.path(),
+                &HttpDownloadConfig::default(),
+                &Client::new(),
+            )
+            .await?;
+        let filenames: Vec<&str> = downloads.iter().map(|d| d.filename.as_str()).collect();
+        assert_eq!(filenames, vec!["abc.part1.bin", "abc.part2.bin"]);
+        assert_eq!(downloads[0].content_length, Some(mock.payload.len() as u64));
+        assert_eq!(
+            downloads[0].config.headers.get(COOKIE).unwrap(),
+            "session=s3ss10n"
+        );
+
+        // The session is reused, an expired token leads to one more login
+        hoster
+            .resolve(
+                &mock.page_url("def"),
+                tmp_dir.path(),
+                &HttpDownloadConfig::default(),
+                &Client::new(),
+            )
+            .await?;
+        assert_eq!(mock.logins(), 1);
+        mock.expire_sessions();
+        hoster
+            .resolve(
+                &mock.page_url("ghi"),
+                tmp_dir.path(),
+                &HttpDownloadConfig::default(),
+                &Client::new(),
+            )
+            .await?;
+        assert_eq!(mock.logins(), 2);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn wrong_credentials_fail_to_login() -> TestResult<()> {
+        let mock = MockHoster::spawn();
+        let hoster = hoster(&mock, "wrong");
+        let tmp_dir = TempDir::new()?;
+        let result = hoster
+            .resolve(
+                &mock.page_url("abc"),
+                tmp_dir.path(),
+                &HttpDownloadConfig::default(),
+                &Client::new(),
+            )
+            .await;
+        assert!(matches!(result, Err(Error::Login(..))));
+        Ok(())
+    }
+}
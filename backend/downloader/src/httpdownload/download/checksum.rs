@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::{Error, Result};
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// Length of a hex encoded digest
+    fn hex_len(&self) -> usize {
+        match self {
+            ChecksumAlgorithm::Md5 => 32,
+            ChecksumAlgorithm::Sha1 => 40,
+            ChecksumAlgorithm::Sha256 | ChecksumAlgorithm::Blake3 => 64,
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace('-', "").as_str() {
+            "md5" => Ok(ChecksumAlgorithm::Md5),
+            "sha1" => Ok(ChecksumAlgorithm::Sha1),
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            _ => Err(Error::InvalidChecksum(format!(
+                "Unsupported checksum algorithm: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Expected digest of a downloaded file, stored as lowercase hex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: String,
+}
+
+impl Checksum {
+    /// Normalizes the digest to lowercase hex and checks that it fits the algorithm
+    pub fn new(algorithm: ChecksumAlgorithm, digest: &str) -> Result<Self> {
+        let digest = digest.trim().to_lowercase();
+        if digest.len() != algorithm.hex_len() || hex::decode(&digest).is_err() {
+            return Err(Error::InvalidChecksum(format!(
+                "'{}' is not a valid {:?} digest",
+                digest, algorithm
+            )));
+        }
+        Ok(Self { algorithm, digest })
+    }
+
+    /// Hashes the file on the blocking thread pool and compares the result to the expected digest
+    pub async fn verify(&self, path: &Path) -> Result<()> {
+        let actual = compute(self.algorithm, path.to_owned()).await?;
+        if actual != self.digest {
+            return Err(Error::ChecksumMismatch {
+                expected: self.digest.clone(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+enum Hasher {
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Md5 => Hasher::Md5(md5::Md5::new()),
+            ChecksumAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            ChecksumAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Md5(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Sha1(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Computes the lowercase hex digest of the file at `path`
+pub async fn compute(algorithm: ChecksumAlgorithm, path: PathBuf) -> Result<String> {
+    let digest = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Hasher::new(algorithm);
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(hasher.finalize())
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn known_digests_test() -> TestResult<()> {
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("abc.txt");
+        tokio::fs::write(&path, "abc").await?;
+        let expected = [
+            (ChecksumAlgorithm::Md5, "900150983cd24fb0d6963f7d28e17f72"),
+            (
+                ChecksumAlgorithm::Sha1,
+                "a9993e364706816aba3e25717850c26c9cd0d89d",
+            ),
+            (
+                ChecksumAlgorithm::Sha256,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                ChecksumAlgorithm::Blake3,
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+        ];
+        for (algorithm, digest) in expected {
+            assert_eq!(compute(algorithm, path.clone()).await?, digest);
+            Checksum::new(algorithm, &digest.to_uppercase())?
+                .verify(&path)
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_checksums_test() {
+        assert!(Checksum::new(ChecksumAlgorithm::Md5, "abc").is_err());
+        assert!(Checksum::new(ChecksumAlgorithm::Md5, &"z".repeat(32)).is_err());
+        assert!("crc32".parse::<ChecksumAlgorithm>().is_err());
+        assert_eq!(
+            "SHA-256".parse::<ChecksumAlgorithm>().unwrap(),
+            ChecksumAlgorithm::Sha256
+        );
+    }
+}
@@ -1,14 +1,36 @@
 use reqwest::header::{self, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
+use super::checksum::Checksum;
+use super::disk::Preallocation;
+use super::filename::CollisionPolicy;
+use super::retry::RetryPolicy;
+
 pub const DEFAULT_USER_AGENT: &str = "ludownloader";
 pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
 
-#[derive(Debug, Clone)]
+/// Fields missing from a serialized config fall back to their default value, this keeps
+/// configs persisted by older versions loadable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct HttpDownloadConfig {
     pub timeout: Duration,
+    #[serde(with = "header_map")]
     pub headers: HeaderMap,
     pub chunk_size: usize,
+    /// Number of byte range segments downloaded in parallel, values above 1 turn the download
+    /// into a hyperdownload if the server supports byte ranges.
+    pub segments: usize,
+    /// Expected digest of the finished file, verified before the download is marked complete
+    pub checksum: Option<Checksum>,
+    pub retry: RetryPolicy,
+    /// Maximum bytes per second of this download, None for no limit
+    pub bandwidth_limit: Option<u64>,
+    /// Applied when the download is created and its target file already exists
+    pub on_collision: CollisionPolicy,
+    /// Only downloads of known size from servers that support byte ranges are preallocated
+    pub preallocation: Preallocation,
 }
 
 impl Default for HttpDownloadConfig {
@@ -17,6 +39,12 @@ impl Default for HttpDownloadConfig {
             timeout: Duration::from_secs(60),
             headers: HeaderMap::new(),
             chunk_size: DEFAULT_CHUNK_SIZE,
+            segments: 1,
+            checksum: None,
+            retry: RetryPolicy::default(),
+            bandwidth_limit: None,
+            on_collision: CollisionPolicy::default(),
+            preallocation: Preallocation::default(),
         };
         config.headers.insert(
             header::USER_AGENT,
@@ -25,3 +53,33 @@ impl Default for HttpDownloadConfig {
         config
     }
 }
+
+/// (De)serializes a HeaderMap as a list of `(name, value)` pairs, keeping repeated headers.
+/// Sensitive values (injected credentials) are left out, they are applied again on every run.
+mod header_map {
+    use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+    use serde::{de::Error, ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(headers: &HeaderMap, serializer: S) -> Result<S::Ok, S::Error> {
+        let headers: Vec<_> = headers
+            .iter()
+            .filter(|(_, value)| !value.is_sensitive())
+            .collect();
+        let mut seq = serializer.serialize_seq(Some(headers.len()))?;
+        for (name, value) in headers {
+            seq.serialize_element(&(name.as_str(), String::from_utf8_lossy(value.as_bytes())))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderMap, D::Error> {
+        let pairs = Vec::<(String, String)>::deserialize(deserializer)?;
+        let mut headers = HeaderMap::with_capacity(pairs.len());
+        for (name, value) in pairs {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(D::Error::custom)?;
+            let value = HeaderValue::from_str(&value).map_err(D::Error::custom)?;
+            headers.append(name, value);
+        }
+        Ok(headers)
+    }
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs::File;
+
+use super::{Error, Result};
+
+/// How the part file of a download of known size is allocated before the transfer starts.
+/// Preallocated downloads are written out of order, see HttpDownload::is_segmented.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preallocation {
+    /// The file grows while it's written
+    #[default]
+    Disabled,
+    /// The file is created at its final size without reserving blocks for it
+    Sparse,
+    /// Blocks for the whole file are reserved upfront, falls back to Sparse where fallocate
+    /// isn't supported
+    Full,
+}
+
+/// Bytes available to unprivileged users on the volume of `path`
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is only read after statvfs succeeded
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Bytes the file actually occupies on disk, holes of sparse files don't count
+pub async fn allocated_bytes(path: &Path) -> u64 {
+    match tokio::fs::metadata(path).await {
+        #[cfg(unix)]
+        Ok(metadata) => {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.blocks() * 512).min(metadata.len())
+        }
+        #[cfg(not(unix))]
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+/// Fails with Error::InsufficientSpace if fewer than `required` bytes are free in `directory`.
+/// The check is skipped if the free space can't be determined.
+pub fn ensure_space(directory: &Path, required: u64) -> Result<()> {
+    match available_space(directory) {
+        Ok(available) if available < required => {
+            log::error!(
+                "Not enough space in {:?}, required: {}, available: {}",
+                directory,
+                required,
+                available
+            );
+            Err(Error::InsufficientSpace {
+                required,
+                available,
+            })
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::warn!("Couldn't determine free space in {:?}: {}", directory, e);
+            Ok(())
+        }
+    }
+}
+
+/// Grows the file to `len` bytes according to the mode, Disabled leaves it as it is
+pub async fn preallocate(file: &File, len: u64, mode: Preallocation) -> Result<()> {
+    match mode {
+        Preallocation::Disabled => Ok(()),
+        Preallocation::Sparse => Ok(file.set_len(len).await?),
+        Preallocation::Full => {
+            if let Err(e) = allocate(file, len).await {
+                log::warn!(
+                    "Couldn't reserve {} bytes, falling back to sparse: {}",
+                    len,
+                    e
+                );
+                file.set_len(len).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn allocate(file: &File, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = libc::off_t::try_from(len)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // The task owns its handle, a cancelled caller can't close the fd while fallocate runs
+    let file = file.try_clone().await?.into_std().await;
+    tokio::task::spawn_blocking(move || {
+        // SAFETY: `fd` belongs to `file`, which is open until the closure returns
+        match unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    })
+    .await?
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn allocate(_file: &File, _len: u64) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn preallocation_test() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let len = 4 * 1024 * 1024;
+        for mode in [Preallocation::Sparse, Preallocation::Full] {
+            let path = tmp_dir.path().join(format!("{:?}", mode));
+            let file = File::create(&path).await?;
+            preallocate(&file, len, mode).await?;
+            assert_eq!(file.metadata().await?.len(), len);
+        }
+        #[cfg(target_os = "linux")]
+        assert_eq!(allocated_bytes(&tmp_dir.path().join("Full")).await, len);
+        Ok(())
+    }
+
+    #[test]
+    fn free_space_is_checked_test() {
+        let tmp_dir = TempDir::new().unwrap();
+        assert!(ensure_space(tmp_dir.path(), 0).is_ok());
+        if available_space(tmp_dir.path()).is_ok() {
+            assert!(matches!(
+                ensure_space(tmp_dir.path(), u64::MAX),
+                Err(Error::InsufficientSpace { .. })
+            ));
+        }
+    }
+}
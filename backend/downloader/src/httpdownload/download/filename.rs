@@ -0,0 +1,464 @@
+use reqwest::header::{HeaderMap, CONTENT_DISPOSITION, CONTENT_TYPE};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::util::parse_filename;
+
+use super::{part_path, Error, Result};
+
+/// Name of downloads that give no hint about their filename
+pub const DEFAULT_FILENAME: &str = "download";
+/// Longest filename (in bytes) of a download. Most filesystems accept 255 bytes, which leaves
+/// room for the longest suffix of the files next to the target, the temporary segments file.
+pub const MAX_FILENAME_LEN: usize = 255 - ".ludl.segments.tmp".len();
+/// Device names Windows refuses as filenames, regardless of the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const MIME_EXTENSIONS: [(&str, &str); 30] = [
+    ("application/gzip", "gz"),
+    ("application/json", "json"),
+    ("application/octet-stream", "bin"),
+    ("application/pdf", "pdf"),
+    ("application/vnd.debian.binary-package", "deb"),
+    ("application/vnd.rar", "rar"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/x-bittorrent", "torrent"),
+    ("application/x-bzip2", "bz2"),
+    ("application/x-iso9660-image", "iso"),
+    ("application/x-rar-compressed", "rar"),
+    ("application/x-tar", "tar"),
+    ("application/x-xz", "xz"),
+    ("application/xml", "xml"),
+    ("application/zip", "zip"),
+    ("audio/flac", "flac"),
+    ("audio/mpeg", "mp3"),
+    ("audio/ogg", "ogg"),
+    ("image/gif", "gif"),
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/svg+xml", "svg"),
+    ("image/webp", "webp"),
+    ("text/csv", "csv"),
+    ("text/html", "html"),
+    ("text/plain", "txt"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+    ("video/x-matroska", "mkv"),
+    ("video/x-msvideo", "avi"),
+];
+
+/// What happens when the target file of a new download already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    /// Appends ` (1)`, ` (2)`, ... to the name until it's free
+    #[default]
+    Rename,
+    /// The existing file is replaced once the download completes
+    Overwrite,
+    /// The download isn't created, fails with Error::FileExists
+    Skip,
+}
+
+/// Derives the filename of a response from its Content-Disposition, the final URL after
+/// redirects or its Content-Type, in that order. The name is always safe to create.
+pub fn detect(url: &Url, headers: &HeaderMap) -> String {
+    let disposition = headers
+        .get(CONTENT_DISPOSITION)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+        .and_then(|value| from_content_disposition(&value))
+        .and_then(|name| sanitize(&name));
+    if let Some(name) = disposition {
+        return name;
+    }
+    let extension = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(extension_for_mime);
+    let name = parse_filename(url)
+        .map(|segment| String::from_utf8_lossy(&percent_decode(segment)).to_string())
+        .and_then(|name| sanitize(&name));
+    match (name, extension) {
+        (Some(name), _) if name.contains('.') => name,
+        (Some(name), Some(extension)) => format!("{}.{}", name, extension),
+        (Some(name), None) => name,
+        (None, Some(extension)) => format!("{}.{}", DEFAULT_FILENAME, extension),
+        (None, None) => DEFAULT_FILENAME.to_string(),
+    }
+}
+
+/// Filename of a Content-Disposition header, `filename*` (RFC 5987) takes precedence over
+/// `filename`
+pub fn from_content_disposition(value: &str) -> Option<String> {
+    let mut filename = None;
+    // The first parameter is the disposition type, e.g. `attachment`
+    for param in split_params(value).into_iter().skip(1) {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                if let Some(name) = decode_ext_value(value.trim()) {
+                    return Some(name);
+                }
+            }
+            "filename" => filename = Some(unquote(value.trim())),
+            _ => {}
+        }
+    }
+    filename.filter(|name| !name.is_empty())
+}
+
+/// Extension for the MIME type of a Content-Type header, parameters are ignored
+pub fn extension_for_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    MIME_EXTENSIONS
+        .iter()
+        .find(|(known, _)| *known == mime)
+        .map(|(_, extension)| *extension)
+}
+
+/// Turns a name sent by a server into a plain filename: directories are stripped, characters
+/// that aren't allowed on common filesystems are replaced with `_` and the length is limited.
+/// None if nothing usable is left.
+pub fn sanitize(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_end_matches('.').trim_end();
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        return None;
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    let name = if RESERVED_NAMES.contains(&stem.to_ascii_uppercase().as_str()) {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    };
+    Some(truncate(name))
+}
+
+/// Shortens the stem of names longer than MAX_FILENAME_LEN, keeping the extension
+fn truncate(name: String) -> String {
+    if name.len() <= MAX_FILENAME_LEN {
+        return name;
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => name.split_at(i),
+        _ => (name.as_str(), ""),
+    };
+    with_suffix(stem, "", extension)
+}
+
+/// `{stem}{suffix}{extension}` with the stem shortened to fit into MAX_FILENAME_LEN
+fn with_suffix(stem: &str, suffix: &str, extension: &str) -> String {
+    let mut end = MAX_FILENAME_LEN
+        .saturating_sub(suffix.len() + extension.len())
+        .min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}{}", &stem[..end], suffix, extension)
+}
+
+/// Applies the policy if the filename is already taken in the directory, either by a file, by
+/// the part file of another download or by one of the `reserved` paths, e.g. the targets of
+/// downloads that weren't started yet. Reserved paths are never overwritten.
+pub fn resolve_collision(
+    directory: &Path,
+    filename: String,
+    policy: CollisionPolicy,
+    reserved: &HashSet<PathBuf>,
+) -> Result<String> {
+    let is_reserved = |name: &str| reserved.contains(&directory.join(name));
+    let taken = |name: &str| {
+        let path = directory.join(name);
+        path.exists() || part_path(&path).exists() || is_reserved(name)
+    };
+    if !taken(&filename) {
+        return Ok(filename);
+    }
+    match policy {
+        CollisionPolicy::Overwrite if !is_reserved(&filename) => {
+            log::info!("Overwriting {:?} once the download completes", filename);
+            Ok(filename)
+        }
+        CollisionPolicy::Overwrite | CollisionPolicy::Skip => {
+            Err(Error::FileExists(directory.join(filename)))
+        }
+        CollisionPolicy::Rename => {
+            let (stem, extension) = split_extension(&filename);
+            let renamed = (1..)
+                .map(|i| with_suffix(stem, &format!(" ({})", i), extension))
+                .find(|name| !taken(name))
+                .expect("Some suffix is always free");
+            log::info!("{:?} already exists, renamed to {:?}", filename, renamed);
+            Ok(renamed)
+        }
+    }
+}
+
+/// Splits the extension off a filename, compound extensions like `.tar.gz` are kept together
+fn split_extension(filename: &str) -> (&str, &str) {
+    let Some(i) = filename.rfind('.').filter(|i| *i > 0) else {
+        return (filename, "");
+    };
+    let stem = &filename[..i];
+    match stem.rfind('.') {
+        Some(j) if j > 0 && stem[j..].eq_ignore_ascii_case(".tar") => filename.split_at(j),
+        _ => filename.split_at(i),
+    }
+}
+
+/// Splits header parameters at `;`, ignoring separators within quoted strings
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    else {
+        return value.to_string();
+    };
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+/// Decodes an RFC 5987 value like `UTF-8''na%C3%AFve.txt`, only UTF-8 and ISO-8859-1 are
+/// supported
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?.to_ascii_lowercase();
+    let _language = parts.next()?;
+    let bytes = percent_decode(parts.next()?);
+    let decoded = match charset.as_str() {
+        "utf-8" => String::from_utf8(bytes).ok()?,
+        "iso-8859-1" => bytes.into_iter().map(char::from).collect(),
+        _ => return None,
+    };
+    Some(decoded).filter(|name| !name.is_empty())
+}
+
+/// Invalid escapes are kept as they are
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use reqwest::header::HeaderValue;
+    use tempfile::TempDir;
+
+    fn headers(pairs: &[(reqwest::header::HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn content_disposition_test() {
+        assert_eq!(
+            from_content_disposition("attachment; filename=\"report; final.pdf\"").as_deref(),
+            Some("report; final.pdf")
+        );
+        assert_eq!(
+            from_content_disposition(
+                "attachment; filename=\"fallback.txt\"; filename*=UTF-8''na%C3%AFve%20file.txt"
+            )
+            .as_deref(),
+            Some("naïve file.txt")
+        );
+        assert_eq!(
+            from_content_disposition("attachment; FILENAME*=iso-8859-1'en'caf%E9.txt").as_deref(),
+            Some("café.txt")
+        );
+        assert_eq!(
+            from_content_disposition("inline; filename=\"a \\\"quoted\\\" name\"").as_deref(),
+            Some("a \"quoted\" name")
+        );
+        assert_eq!(from_content_disposition("attachment"), None);
+    }
+
+    #[test]
+    fn detect_test() -> Result<()> {
+        let url = Url::parse("https://somewebsite.biz/download?id=123").unwrap();
+        let disposition = headers(&[(CONTENT_DISPOSITION, "attachment; filename=\"../a.zip\"")]);
+        assert_eq!(detect(&url, &disposition), "a.zip");
+        // The final URL is used next, without an extension the MIME type provides one
+        let pdf = headers(&[(CONTENT_TYPE, "application/pdf; charset=binary")]);
+        assert_eq!(detect(&url, &pdf), "download.pdf");
+        let url = Url::parse("https://somewebsite.biz/files/my%20file.tar.gz").unwrap();
+        assert_eq!(detect(&url, &pdf), "my file.tar.gz");
+        let url = Url::parse("https://somewebsite.biz/").unwrap();
+        assert_eq!(detect(&url, &pdf), "download.pdf");
+        assert_eq!(detect(&url, &HeaderMap::new()), DEFAULT_FILENAME);
+        Ok(())
+    }
+
+    #[test]
+    fn sanitize_test() {
+        assert_eq!(
+            sanitize("C:\\Users\\me\\a<b>.txt").as_deref(),
+            Some("a_b_.txt")
+        );
+        assert_eq!(sanitize("what?.txt. ").as_deref(), Some("what_.txt"));
+        assert_eq!(sanitize("con.txt").as_deref(), Some("_con.txt"));
+        assert_eq!(sanitize("line\nbreak").as_deref(), Some("line_break"));
+        assert_eq!(sanitize(".."), None);
+        assert_eq!(sanitize("dir/"), None);
+        let long = format!("{}.mkv", "é".repeat(200));
+        let truncated = sanitize(&long).unwrap();
+        assert!(truncated.len() <= MAX_FILENAME_LEN);
+        assert!(truncated.ends_with("é.mkv"));
+    }
+
+    #[test]
+    fn collision_policy_test() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let directory = tmp_dir.path();
+        let none = HashSet::new();
+        std::fs::write(directory.join("file.bin"), [])?;
+        std::fs::write(part_path(&directory.join("file (1).bin")), [])?;
+        assert_eq!(
+            resolve_collision(
+                directory,
+                "file.bin".to_string(),
+                CollisionPolicy::Rename,
+                &none
+            )?,
+            "file (2).bin"
+        );
+        assert_eq!(
+            resolve_collision(
+                directory,
+                "file.bin".to_string(),
+                CollisionPolicy::Overwrite,
+                &none
+            )?,
+            "file.bin"
+        );
+        assert!(matches!(
+            resolve_collision(
+                directory,
+                "file.bin".to_string(),
+                CollisionPolicy::Skip,
+                &none
+            ),
+            Err(Error::FileExists(_))
+        ));
+        assert_eq!(
+            resolve_collision(
+                directory,
+                "free.bin".to_string(),
+                CollisionPolicy::Skip,
+                &none
+            )?,
+            "free.bin"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reserved_paths_are_taken() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let directory = tmp_dir.path();
+        let reserved = HashSet::from([directory.join("file.bin"), directory.join("file (1).bin")]);
+        assert_eq!(
+            resolve_collision(
+                directory,
+                "file.bin".to_string(),
+                CollisionPolicy::Rename,
+                &reserved
+            )?,
+            "file (2).bin"
+        );
+        // Another download owns the path, overwriting it would mix both downloads
+        assert!(matches!(
+            resolve_collision(
+                directory,
+                "file.bin".to_string(),
+                CollisionPolicy::Overwrite,
+                &reserved
+            ),
+            Err(Error::FileExists(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn compound_extensions_are_kept_on_rename() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let directory = tmp_dir.path();
+        let none = HashSet::new();
+        for name in ["a.tar.gz", "b.TAR.XZ", "c.d.zip", ".hidden", "plain"] {
+            std::fs::write(directory.join(name), [])?;
+        }
+        let rename = |name: &str| {
+            resolve_collision(directory, name.to_string(), CollisionPolicy::Rename, &none)
+        };
+        assert_eq!(rename("a.tar.gz")?, "a (1).tar.gz");
+        assert_eq!(rename("b.TAR.XZ")?, "b (1).TAR.XZ");
+        assert_eq!(rename("c.d.zip")?, "c.d (1).zip");
+        assert_eq!(rename(".hidden")?, ".hidden (1)");
+        assert_eq!(rename("plain")?, "plain (1)");
+        Ok(())
+    }
+}
@@ -1,19 +1,43 @@
+pub mod checksum;
 pub mod config;
+pub mod disk;
+pub mod filename;
+pub mod progress;
+pub mod retry;
 
 use futures_util::StreamExt;
-use reqwest::header::RANGE;
-use reqwest::{Client, Response, Url};
+use reqwest::header::{HeaderMap, HeaderName, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::Sender;
 
+use crate::bandwidth::RateLimiter;
+use crate::credentials::CredentialStore;
+use crate::hyperdownload;
+use crate::proxy::ClientPool;
 use crate::util::{file_size, mb, supports_byte_ranges, HALF_SECOND};
 
 use self::config::HttpDownloadConfig;
+use self::disk::Preallocation;
+use self::progress::ProgressTracker;
 
-use super::DownloadMetadata;
+use super::{Download, DownloadMetadata};
+
+const PART_EXTENSION: &str = "ludl.part";
+
+/// Temporary file next to the target that receives the data until the download is complete
+pub fn part_path(file_path: &Path) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(PART_EXTENSION);
+    PathBuf::from(path)
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -21,7 +45,7 @@ pub enum Error {
     Io(#[from] tokio::io::Error),
     #[error("Request error: '{0}'")]
     Request(#[from] reqwest::Error),
-    #[error("Content length not provided for url: '{0}'")]
+    #[error("Content length required but not provided for url: '{0}'")]
     MissingContentLength(Url),
     #[error("Download was already finished, downloaded bytes: '{0}'")]
     DownloadComplete(u64),
@@ -29,15 +53,61 @@ pub enum Error {
     DownloadNotOk(reqwest::StatusCode, String),
     #[error("Download ended before completion, downloaded bytes: '{0}'")]
     StreamEndedBeforeCompletion(u64),
+    #[error("Server did not answer a byte range request with 206, instead: '{0}'")]
+    RangeNotSupported(reqwest::StatusCode),
+    #[error("Server answered with the byte range '{received}' instead of '{requested}'")]
+    UnexpectedRange { requested: String, received: String },
+    #[error("Remote file changed since the download was created: '{0}'")]
+    ResourceChanged(Url),
+    #[error("Invalid checksum: '{0}'")]
+    InvalidChecksum(String),
+    #[error("Checksum mismatch, expected: '{expected}', actual: '{actual}'")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("File already exists: '{0:?}'")]
+    FileExists(PathBuf),
+    #[error("Not enough free space, required: {required} bytes, available: {available} bytes")]
+    InsufficientSpace { required: u64, available: u64 },
+    #[error("Invalid record: '{0}'")]
+    InvalidRecord(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum State {
     Complete,
     Paused(u64),
+    /// Waiting for a free slot in the DownloadManager
+    Queued,
+    /// Waiting for its start time or for a schedule window to open at `at` (Unix timestamp in
+    /// milliseconds)
+    Scheduled {
+        at: u64,
+    },
     Running {
         bytes_downloaded: u64,
+        /// Smoothed speed, see ProgressTracker
         bytes_per_second: u64,
+        /// Average speed since the download was started or resumed
+        #[serde(default)]
+        average_bytes_per_second: u64,
+        /// None if the size of the download is unknown
+        #[serde(default)]
+        percentage: Option<f64>,
+        /// Estimated time remaining at the smoothed speed, None if the size is unknown or
+        /// the download is stalled
+        #[serde(default)]
+        eta_seconds: Option<u64>,
+    },
+    /// The file is completely downloaded and its checksum is being verified
+    Verifying,
+    /// The download is complete and uploaded to peers until its seed ratio is reached
+    Seeding {
+        bytes_uploaded: u64,
+    },
+    /// The last attempt failed with a transient error, the next one resumes at `next_at`
+    /// (Unix timestamp in milliseconds)
+    Retrying {
+        attempt: u32,
+        next_at: u64,
     },
     Error(String),
 }
@@ -57,25 +127,151 @@ pub struct HttpDownload {
     pub directory: PathBuf,
     pub filename: String,
     pub config: HttpDownloadConfig,
-    pub content_length: u64,
+    /// None if the server didn't send a Content-Length, e.g. for chunked responses. Such
+    /// downloads stream until the body ends.
+    pub content_length: Option<u64>,
     pub supports_byte_ranges: bool,
+    /// Validators of the remote file captured at creation, sent as If-Range when resuming
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
     pub client: Client,
+    /// Limits this download to `config.bandwidth_limit`, adjustable while the download runs.
+    /// Its limit takes precedence over the config once it was changed.
+    pub limiter: RateLimiter,
+    /// Shared by all downloads of a DownloadManager, unlimited for standalone downloads
+    pub global_limiter: RateLimiter,
+    /// Bytes received by all runs, data downloaded again after a restart counts again
+    pub received: Arc<AtomicU64>,
+    /// Set for downloads persisted before part files were introduced, their data is still at the
+    /// target path. The next resume moves it into the part file and clears the flag.
+    pub in_place: Arc<AtomicBool>,
+}
+
+/// Persisted form of an HttpDownload
+#[derive(Debug, Serialize, Deserialize)]
+struct HttpDownloadRecord {
+    id: uuid::Uuid,
+    url: String,
+    directory: PathBuf,
+    filename: String,
+    content_length: Option<u64>,
+    supports_byte_ranges: bool,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    config: HttpDownloadConfig,
+    #[serde(default)]
+    in_place: bool,
 }
 
 impl HttpDownload {
+    pub const KIND: &'static str = "http";
+
+    /// Restores a download persisted with `Download::to_record`, it gets a fresh Client
+    pub fn from_record(record: serde_json::Value) -> Result<Self> {
+        let record: HttpDownloadRecord =
+            serde_json::from_value(record).map_err(|e| Error::InvalidRecord(e.to_string()))?;
+        let url = Url::parse(&record.url).map_err(|e| Error::InvalidRecord(e.to_string()))?;
+        Ok(Self {
+            url,
+            id: record.id,
+            directory: record.directory,
+            filename: record.filename,
+            limiter: RateLimiter::new(record.config.bandwidth_limit),
+            global_limiter: RateLimiter::default(),
+            received: Default::default(),
+            config: record.config,
+            content_length: record.content_length,
+            supports_byte_ranges: record.supports_byte_ranges,
+            etag: record.etag,
+            last_modified: record.last_modified,
+            client: Client::new(),
+            in_place: Arc::new(AtomicBool::new(record.in_place)),
+        })
+    }
+
+    /// RestoreFn of HttpDownloads for the SqliteStore
+    pub fn restore(record: serde_json::Value) -> anyhow::Result<Box<dyn Download>> {
+        Ok(Box::new(Self::from_record(record)?))
+    }
+
+    /// Downloads into the part file, which only replaces the target file once the transfer
+    /// succeeded and the checksum matched
     pub async fn start(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+        self.ensure_space().await?;
+        let downloaded_bytes = self.start_transfer(update_ch.clone()).await?;
+        self.verify(update_ch).await?;
+        self.finish().await?;
+        Ok(downloaded_bytes)
+    }
+
+    pub async fn resume(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+        self.ensure_space().await?;
+        let downloaded_bytes = self.resume_transfer(update_ch.clone()).await?;
+        self.verify(update_ch).await?;
+        self.finish().await?;
+        Ok(downloaded_bytes)
+    }
+
+    /// Fails fast if the rest of the download doesn't fit on the target volume, space the part
+    /// file already occupies is taken into account
+    async fn ensure_space(&self) -> Result<()> {
+        let Some(content_length) = self.content_length else {
+            return Ok(());
+        };
+        let allocated = disk::allocated_bytes(&self.part_path()).await;
+        disk::ensure_space(&self.directory, content_length.saturating_sub(allocated))
+    }
+
+    /// Moves the complete part file to the target path, a rename within the same directory is
+    /// atomic so the target never holds a partial download
+    async fn finish(&self) -> Result<()> {
+        // Otherwise a crash right after the rename can leave a truncated file at the target
+        File::open(self.part_path()).await?.sync_all().await?;
+        tokio::fs::rename(self.part_path(), self.file_path()).await?;
+        log::info!("Download {} moved to {:?}", self.id, self.file_path());
+        Ok(())
+    }
+
+    /// Compares the part file to the expected checksum of the config, if there is one
+    async fn verify(&self, update_ch: Sender<DownloadUpdate>) -> Result<()> {
+        let Some(checksum) = self.config.checksum.as_ref() else {
+            return Ok(());
+        };
+        log::info!(
+            "Verifying {:?} checksum of download {}",
+            checksum.algorithm,
+            self.id
+        );
+        let _ = update_ch
+            .send(DownloadUpdate {
+                id: self.id,
+                state: State::Verifying,
+            })
+            .await;
+        checksum.verify(&self.part_path()).await
+    }
+
+    async fn start_transfer(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+        if self.is_segmented() {
+            return hyperdownload::start(self, update_ch).await;
+        }
         let resp = self
             .client
             .get(self.url.as_ref())
             .headers(self.config.headers.clone())
             .send()
             .await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(Error::DownloadNotOk(status, body));
+        }
         log::info!(
             "Starting new download for url {}, creating file at {:?}",
             self.url,
-            self.file_path()
+            self.part_path()
         );
-        let file_handler = File::create(self.file_path()).await?;
+        let file_handler = File::create(self.part_path()).await?;
         self.progress(resp, file_handler, update_ch, 0).await
     }
 
@@ -83,44 +279,184 @@ impl HttpDownload {
         self.directory.join(&self.filename)
     }
 
-    pub async fn resume(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+    /// Applies the CollisionPolicy of the config again with the `reserved` paths taken as well,
+    /// the filename changes if the policy renames the download
+    pub fn avoid_collisions(&mut self, reserved: &HashSet<PathBuf>) -> Result<()> {
+        self.filename = filename::resolve_collision(
+            &self.directory,
+            self.filename.clone(),
+            self.config.on_collision,
+            reserved,
+        )?;
+        Ok(())
+    }
+
+    /// Where the data is written while the download is incomplete
+    pub fn part_path(&self) -> PathBuf {
+        part_path(&self.file_path())
+    }
+
+    /// Segmented downloads are delegated to the hyperdownload module, they need to know the
+    /// size of the file upfront. Preallocated downloads are segmented even with a single segment,
+    /// their progress can't be read from the file size.
+    pub fn is_segmented(&self) -> bool {
+        let out_of_order =
+            self.config.segments > 1 || self.config.preallocation != Preallocation::Disabled;
+        out_of_order && self.supports_byte_ranges && self.content_length.is_some()
+    }
+
+    async fn resume_transfer(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+        let mut part_exists = self.part_path().exists();
+        if !part_exists && self.in_place.swap(false, Ordering::Relaxed) && self.file_path().exists()
+        {
+            log::info!("Moving data of download {} into its part file", self.id);
+            tokio::fs::rename(self.file_path(), self.part_path()).await?;
+            part_exists = true;
+        }
         let bytes_on_disk = self.get_bytes_on_disk().await;
-        if bytes_on_disk == self.content_length {
+        if part_exists && self.is_segmented() {
+            // The size of an allocated part file says nothing about its progress
+            return hyperdownload::resume(self, update_ch).await;
+        }
+        if Some(bytes_on_disk) == self.content_length && part_exists {
+            // The transfer finished but the download was interrupted before it was moved
+            log::info!("Part file of download {} is already complete", self.id);
+            return Ok(bytes_on_disk);
+        }
+        if Some(bytes_on_disk) == self.content_length {
             log::warn!(
                 "Tried downloading a file that was already completely downloaded: {}",
                 self.url
             );
             return Err(Error::DownloadComplete(bytes_on_disk));
         }
+        if !part_exists {
+            // A file at the target isn't data of this download, e.g. one to be overwritten
+            log::info!(
+                "Download {} has no part file, starting from scratch",
+                self.id
+            );
+            return self.start_transfer(update_ch).await;
+        }
         if !self.supports_byte_ranges {
             log::warn!(
                 "Tried resuming a download that doesn't support byte ranges: {}",
                 self.url
             );
             log::info!("Starting from scratch: {}", self.url);
-            return self.start(update_ch).await;
+            return self.start_transfer(update_ch).await;
+        }
+        if self.is_segmented() {
+            return hyperdownload::resume(self, update_ch).await;
         }
-        let file_handler = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(self.file_path())
+        let resp = self
+            .range_request(format!("bytes={}-", bytes_on_disk))
+            .send()
             .await?;
+        match resp.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                let last = self.content_length.map(|len| len - 1);
+                if let Err(e) = self.ensure_range(resp.headers(), bytes_on_disk, last) {
+                    // Appending a different range would corrupt the download
+                    log::warn!("{}, restarting from scratch: {}", e, self.url);
+                    return self.start_transfer(update_ch).await;
+                }
+                let file_handler = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.part_path())
+                    .await?;
+                self.progress(resp, file_handler, update_ch, bytes_on_disk)
+                    .await
+            }
+            StatusCode::OK => {
+                // The body is the whole file, appending it would corrupt the download
+                self.ensure_unchanged(resp.headers())?;
+                log::warn!(
+                    "Server answered the range request with the whole file, restarting from scratch: {}",
+                    self.url
+                );
+                let file_handler = File::create(self.part_path()).await?;
+                self.progress(resp, file_handler, update_ch, 0).await
+            }
+            status => {
+                let body = resp.text().await.unwrap_or_default();
+                Err(Error::DownloadNotOk(status, body))
+            }
+        }
+    }
 
-        let resp = self
+    /// GET request for a byte range of the file, with If-Range the server only honors the range
+    /// if the file didn't change since the download was created.
+    pub fn range_request(&self, range: String) -> RequestBuilder {
+        let request = self
             .client
             .get(self.url.as_ref())
             .headers(self.config.headers.clone())
-            .header(RANGE, format!("bytes={}-", bytes_on_disk))
-            .send()
-            .await?;
-        self.progress(resp, file_handler, update_ch, bytes_on_disk)
-            .await
+            .header(RANGE, range);
+        match self.if_range() {
+            Some(validator) => request.header(IF_RANGE, validator),
+            None => request,
+        }
+    }
+
+    /// If-Range only accepts strong ETags, Last-Modified is used otherwise
+    fn if_range(&self) -> Option<&str> {
+        match self.etag.as_deref() {
+            Some(etag) if !etag.starts_with("W/") => Some(etag),
+            _ => self.last_modified.as_deref(),
+        }
+    }
+
+    /// Fails with Error::ResourceChanged if the validators of a response differ from the ones
+    /// captured at creation
+    pub fn ensure_unchanged(&self, headers: &HeaderMap) -> Result<()> {
+        let changed = |captured: &Option<String>, name: HeaderName| {
+            captured.is_some() && *captured != header_string(headers, name)
+        };
+        if changed(&self.etag, ETAG) || changed(&self.last_modified, LAST_MODIFIED) {
+            log::error!("Remote file of download {} changed: {}", self.id, self.url);
+            return Err(Error::ResourceChanged(self.url.clone()));
+        }
+        Ok(())
+    }
+
+    /// Fails with Error::UnexpectedRange unless the Content-Range of a 206 response starts at
+    /// `first` and ends at `last`, open ranges are requested with `last` as None
+    pub fn ensure_range(&self, headers: &HeaderMap, first: u64, last: Option<u64>) -> Result<()> {
+        let received = content_range(headers);
+        let matches = received.is_some_and(|(received_first, received_last)| {
+            received_first == first && last.is_none_or(|last| last == received_last)
+        });
+        if matches {
+            return Ok(());
+        }
+        let requested = match last {
+            Some(last) => format!("{}-{}", first, last),
+            None => format!("{}-", first),
+        };
+        let received = received
+            .map(|(first, last)| format!("{}-{}", first, last))
+            .unwrap_or_default();
+        log::error!(
+            "Download {} requested bytes {} but received '{}': {}",
+            self.id,
+            requested,
+            received,
+            self.url
+        );
+        Err(Error::UnexpectedRange {
+            requested,
+            received,
+        })
     }
 
+    /// Without a `filename` it's detected from the response, see filename::detect. The
+    /// CollisionPolicy of the config applies if the target file already exists.
     pub async fn create(
         url: Url,
         directory: PathBuf,
-        filename: String,
+        filename: Option<String>,
         client: Client,
         config: Option<HttpDownloadConfig>,
     ) -> Result<Self> {
@@ -143,20 +479,41 @@ impl HttpDownload {
             }
         };
 
-        let content_length = match resp.content_length() {
-            Some(val) => Ok(val),
-            None => Err(Error::MissingContentLength(url.clone())),
-        }?;
+        let content_length = resp.content_length();
+        if content_length.is_none() {
+            log::info!(
+                "No content length provided for url {}, size is unknown",
+                url
+            );
+        }
+        let filename = filename
+            .and_then(|filename| filename::sanitize(&filename))
+            .unwrap_or_else(|| filename::detect(resp.url(), resp.headers()));
+        let filename = filename::resolve_collision(
+            &directory,
+            filename,
+            config.on_collision,
+            &HashSet::new(),
+        )?;
         let supports_byte_ranges = supports_byte_ranges(resp.headers());
+        let etag = header_string(resp.headers(), ETAG);
+        let last_modified = header_string(resp.headers(), LAST_MODIFIED);
+        let limiter = RateLimiter::new(config.bandwidth_limit);
         let download = HttpDownload {
             id,
             url,
             directory,
-            filename: filename.to_string(),
+            filename,
             config,
             client,
             supports_byte_ranges,
             content_length,
+            etag,
+            last_modified,
+            limiter,
+            global_limiter: RateLimiter::default(),
+            received: Default::default(),
+            in_place: Default::default(),
         };
         Ok(download)
     }
@@ -169,34 +526,36 @@ impl HttpDownload {
         mut downloaded_bytes: u64,
     ) -> Result<u64> {
         let mut stream = resp.bytes_stream();
+        let mut tracker = ProgressTracker::new(self.content_length, downloaded_bytes);
         let mut last_update = std::time::Instant::now();
-        let mut previous_bytes = 0u64;
         while let Some(chunk) = stream.next().await {
             let item = chunk?;
-            let bytes_written = file_handler.write(&item).await? as u64;
+            file_handler.write_all(&item).await?;
+            let bytes_written = item.len() as u64;
+            self.received.fetch_add(bytes_written, Ordering::Relaxed);
+            self.throttle(bytes_written).await;
             downloaded_bytes += bytes_written;
-            previous_bytes += bytes_written;
-            let elapsed = last_update.elapsed();
-            if elapsed > HALF_SECOND {
+            if last_update.elapsed() > HALF_SECOND {
                 let _ = update_ch.try_send(DownloadUpdate {
                     id: self.id,
-                    state: State::Running {
-                        bytes_downloaded: downloaded_bytes,
-                        bytes_per_second: previous_bytes / last_update.elapsed().as_millis() as u64
-                            * 1000,
-                    },
+                    state: tracker.update(downloaded_bytes),
                 });
                 last_update = std::time::Instant::now();
-                previous_bytes = 0u64;
             }
         }
-        if downloaded_bytes < self.content_length {
-            log::error!(
-                "Download stream ended before completion, downloaded bytes: {}, content length: {}",
-                downloaded_bytes,
-                self.content_length
-            );
-            return Err(Error::StreamEndedBeforeCompletion(downloaded_bytes));
+        // tokio finishes the last write in the background, verifying reads the file right away
+        file_handler.flush().await?;
+        file_handler.sync_data().await?;
+        // Without a content length the end of the body marks the completion
+        if let Some(content_length) = self.content_length {
+            if downloaded_bytes < content_length {
+                log::error!(
+                    "Download stream ended before completion, downloaded bytes: {}, content length: {}",
+                    downloaded_bytes,
+                    content_length
+                );
+                return Err(Error::StreamEndedBeforeCompletion(downloaded_bytes));
+            }
         }
         log::info!(
             "Download completed successfully: {}, {}MB",
@@ -206,20 +565,132 @@ impl HttpDownload {
         Ok(downloaded_bytes)
     }
 
+    /// Waits until both the download's own and the global limiter allow `bytes` more bytes
+    pub async fn throttle(&self, bytes: u64) {
+        tokio::join!(
+            self.limiter.acquire(bytes),
+            self.global_limiter.acquire(bytes)
+        );
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
     pub fn get_metadata(&self) -> DownloadMetadata {
         DownloadMetadata {
             id: self.id,
             url: self.url.to_string(),
             file_path: self.file_path(),
             download_size: self.content_length,
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
         }
     }
 
+    /// Segmented downloads allocate the whole file upfront, their progress is read from the
+    /// segments file instead. Without a part file the download is either complete or wasn't
+    /// started yet.
     pub async fn get_bytes_on_disk(&self) -> u64 {
-        file_size(&self.file_path()).await
+        let file_path = self.file_path();
+        if let Some(bytes) = hyperdownload::bytes_downloaded(&file_path).await {
+            return bytes;
+        }
+        let part_path = self.part_path();
+        if part_path.exists() {
+            file_size(&part_path).await
+        } else {
+            file_size(&file_path).await
+        }
     }
 }
 
+/// Starting and resuming retry failed transfers according to the RetryPolicy of the config
+#[async_trait::async_trait]
+impl Download for HttpDownload {
+    fn id(&self) -> uuid::Uuid {
+        self.id
+    }
+
+    fn get_metadata(&self) -> DownloadMetadata {
+        HttpDownload::get_metadata(self)
+    }
+
+    async fn get_bytes_on_disk(&self) -> u64 {
+        HttpDownload::get_bytes_on_disk(self).await
+    }
+
+    async fn start(&self, update_ch: Sender<DownloadUpdate>) -> anyhow::Result<u64> {
+        Ok(self.run(update_ch, false).await?)
+    }
+
+    async fn resume(&self, update_ch: Sender<DownloadUpdate>) -> anyhow::Result<u64> {
+        Ok(self.run(update_ch, true).await?)
+    }
+
+    fn kind(&self) -> &'static str {
+        Self::KIND
+    }
+
+    /// The config is stored with the current limit of the limiter, which can change while the
+    /// download runs
+    fn to_record(&self) -> Option<serde_json::Value> {
+        let record = HttpDownloadRecord {
+            id: self.id,
+            url: self.url.to_string(),
+            directory: self.directory.clone(),
+            filename: self.filename.clone(),
+            content_length: self.content_length,
+            supports_byte_ranges: self.supports_byte_ranges,
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
+            config: HttpDownloadConfig {
+                bandwidth_limit: self.limiter.limit(),
+                ..self.config.clone()
+            },
+            in_place: self.in_place.load(Ordering::Relaxed),
+        };
+        Some(serde_json::to_value(record).expect("Records are always serializable"))
+    }
+
+    fn set_global_limiter(&mut self, limiter: RateLimiter) {
+        self.global_limiter = limiter;
+    }
+
+    fn prepare_run(
+        &mut self,
+        clients: &ClientPool,
+        credentials: Option<&CredentialStore>,
+    ) -> Option<uuid::Uuid> {
+        self.client = clients.assign(&self.id, &self.url);
+        credentials.and_then(|credentials| credentials.apply(&self.url, &mut self.config.headers))
+    }
+
+    fn set_bandwidth_limit(&self, bytes_per_second: Option<u64>) -> bool {
+        self.limiter.set_limit(bytes_per_second);
+        true
+    }
+
+    fn bytes_received(&self) -> u64 {
+        HttpDownload::bytes_received(self)
+    }
+}
+
+/// First and last byte of a `Content-Range: bytes first-last/length` header
+fn content_range(headers: &HeaderMap) -> Option<(u64, u64)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (range, _length) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((first.trim().parse().ok()?, last.trim().parse().ok()?))
+}
+
+fn header_string(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
 #[cfg(test)]
 mod test {
     use std::error::Error;
@@ -227,9 +698,14 @@ mod test {
 
     use pretty_assertions::assert_eq;
 
-    use crate::util::{parse_filename, setup_test_download};
+    use crate::util::setup_test_download;
+    use crate::util::test_server::{test_payload, TestServer};
 
+    use super::checksum::{Checksum, ChecksumAlgorithm};
     use super::*;
+    use sha2::Digest;
+    use tempfile::TempDir;
+    use tokio::sync::mpsc;
 
     type Test<T> = std::result::Result<T, Box<dyn Error>>;
     const TEST_DOWNLOAD_URL: &str =
@@ -240,10 +716,9 @@ mod test {
         // given
         let url_str = TEST_DOWNLOAD_URL;
         let url = Url::parse(url_str)?;
-        let filename = parse_filename(&url).unwrap().to_string();
         let directory = PathBuf::new();
         // when creating a download, server data is present in the download struct
-        let download = HttpDownload::create(url, directory, filename, Client::new(), None).await?;
+        let download = HttpDownload::create(url, directory, None, Client::new(), None).await?;
         // then
         assert!(
             download.supports_byte_ranges,
@@ -262,11 +737,11 @@ mod test {
         // then
         assert_eq!(
             download.content_length,
-            file_size(&download.file_path()).await,
+            Some(file_size(&download.file_path()).await),
             "File size should be equal to content_length"
         );
         assert_eq!(
-            downloaded_bytes,
+            Some(downloaded_bytes),
             download.content_length,
             "The downloaded bytes need to be equal to the content_length when the download is finished"
         );
@@ -276,8 +751,10 @@ mod test {
     #[test(tokio::test)]
     async fn download_with_custom_chunksize_test() -> Test<()> {
         // given
-        let mut config = HttpDownloadConfig::default();
-        config.chunk_size = 1024 * 1029;
+        let config = HttpDownloadConfig {
+            chunk_size: 1024 * 1029,
+            ..Default::default()
+        };
         // and
         let (mut download, _tmp_dir) = setup_test_download(TEST_DOWNLOAD_URL).await?;
         download.config = config;
@@ -287,14 +764,267 @@ mod test {
         // then
         assert_eq!(
             download.content_length,
-            file_size(&download.file_path()).await,
+            Some(file_size(&download.file_path()).await),
             "File size should be equal to content_length"
         );
         assert_eq!(
-            downloaded_bytes,
+            Some(downloaded_bytes),
             download.content_length,
             "The downloaded bytes need to be equal to the content_length when the download is finished"
         );
         Ok(())
     }
+
+    async fn setup_checksum_download(digest: &str) -> Test<(HttpDownload, TestServer, TempDir)> {
+        let server = TestServer::spawn(test_payload(512 * 1024));
+        let tmp_dir = TempDir::new()?;
+        let config = HttpDownloadConfig {
+            checksum: Some(Checksum::new(ChecksumAlgorithm::Sha256, digest)?),
+            ..Default::default()
+        };
+        let download = HttpDownload::create(
+            server.url.clone(),
+            tmp_dir.path().to_owned(),
+            Some("file.bin".to_string()),
+            Client::new(),
+            Some(config),
+        )
+        .await?;
+        Ok((download, server, tmp_dir))
+    }
+
+    #[test(tokio::test)]
+    async fn checksum_is_verified_after_download_test() -> Test<()> {
+        let payload = test_payload(512 * 1024);
+        let digest = hex::encode(sha2::Sha256::digest(&payload));
+        let (download, _server, _tmp_dir) = setup_checksum_download(&digest).await?;
+        let (update_sender, mut update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        let downloaded_bytes = download.start(update_sender).await?;
+        assert_eq!(downloaded_bytes, payload.len() as u64);
+        let mut verified = false;
+        while let Ok(update) = update_recv.try_recv() {
+            verified |= matches!(update.state, State::Verifying);
+        }
+        assert!(verified, "Download should pass through State::Verifying");
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn checksum_mismatch_test() -> Test<()> {
+        let digest = "0".repeat(64);
+        let (download, _server, _tmp_dir) = setup_checksum_download(&digest).await?;
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        match download.start(update_sender).await {
+            Err(super::Error::ChecksumMismatch { expected, actual }) => {
+                assert_eq!(expected, digest);
+                assert_ne!(actual, digest);
+            }
+            result => panic!("Expected a checksum mismatch, got {:?}", result),
+        }
+        // A corrupted download never shows up at the target path
+        assert!(!download.file_path().exists());
+        assert!(download.part_path().exists());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn download_without_content_length_test() -> Test<()> {
+        let server = TestServer::spawn_chunked(test_payload(3 * 1024 * 1024 + 17));
+        let tmp_dir = TempDir::new()?;
+        let download = HttpDownload::create(
+            server.url.clone(),
+            tmp_dir.path().to_owned(),
+            Some("file.bin".to_string()),
+            Client::new(),
+            None,
+        )
+        .await?;
+        assert_eq!(download.content_length, None);
+        assert_eq!(download.get_metadata().download_size, None);
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        let downloaded_bytes = download.start(update_sender).await?;
+        assert_eq!(downloaded_bytes, server.payload.len() as u64);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        Ok(())
+    }
+
+    async fn create_local_download(server: &TestServer, tmp_dir: &TempDir) -> Test<HttpDownload> {
+        let download = HttpDownload::create(
+            server.url.clone(),
+            tmp_dir.path().to_owned(),
+            Some("file.bin".to_string()),
+            Client::new(),
+            None,
+        )
+        .await?;
+        Ok(download)
+    }
+
+    #[test(tokio::test)]
+    async fn resume_sends_if_range_test() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let tmp_dir = TempDir::new()?;
+        let download = create_local_download(&server, &tmp_dir).await?;
+        assert_eq!(download.etag.as_deref(), Some("\"v1\""));
+        tokio::fs::write(download.part_path(), &server.payload[..1000]).await?;
+        assert_eq!(download.get_bytes_on_disk().await, 1000);
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.resume(update_sender).await?;
+        assert_eq!(server.ranges(), vec!["bytes=1000-".to_string()]);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn resume_fails_if_resource_changed_test() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let tmp_dir = TempDir::new()?;
+        let download = create_local_download(&server, &tmp_dir).await?;
+        tokio::fs::write(download.part_path(), &server.payload[..1000]).await?;
+        server.set_etag("\"v2\"");
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        let result = download.resume(update_sender).await;
+        assert!(
+            matches!(result, Err(super::Error::ResourceChanged(_))),
+            "Expected ResourceChanged, got {:?}",
+            result
+        );
+        assert_eq!(file_size(&download.part_path()).await, 1000);
+        assert!(!download.file_path().exists());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn long_filenames_leave_room_for_part_files_test() -> Test<()> {
+        let server = TestServer::spawn(test_payload(3 * 1024 * 1024));
+        server.set_content_disposition(&format!(
+            "attachment; filename*=UTF-8''{}.bin",
+            "a".repeat(300)
+        ));
+        let tmp_dir = TempDir::new()?;
+        let config = HttpDownloadConfig {
+            segments: 2,
+            ..Default::default()
+        };
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        for suffix in [".bin", " (1).bin"] {
+            let download = HttpDownload::create(
+                server.url.clone(),
+                tmp_dir.path().to_owned(),
+                None,
+                Client::new(),
+                Some(config.clone()),
+            )
+            .await?;
+            assert!(download.filename.len() <= filename::MAX_FILENAME_LEN);
+            assert!(download.filename.ends_with(suffix));
+            download.start(update_sender.clone()).await?;
+            assert_eq!(
+                tokio::fs::read(download.file_path()).await?,
+                *server.payload
+            );
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn unexpected_range_is_not_appended_test() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let tmp_dir = TempDir::new()?;
+        let download = create_local_download(&server, &tmp_dir).await?;
+        tokio::fs::write(download.part_path(), &server.payload[..1000]).await?;
+        server.shift_next_ranges(1);
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.resume(update_sender).await?;
+        assert_eq!(server.ranges(), vec!["bytes=1000-".to_string()]);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn ignored_range_is_not_appended_test() -> Test<()> {
+        let server = TestServer::spawn_chunked(test_payload(1024 * 1024));
+        let tmp_dir = TempDir::new()?;
+        let mut download = create_local_download(&server, &tmp_dir).await?;
+        // Pretend the server advertised byte ranges, it still answers every request with 200
+        download.supports_byte_ranges = true;
+        tokio::fs::write(download.part_path(), [0u8; 1000]).await?;
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.resume(update_sender).await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn data_is_moved_to_target_after_completion_test() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let tmp_dir = TempDir::new()?;
+        let download = create_local_download(&server, &tmp_dir).await?;
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.start(update_sender.clone()).await?;
+        assert!(!download.part_path().exists());
+        assert_eq!(
+            download.get_bytes_on_disk().await,
+            server.payload.len() as u64
+        );
+        // A complete part file left behind by an interrupted download is moved on resume
+        tokio::fs::rename(download.file_path(), download.part_path()).await?;
+        download.resume(update_sender.clone()).await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        assert!(server.ranges().is_empty());
+        // Partial files of downloads from before part files existed are resumed
+        tokio::fs::write(download.file_path(), &server.payload[..1000]).await?;
+        download.in_place.store(true, Ordering::Relaxed);
+        download.resume(update_sender.clone()).await?;
+        assert_eq!(server.ranges(), vec!["bytes=1000-".to_string()]);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        // Any other file at the target is replaced, not resumed
+        tokio::fs::write(download.file_path(), [0u8; 1000]).await?;
+        download.resume(update_sender).await?;
+        assert_eq!(server.ranges().len(), 1);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn preallocated_download_test() -> Test<()> {
+        let server = TestServer::spawn(test_payload(2 * 1024 * 1024));
+        let tmp_dir = TempDir::new()?;
+        let mut download = create_local_download(&server, &tmp_dir).await?;
+        download.config.preallocation = Preallocation::Full;
+        // Progress of a preallocated file is tracked per segment, not by its size
+        assert!(download.is_segmented());
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.start(update_sender).await?;
+        assert_eq!(
+            server.ranges(),
+            vec![format!("bytes=0-{}", server.payload.len() - 1)]
+        );
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,147 @@
+use std::time::Instant;
+
+use super::State;
+
+/// Weight of the newest sample in the smoothed speed, lower values give steadier ETAs
+const SMOOTHING: f64 = 0.2;
+
+/// Turns the byte counter of a running download into `State::Running` reports with a smoothed
+/// speed (exponentially weighted moving average), the average speed of the run, the percentage
+/// and the estimated time remaining.
+#[derive(Debug, Clone)]
+pub struct ProgressTracker {
+    total: Option<u64>,
+    started: Instant,
+    start_bytes: u64,
+    last_sample: Instant,
+    last_bytes: u64,
+    smoothed: Option<f64>,
+}
+
+impl ProgressTracker {
+    /// `total` is the size of the download if known, `bytes_downloaded` is where the run starts,
+    /// e.g. the bytes on disk when resuming
+    pub fn new(total: Option<u64>, bytes_downloaded: u64) -> Self {
+        Self::started_at(total, bytes_downloaded, Instant::now())
+    }
+
+    fn started_at(total: Option<u64>, bytes_downloaded: u64, now: Instant) -> Self {
+        Self {
+            total,
+            started: now,
+            start_bytes: bytes_downloaded,
+            last_sample: now,
+            last_bytes: bytes_downloaded,
+            smoothed: None,
+        }
+    }
+
+    pub fn set_total(&mut self, total: Option<u64>) {
+        self.total = total;
+    }
+
+    /// Records the bytes downloaded so far and returns the state to report
+    pub fn update(&mut self, bytes_downloaded: u64) -> State {
+        self.update_at(bytes_downloaded, Instant::now())
+    }
+
+    fn update_at(&mut self, bytes_downloaded: u64, now: Instant) -> State {
+        let elapsed = now.duration_since(self.last_sample).as_secs_f64();
+        if elapsed > 0.0 {
+            let sample = bytes_downloaded.saturating_sub(self.last_bytes) as f64 / elapsed;
+            self.smoothed = Some(match self.smoothed {
+                Some(smoothed) => SMOOTHING * sample + (1.0 - SMOOTHING) * smoothed,
+                None => sample,
+            });
+            self.last_sample = now;
+            self.last_bytes = bytes_downloaded;
+        }
+        let run_time = now.duration_since(self.started).as_secs_f64();
+        let average = if run_time > 0.0 {
+            bytes_downloaded.saturating_sub(self.start_bytes) as f64 / run_time
+        } else {
+            0.0
+        };
+        let speed = self.smoothed.unwrap_or_default();
+        let eta_seconds = match self.total {
+            Some(total) if speed >= 1.0 => {
+                Some((total.saturating_sub(bytes_downloaded) as f64 / speed).ceil() as u64)
+            }
+            _ => None,
+        };
+        State::Running {
+            bytes_downloaded,
+            bytes_per_second: speed.round() as u64,
+            average_bytes_per_second: average.round() as u64,
+            percentage: self
+                .total
+                .filter(|total| *total > 0)
+                .map(|total| (bytes_downloaded as f64 * 100.0 / total as f64).min(100.0)),
+            eta_seconds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn running(state: State) -> (u64, u64, u64, Option<f64>, Option<u64>) {
+        match state {
+            State::Running {
+                bytes_downloaded,
+                bytes_per_second,
+                average_bytes_per_second,
+                percentage,
+                eta_seconds,
+            } => (
+                bytes_downloaded,
+                bytes_per_second,
+                average_bytes_per_second,
+                percentage,
+                eta_seconds,
+            ),
+            state => panic!("Expected Running, got {:?}", state),
+        }
+    }
+
+    #[test]
+    fn speed_is_smoothed_and_eta_derived_from_it() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut tracker = ProgressTracker::started_at(Some(10_000), 1_000, start);
+
+        let (bytes, speed, average, percentage, eta) =
+            running(tracker.update_at(2_000, start + second));
+        assert_eq!((bytes, speed, average), (2_000, 1_000, 1_000));
+        assert_eq!(percentage, Some(20.0));
+        assert_eq!(eta, Some(8));
+
+        // A single fast sample only moves the smoothed speed by SMOOTHING
+        let (_, speed, average, _, eta) = running(tracker.update_at(4_000, start + second * 2));
+        assert_eq!(speed, 1_200);
+        assert_eq!(average, 1_500);
+        assert_eq!(eta, Some(5));
+
+        // Sub-millisecond intervals still report a speed
+        let (_, speed, ..) =
+            running(tracker.update_at(4_100, start + second * 2 + Duration::from_micros(500)));
+        assert!(speed > 1_200);
+    }
+
+    #[test]
+    fn unknown_size_has_no_percentage_or_eta() {
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::started_at(None, 0, start);
+        let (_, speed, _, percentage, eta) =
+            running(tracker.update_at(500, start + Duration::from_millis(500)));
+        assert_eq!(speed, 1_000);
+        assert_eq!(percentage, None);
+        assert_eq!(eta, None);
+        // Stalled downloads have no ETA either
+        let mut tracker = ProgressTracker::started_at(Some(1_000), 0, start);
+        let (.., eta) = running(tracker.update_at(0, start + Duration::from_secs(1)));
+        assert_eq!(eta, None);
+    }
+}
@@ -0,0 +1,256 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::Sender;
+
+use super::{DownloadUpdate, Error, HttpDownload, Result, State};
+
+/// Kinds of errors that can be retried, HTTP statuses are configured separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryableError {
+    /// The connection to the server couldn't be established
+    Connect,
+    Timeout,
+    /// The response body broke off, e.g. on a connection reset
+    Body,
+    /// The body ended before the whole file was received
+    StreamEnded,
+}
+
+/// Failed attempts are retried after `base_delay * 2^(attempt - 1)`, capped at `max_delay` and
+/// randomized by `jitter`. Every retry resumes from the bytes on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Retries after the first failed attempt, 0 disables retrying
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of the delay that is randomly added or subtracted, between 0 and 1
+    pub jitter: f64,
+    pub retry_on: Vec<RetryableError>,
+    pub retry_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            retry_on: vec![
+                RetryableError::Connect,
+                RetryableError::Timeout,
+                RetryableError::Body,
+                RetryableError::StreamEnded,
+            ],
+            retry_statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, error: &Error) -> bool {
+        match error {
+            Error::Request(e) => {
+                let kind = if e.is_connect() {
+                    Some(RetryableError::Connect)
+                } else if e.is_timeout() {
+                    Some(RetryableError::Timeout)
+                } else if e.is_body() || e.is_decode() {
+                    Some(RetryableError::Body)
+                } else {
+                    None
+                };
+                kind.is_some_and(|kind| self.retry_on.contains(&kind))
+                    || e.status()
+                        .is_some_and(|status| self.retry_statuses.contains(&status.as_u16()))
+            }
+            Error::StreamEndedBeforeCompletion(_) => {
+                self.retry_on.contains(&RetryableError::StreamEnded)
+            }
+            Error::DownloadNotOk(status, _) | Error::RangeNotSupported(status) => {
+                self.retry_statuses.contains(&status.as_u16())
+            }
+            _ => false,
+        }
+    }
+
+    /// Delay before the given retry, `attempt` starts at 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+        delay.mul_f64(factor)
+    }
+}
+
+impl HttpDownload {
+    /// Starts (or resumes) the download and retries transient failures according to the
+    /// RetryPolicy of the config, observers see `State::Retrying` while waiting for the next
+    /// attempt.
+    pub async fn run(&self, update_ch: Sender<DownloadUpdate>, resume: bool) -> Result<u64> {
+        let policy = &self.config.retry;
+        let mut result = if resume {
+            self.resume(update_ch.clone()).await
+        } else {
+            self.start(update_ch.clone()).await
+        };
+        let mut attempt = 0;
+        while let Err(e) = &result {
+            if attempt >= policy.max_attempts || !policy.is_retryable(e) {
+                break;
+            }
+            attempt += 1;
+            let delay = policy.delay(attempt);
+            log::warn!(
+                "Download {} failed: {}, retry {}/{} in {:?}",
+                self.id,
+                e,
+                attempt,
+                policy.max_attempts,
+                delay
+            );
+            let next_at = SystemTime::now() + delay;
+            let _ = update_ch
+                .send(DownloadUpdate {
+                    id: self.id,
+                    state: State::Retrying {
+                        attempt,
+                        next_at: next_at
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_millis() as u64,
+                    },
+                })
+                .await;
+            tokio::time::sleep(delay).await;
+            result = self.resume(update_ch.clone()).await;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::httpdownload::download::config::HttpDownloadConfig;
+    use crate::util::test_server::{test_payload, TestServer};
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use reqwest::Client;
+    use tempfile::TempDir;
+    use test_log::test;
+    use tokio::sync::mpsc;
+
+    async fn setup(max_attempts: u32) -> TestResult<(HttpDownload, TestServer, TempDir)> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let tmp_dir = TempDir::new()?;
+        let config = HttpDownloadConfig {
+            retry: RetryPolicy {
+                max_attempts,
+                base_delay: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let download = HttpDownload::create(
+            server.url.clone(),
+            tmp_dir.path().to_owned(),
+            Some("file.bin".to_string()),
+            Client::new(),
+            Some(config),
+        )
+        .await?;
+        Ok((download, server, tmp_dir))
+    }
+
+    #[test(tokio::test)]
+    async fn transient_failures_are_retried_test() -> TestResult<()> {
+        let (download, server, _tmp_dir) = setup(3).await?;
+        server.fail_next_requests(2);
+        let (update_sender, mut update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.run(update_sender, false).await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        let mut attempts = Vec::new();
+        while let Ok(update) = update_recv.try_recv() {
+            if let State::Retrying { attempt, .. } = update.state {
+                attempts.push(attempt);
+            }
+        }
+        assert_eq!(attempts, vec![1, 2]);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn retries_are_limited_test() -> TestResult<()> {
+        let (download, server, _tmp_dir) = setup(1).await?;
+        server.fail_next_requests(2);
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        let result = download.run(update_sender, false).await;
+        assert!(matches!(
+            result,
+            Err(Error::DownloadNotOk(
+                reqwest::StatusCode::SERVICE_UNAVAILABLE,
+                _
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn delay_grows_exponentially_test() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        let delay = policy.delay(1);
+        assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn retryable_errors_test() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retryable(&Error::StreamEndedBeforeCompletion(10)));
+        assert!(policy.is_retryable(&Error::DownloadNotOk(
+            reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            String::new()
+        )));
+        assert!(!policy.is_retryable(&Error::DownloadNotOk(
+            reqwest::StatusCode::NOT_FOUND,
+            String::new()
+        )));
+        assert!(!policy.is_retryable(&Error::ChecksumMismatch {
+            expected: "a".to_string(),
+            actual: "b".to_string()
+        }));
+        let policy = RetryPolicy {
+            retry_on: vec![],
+            ..Default::default()
+        };
+        assert!(!policy.is_retryable(&Error::StreamEndedBeforeCompletion(10)));
+    }
+}
@@ -1,15 +1,20 @@
-use crate::httpdownload::download::{DownloadUpdate, HttpDownload};
-use crate::httpdownload::DownloadMetadata;
+use crate::bandwidth::RateLimiter;
+use crate::credentials::CredentialStore;
+use crate::httpdownload::download::{self, DownloadUpdate};
+use crate::httpdownload::{Download, DownloadMetadata};
+use crate::proxy::ClientPool;
+use crate::schedule::{self, TimeWindow};
 
 use anyhow::anyhow;
+use chrono::Local;
 use futures_util::future::join_all;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::process::exit;
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
-use super::item::DownloaderItem;
-use super::{Result, UpdateConsumer};
+use super::item::{DownloaderItem, Finished};
+use super::{QueuePosition, Result, UpdateConsumer};
 
 impl UpdateConsumer for () {
     fn consume(&mut self, update: DownloadUpdate) {
@@ -21,16 +26,50 @@ impl UpdateConsumer for () {
 pub struct ManagerInner {
     pub update_ch: mpsc::Sender<DownloadUpdate>,
     pub items: HashMap<Uuid, DownloaderItem>,
+    finished_ch: mpsc::UnboundedSender<Finished>,
+    /// Downloads waiting for a free slot together with their resume flag, ordered by priority and
+    /// first in first out within the same priority
+    queue: VecDeque<(Uuid, bool)>,
+    /// Downloads holding a slot, in the order they were started
+    running: Vec<Uuid>,
+    /// Maximum number of downloads running at the same time, 0 means unlimited
+    max_concurrent: usize,
+    /// Running downloads of a lower priority give up their slot to queued ones
+    preempt: bool,
+    /// Downloads waiting for their start time (Unix timestamp in milliseconds) together with
+    /// their resume flag
+    scheduled: HashMap<Uuid, (u64, bool)>,
+    /// Weekly windows during which the queue runs, it always runs without windows
+    windows: Vec<TimeWindow>,
+    /// Window that was open at the last tick
+    active_window: Option<TimeWindow>,
+    /// Limit of the global_limiter while no window with its own limit is open
+    bandwidth_limit: Option<u64>,
+    /// Shared by every download added to the manager
+    pub global_limiter: RateLimiter,
+    /// Provides the client of every run of a download
+    pub client_pool: ClientPool,
+    /// Credentials matching the URL are put into the headers of every run of a download
+    pub credentials: Option<CredentialStore>,
+    /// Credential applied to the current run of a download, charged with the bytes the run
+    /// transferred once it's over
+    run_credentials: HashMap<Uuid, Uuid>,
 }
 
 impl Default for ManagerInner {
     fn default() -> Self {
-        ManagerInner::new(())
+        let (finished_sender, _) = mpsc::unbounded_channel();
+        ManagerInner::new((), finished_sender)
     }
 }
 
 impl ManagerInner {
-    pub fn new(mut update_consumer: impl UpdateConsumer + Send + Sync + 'static) -> Self {
+    /// Every item reports on `finished_ch` once its download task is over, the receiving end is
+    /// expected to call `on_finished` to release the slot of the download.
+    pub fn new(
+        mut update_consumer: impl UpdateConsumer + Send + Sync + 'static,
+        finished_ch: mpsc::UnboundedSender<Finished>,
+    ) -> Self {
         let (update_sender, mut update_recv) = mpsc::channel::<DownloadUpdate>(1000);
         log::info!("Spawning update consumer task");
         tokio::task::spawn(async move {
@@ -45,12 +84,26 @@ impl ManagerInner {
         ManagerInner {
             update_ch: update_sender,
             items: HashMap::new(),
+            finished_ch,
+            queue: VecDeque::new(),
+            running: Vec::new(),
+            max_concurrent: 0,
+            preempt: false,
+            scheduled: HashMap::new(),
+            windows: Vec::new(),
+            active_window: None,
+            bandwidth_limit: None,
+            global_limiter: RateLimiter::default(),
+            client_pool: ClientPool::default(),
+            credentials: None,
+            run_credentials: HashMap::new(),
         }
     }
 
-    pub fn add(&mut self, download: HttpDownload) -> Uuid {
+    pub fn add(&mut self, mut download: Box<dyn Download>) -> Uuid {
+        download.set_global_limiter(self.global_limiter.clone());
         log::info!("Adding download: {:?}", download);
-        let id = download.id;
+        let id = download.id();
         let item = DownloaderItem::new(download);
         self.items.insert(id, item);
         id
@@ -73,51 +126,466 @@ impl ManagerInner {
         .await
     }
 
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// Takes effect immediately: queued downloads are started if the limit was raised, the most
+    /// recently started downloads are stopped and put back at the front of the queue if it was
+    /// lowered.
+    pub fn set_max_concurrent(&mut self, max_concurrent: usize) {
+        log::info!(
+            "Setting maximum of concurrent downloads to {}",
+            max_concurrent
+        );
+        self.max_concurrent = max_concurrent;
+        while max_concurrent > 0 && self.running.len() > max_concurrent {
+            let id = self.running.pop().expect("running can't be empty here");
+            log::info!("Moving download {} back to the queue", id);
+            if let Some(item) = self.items.get_mut(&id) {
+                let _ = item.stop();
+            }
+            self.enqueue(id, true, true);
+        }
+        self.fill_slots();
+    }
+
+    /// Ids of all downloads waiting for a free slot, next in line first
+    pub fn queue(&self) -> Vec<Uuid> {
+        self.queue.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// Places the download behind the queued downloads of the same or a higher priority, or in
+    /// front of the ones of the same or a lower priority if `front` is set
+    fn enqueue(&mut self, id: Uuid, resume: bool, front: bool) {
+        let priority = self.priority(&id);
+        let index = self
+            .queue
+            .iter()
+            .position(|(queued, _)| {
+                let queued = self.priority(queued);
+                if front {
+                    queued <= priority
+                } else {
+                    queued < priority
+                }
+            })
+            .unwrap_or(self.queue.len());
+        self.queue.insert(index, (id, resume));
+    }
+
+    fn queue_index(&self, id: &Uuid) -> Result<usize> {
+        self.queue
+            .iter()
+            .position(|(queued, _)| queued == id)
+            .ok_or_else(|| anyhow!("Download {} is not queued", id))
+    }
+
+    pub fn priority(&self, id: &Uuid) -> i32 {
+        self.items.get(id).map_or(0, |item| item.priority)
+    }
+
+    /// A queued download moves behind the downloads of its new priority, running downloads may
+    /// be preempted by it, see `set_preempt`
+    pub fn set_priority(&mut self, id: &Uuid, priority: i32) -> Result<()> {
+        let item = self
+            .items
+            .get_mut(id)
+            .ok_or_else(|| anyhow!("Download with id {} not found", id))?;
+        log::info!("Setting priority of download {} to {}", id, priority);
+        item.priority = priority;
+        if let Ok(index) = self.queue_index(id) {
+            let (id, resume) = self.queue.remove(index).expect("index is in the queue");
+            self.enqueue(id, resume, false);
+        }
+        self.fill_slots();
+        Ok(())
+    }
+
+    /// Moves a queued download, it takes the priority of its new neighbour so the queue stays
+    /// ordered by priority. Returns the priority of the download.
+    pub fn move_in_queue(&mut self, id: &Uuid, position: QueuePosition) -> Result<i32> {
+        let index = self.queue_index(id)?;
+        let target = match position {
+            QueuePosition::Before(other) | QueuePosition::After(other) => {
+                Some(self.queue_index(&other)?)
+            }
+            QueuePosition::Top | QueuePosition::Bottom => None,
+        };
+        if target == Some(index) {
+            return Ok(self.priority(id));
+        }
+        log::info!("Moving download {} in the queue: {:?}", id, position);
+        let entry = self.queue.remove(index).expect("index is in the queue");
+        // Index of the target once the download left the queue
+        let target = target.map(|target| if target > index { target - 1 } else { target });
+        let (index, neighbour) = match (position, target) {
+            (QueuePosition::Top, _) => (0, self.queue.front()),
+            (QueuePosition::Bottom, _) => (self.queue.len(), self.queue.back()),
+            (QueuePosition::Before(_), Some(target)) => (target, self.queue.get(target)),
+            (QueuePosition::After(_), Some(target)) => (target + 1, self.queue.get(target)),
+            _ => unreachable!("Before and After have a target"),
+        };
+        let priority =
+            neighbour.map_or(self.priority(id), |(neighbour, _)| self.priority(neighbour));
+        if let Some(item) = self.items.get_mut(id) {
+            item.priority = priority;
+        }
+        self.queue.insert(index, entry);
+        self.fill_slots();
+        Ok(priority)
+    }
+
+    pub fn preempt(&self) -> bool {
+        self.preempt
+    }
+
+    /// With preemption enabled, a queued download without a free slot stops the running download
+    /// of the lowest priority if that one is lower than its own
+    pub fn set_preempt(&mut self, preempt: bool) {
+        log::info!("Setting preemption of running downloads to {}", preempt);
+        self.preempt = preempt;
+        self.fill_slots();
+    }
+
+    fn has_free_slot(&self) -> bool {
+        self.is_window_open()
+            && (self.max_concurrent == 0 || self.running.len() < self.max_concurrent)
+    }
+
+    fn is_window_open(&self) -> bool {
+        self.windows.is_empty() || self.active_window.is_some()
+    }
+
+    pub fn bandwidth_limit(&self) -> Option<u64> {
+        self.bandwidth_limit
+    }
+
+    /// The limit of an open window takes precedence
+    pub fn set_bandwidth_limit(&mut self, bytes_per_second: Option<u64>) {
+        self.bandwidth_limit = bytes_per_second;
+        self.apply_bandwidth_limit();
+    }
+
+    fn apply_bandwidth_limit(&self) {
+        let window_limit = self
+            .active_window
+            .as_ref()
+            .and_then(|window| window.bandwidth_limit);
+        self.global_limiter
+            .set_limit(window_limit.or(self.bandwidth_limit));
+    }
+
+    pub fn windows(&self) -> Vec<TimeWindow> {
+        self.windows.clone()
+    }
+
+    /// Takes effect immediately, see `on_window_change`
+    pub fn set_windows(&mut self, windows: Vec<TimeWindow>) {
+        log::info!("Setting {} schedule windows", windows.len());
+        let was_open = self.is_window_open();
+        self.windows = windows;
+        self.active_window = schedule::active_window(&self.windows, &Local::now()).cloned();
+        self.on_window_change(was_open);
+    }
+
+    fn update_window(&mut self) {
+        let active = schedule::active_window(&self.windows, &Local::now()).cloned();
+        if active == self.active_window {
+            return;
+        }
+        let was_open = self.is_window_open();
+        self.active_window = active;
+        self.on_window_change(was_open);
+    }
+
+    /// Closing the queue stops the running downloads and puts them back at the front of the
+    /// queue, opening it starts queued downloads. The bandwidth limit follows the open window.
+    fn on_window_change(&mut self, was_open: bool) {
+        self.apply_bandwidth_limit();
+        match (was_open, self.is_window_open()) {
+            (true, false) => {
+                log::info!(
+                    "Schedule window closed, pausing {} downloads",
+                    self.running.len()
+                );
+                while let Some(id) = self.running.pop() {
+                    if let Some(item) = self.items.get_mut(&id) {
+                        let _ = item.stop();
+                    }
+                    self.enqueue(id, true, true);
+                }
+                self.announce_queue();
+            }
+            (false, true) => {
+                log::info!("Schedule window opened, starting queued downloads");
+                self.fill_slots();
+                self.announce_queue();
+            }
+            _ => {}
+        }
+    }
+
+    /// State of downloads that are queued, Scheduled while they wait for the next window
+    fn waiting_state(&self) -> download::State {
+        if self.is_window_open() {
+            return download::State::Queued;
+        }
+        match schedule::next_opening(&self.windows, &Local::now()) {
+            Some(at) => download::State::Scheduled {
+                at: at.timestamp_millis() as u64,
+            },
+            None => download::State::Queued,
+        }
+    }
+
+    fn announce_queue(&self) {
+        let state = self.waiting_state();
+        for (id, _) in self.queue.iter() {
+            self.send_state(id, state.clone());
+        }
+    }
+
+    /// Runs the downloads whose start time has come and follows the schedule windows
+    pub fn tick(&mut self) {
+        self.update_window();
+        let now = Local::now().timestamp_millis() as u64;
+        let due: Vec<(Uuid, bool)> = self
+            .scheduled
+            .iter()
+            .filter(|(_, (at, _))| *at <= now)
+            .map(|(id, (_, resume))| (*id, *resume))
+            .collect();
+        for (id, resume) in due {
+            self.scheduled.remove(&id);
+            log::info!("Start time of download {} has come", id);
+            if let Err(e) = self.run(&id, resume) {
+                log::warn!("Couldn't run scheduled download {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Runs the download at `at` (Unix timestamp in milliseconds) instead of now, a queued
+    /// download leaves the queue until then
+    pub fn schedule(&mut self, id: &Uuid, at: u64, resume: bool) -> Result<()> {
+        let item = self
+            .items
+            .get(id)
+            .ok_or_else(|| anyhow!("Download with id {} not found", id))?;
+        if item.is_in_flight() || self.running.contains(id) {
+            return Err(anyhow!("Download is running, stop it before scheduling it"));
+        }
+        log::info!("Scheduling download {} at {}", id, at);
+        self.queue.retain(|(queued, _)| queued != id);
+        self.scheduled.insert(*id, (at, resume));
+        self.send_state(id, download::State::Scheduled { at });
+        Ok(())
+    }
+
+    fn is_queued(&self, id: &Uuid) -> bool {
+        self.queue.iter().any(|(queued, _)| queued == id)
+    }
+
+    fn send_state(&self, id: &Uuid, state: download::State) {
+        let update = DownloadUpdate { id: *id, state };
+        if let Err(e) = self.update_ch.try_send(update) {
+            log::warn!("Couldn't send state update for download {}: {}", id, e);
+        }
+    }
+
+    fn spawn(&mut self, id: &Uuid, resume: bool) {
+        if let Some(item) = self.items.get_mut(id) {
+            log::info!("Starting download: {}, resume: {}", id, resume);
+            match item.download.try_write() {
+                Ok(mut download) => {
+                    let credential =
+                        download.prepare_run(&self.client_pool, self.credentials.as_ref());
+                    match credential {
+                        Some(credential) => self.run_credentials.insert(*id, credential),
+                        None => self.run_credentials.remove(id),
+                    };
+                }
+                Err(_) => log::warn!("Download {} is locked, keeping its client", id),
+            }
+            item.run(self.update_ch.clone(), self.finished_ch.clone(), resume);
+            self.running.push(*id);
+        }
+    }
+
+    /// Starts queued downloads until all slots are taken, preempting running downloads of a lower
+    /// priority if enabled.
+    /// Downloads whose previous task is still in flight (e.g. shutting down after being moved back
+    /// to the queue) are skipped, their Finished message triggers another attempt.
+    pub fn fill_slots(&mut self) {
+        loop {
+            let mut index = 0;
+            while self.has_free_slot() && index < self.queue.len() {
+                let (id, resume) = self.queue[index];
+                match self.items.get(&id) {
+                    None => {
+                        self.queue.remove(index);
+                    }
+                    Some(item) if item.is_in_flight() => index += 1,
+                    Some(_) => {
+                        self.queue.remove(index);
+                        self.spawn(&id, resume);
+                    }
+                }
+            }
+            if !self.preempt_lowest() {
+                break;
+            }
+        }
+    }
+
+    /// Stops the most recently started running download of the lowest priority and puts it back
+    /// at the front of its priority in the queue, if the next startable queued download has a
+    /// higher priority and no slot is free. Returns whether a slot was freed.
+    /// The stopped download can't be started again before its Finished message arrived.
+    fn preempt_lowest(&mut self) -> bool {
+        if !self.preempt || !self.is_window_open() || self.has_free_slot() {
+            return false;
+        }
+        let next = self
+            .queue
+            .iter()
+            .find(|(id, _)| self.items.get(id).is_some_and(|item| !item.is_in_flight()));
+        let Some((next, _)) = next else {
+            return false;
+        };
+        let Some(lowest) = self
+            .running
+            .iter()
+            .rev()
+            .min_by_key(|id| self.priority(id))
+            .copied()
+        else {
+            return false;
+        };
+        if self.priority(&lowest) >= self.priority(next) {
+            return false;
+        }
+        log::info!(
+            "Download {} gives up its slot to download {} of a higher priority",
+            lowest,
+            next
+        );
+        self.running.retain(|running| *running != lowest);
+        if let Some(item) = self.items.get_mut(&lowest) {
+            let _ = item.stop();
+        }
+        self.enqueue(lowest, true, true);
+        true
+    }
+
+    /// Releases the slot of a download whose task is over and hands it to the next queued one.
+    /// Returns the credential the run used together with the bytes it transferred, they are
+    /// yet to be recorded. Messages of an older run or of a removed download are ignored.
+    pub fn on_finished(&mut self, finished: &Finished) -> Option<(Uuid, u64)> {
+        let id = &finished.id;
+        match self.items.get_mut(id) {
+            Some(item) if item.run_id == finished.run_id => item.finished(),
+            _ => return None,
+        }
+        log::info!("Download {} finished, releasing its slot", id);
+        self.running.retain(|running| running != id);
+        if self.is_queued(id) {
+            // The download was moved back to the queue, its task reported it as paused since
+            self.send_state(id, self.waiting_state());
+        }
+        self.fill_slots();
+        self.run_credentials
+            .remove(id)
+            .filter(|_| finished.transferred > 0)
+            .map(|credential| (credential, finished.transferred))
+    }
+
     pub fn start_all(&mut self) {
         log::info!("Start/Resume all {} downloads", self.items.len());
-        for (id, item) in self.items.iter_mut() {
-            if item.is_locked() {
-                log::info!("HttpDownload: {} is locked, skipping...", id);
-                continue;
+        // Scheduled downloads keep waiting for their start time
+        let ids: Vec<Uuid> = self
+            .items
+            .keys()
+            .filter(|id| !self.scheduled.contains_key(id))
+            .copied()
+            .collect();
+        for id in ids.iter() {
+            if let Err(e) = self.run(id, true) {
+                log::info!("HttpDownload: {} skipped, {}", id, e);
             }
-            log::info!("Starting download: {}", id);
-            item.run(self.update_ch.clone(), true);
         }
     }
 
-    pub fn stop_all(&mut self) {
+    pub async fn stop_all(&mut self) {
         log::info!("Stopping all {} downloads", self.items.len());
-        for (id, item) in self.items.iter_mut() {
+        let ids: Vec<Uuid> = self.items.keys().copied().collect();
+        for id in ids.iter() {
             log::info!("Stopping download: {}", id);
-            let _ = item.stop();
+            let _ = self.stop(id).await;
         }
     }
 
+    /// Starts the download if a slot is free, otherwise it's queued.
+    /// A scheduled download is started right away, a download that is still stopping is queued
+    /// and starts once its previous task is over.
     pub fn run(&mut self, id: &Uuid, resume: bool) -> Result<()> {
-        if let Some(item) = self.items.get_mut(id) {
-            let update_ch = self.update_ch.clone();
-            if item.is_locked() {
-                return Err(anyhow!("Download is already locked, probably running already or locked up by pending operation!"));
-            }
-            item.run(update_ch, resume);
-            Ok(())
+        let item = self
+            .items
+            .get(id)
+            .ok_or_else(|| anyhow!("Download with id {} not found", id))?;
+        if self.is_queued(id) {
+            return Err(anyhow!("Download is already queued"));
+        }
+        if item.is_stopping() {
+            log::info!("Download {} is still stopping, queueing it", id);
+            self.scheduled.remove(id);
+            self.enqueue(*id, resume, true);
+            self.send_state(id, self.waiting_state());
+            return Ok(());
+        }
+        if item.is_in_flight() || self.running.contains(id) {
+            return Err(anyhow!("Download is already running"));
+        }
+        self.scheduled.remove(id);
+        if self.has_free_slot() {
+            self.spawn(id, resume);
         } else {
-            Err(anyhow!("Download with id {} not found", id))
+            log::info!("No free slot, queueing download {}", id);
+            self.enqueue(*id, resume, false);
+            self.send_state(id, self.waiting_state());
         }
+        Ok(())
     }
 
-    pub fn stop(&mut self, id: &Uuid) -> Result<()> {
+    pub async fn stop(&mut self, id: &Uuid) -> Result<()> {
         log::info!("Stop action requested for download: {}", id);
-        if let Some(item) = self.items.get_mut(id) {
-            log::info!("Stopping download {}", id);
-            item.stop()
-        } else {
-            Err(anyhow!("Download with id {} not found", id))
+        let item = self
+            .items
+            .get_mut(id)
+            .ok_or_else(|| anyhow!("Download with id {} not found", id))?;
+        let queued = self.queue.iter().position(|(queued, _)| queued == id);
+        if let Some(index) = queued {
+            log::info!("Removing download {} from the queue", id);
+            self.queue.remove(index);
+        }
+        if queued.is_some() || self.scheduled.remove(id).is_some() {
+            let bytes_on_disk = item.download.read().await.get_bytes_on_disk().await;
+            self.send_state(id, download::State::Paused(bytes_on_disk));
+            return Ok(());
         }
+        log::info!("Stopping download {}", id);
+        item.stop()
     }
 
     pub fn remove(&mut self, id: &Uuid) -> Option<DownloaderItem> {
         log::info!("Removing download: {}", id);
-        self.items.remove(id)
+        self.queue.retain(|(queued, _)| queued != id);
+        self.running.retain(|running| running != id);
+        self.run_credentials.remove(id);
+        self.scheduled.remove(id);
+        self.client_pool.release(id);
+        let item = self.items.remove(id);
+        self.fill_slots();
+        item
     }
 }
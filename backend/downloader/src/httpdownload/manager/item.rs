@@ -1,60 +1,98 @@
 use super::download;
-use super::download::{DownloadUpdate, HttpDownload};
+use super::download::DownloadUpdate;
 use crate::httpdownload::manager::Result;
-use crate::httpdownload::DownloadMetadata;
-use anyhow::anyhow;
+use crate::httpdownload::{Download, DownloadMetadata};
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot, Notify, RwLock};
+use tokio::sync::{mpsc, Notify, RwLock};
+use uuid::Uuid;
 
-/// Wrapper over HttpDownload to allow multi-threaded managing
-/// TODO: add packages to allow batching download commands
+/// Sent by a DownloaderItem once its download task is over and the download is unlocked again
+#[derive(Debug, Clone, Copy)]
+pub struct Finished {
+    pub id: Uuid,
+    pub run_id: u64,
+    /// Bytes the run received, charged to the credential the run used
+    pub transferred: u64,
+}
+
+/// Wrapper over a Download to allow multi-threaded managing
 #[derive(Debug)]
 pub struct DownloaderItem {
-    pub(super) download: Arc<RwLock<HttpDownload>>,
+    pub(super) download: Arc<RwLock<Box<dyn Download>>>,
     /// This sender contains the channel to notify the thread to stop the download function
     notifier: Option<Arc<Notify>>,
+    /// Incremented on every run, tells apart the Finished message of an older run
+    pub(super) run_id: u64,
+    /// Queued downloads of a higher priority are started first
+    pub(super) priority: i32,
+    /// Set by `run` until the manager handled the Finished message of the run, a stopped
+    /// download keeps its task until the task reacted to the stop
+    in_flight: bool,
 }
 
 impl DownloaderItem {
-    pub fn new(download: HttpDownload) -> Self {
+    pub fn new(download: Box<dyn Download>) -> Self {
         DownloaderItem {
             download: Arc::new(RwLock::new(download)),
             notifier: None,
+            run_id: 0,
+            priority: 0,
+            in_flight: false,
         }
     }
 
-    pub fn is_locked(&self) -> bool {
-        self.download.try_read().is_err()
+    /// Whether a task of the download is still around, no other run may start until it's over
+    pub fn is_in_flight(&self) -> bool {
+        self.in_flight
+    }
+
+    /// Whether the download was told to stop and its task didn't finish yet
+    pub fn is_stopping(&self) -> bool {
+        self.in_flight && self.notifier.is_none()
+    }
+
+    /// Called by the manager once it received the Finished message of the current run
+    pub(super) fn finished(&mut self) {
+        self.in_flight = false;
+        self.notifier = None;
     }
 
-    pub fn run(&mut self, update_ch: mpsc::Sender<DownloadUpdate>, resume: bool) {
+    pub fn run(
+        &mut self,
+        update_ch: mpsc::Sender<DownloadUpdate>,
+        finished_ch: mpsc::UnboundedSender<Finished>,
+        resume: bool,
+    ) {
         let notifier = Arc::new(Notify::new());
         self.notifier = Some(notifier.clone());
+        self.run_id += 1;
+        self.in_flight = true;
+        let run_id = self.run_id;
         let download_arc = self.download.clone();
         tokio::spawn(async move {
             let download = download_arc.read().await;
+            let id = download.id();
             log::info!(
                 "Acquired read lock for download: {}, resume: {}",
-                download.id,
+                id,
                 resume
             );
 
+            let received = download.bytes_received();
             let update_ch_cl = update_ch.clone();
             let download_task = async {
                 if resume {
-                    log::info!("Resuming download: {}", download.id);
                     download.resume(update_ch_cl).await
                 } else {
-                    log::info!("Starting download: {}", download.id);
                     download.start(update_ch_cl).await
                 }
             };
             let update = tokio::select! {
                 _ = notifier.notified() => {
-                    log::info!("Stopping download: {}", download.id);
+                    log::info!("Stopping download: {}", id);
                     let downloaded_bytes = download.get_bytes_on_disk().await;
                     DownloadUpdate {
-                        id: download.id,
+                        id,
                         state: download::State::Paused(downloaded_bytes),
                     }
                 }
@@ -62,18 +100,18 @@ impl DownloaderItem {
                     match download_result {
                         Ok(_) => {
                             DownloadUpdate {
-                                id: download.id,
+                                id,
                                 state: download::State::Complete,
                             }
                         }
                         Err(e) => {
                             log::error!(
                                 "Error encountered while downloading {}, Error: {}",
-                                download.id,
+                                id,
                                 e
                             );
                             DownloadUpdate {
-                                id: download.id,
+                                id,
                                 state: download::State::Error(format!("{}", e)),
                             }
                         }
@@ -81,6 +119,14 @@ impl DownloaderItem {
                 }
             };
             let _ = update_ch.send(update).await;
+            let transferred = download.bytes_received() - received;
+            // Release the lock before reporting, so the download can be run again right away
+            drop(download);
+            let _ = finished_ch.send(Finished {
+                id,
+                run_id,
+                transferred,
+            });
         });
     }
 
@@ -1,24 +1,73 @@
 mod inner;
 mod item;
+mod packages;
 
+use crate::credentials::CredentialStore;
+use crate::hoster::HosterRegistry;
 use crate::httpdownload::download;
+use crate::httpdownload::download::config::HttpDownloadConfig;
 use crate::httpdownload::download::{DownloadUpdate, HttpDownload};
+use crate::hyperdownload;
+use crate::persistence::SqliteStore;
+use crate::proxy::ClientPool;
+use crate::schedule::TimeWindow;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use uuid::Uuid;
 
 use self::inner::ManagerInner;
+pub use self::packages::PackageIndex;
 
 use super::observer::{DownloadObserver, DownloadUpdateBuffer};
-use super::{DownloadMetadata, Subscribers};
+use super::package::Package;
+use super::{
+    Download, DownloadMetadata, DownloadUpdateSubscriber, LagPolicy, Subscribers, Subscription,
+};
 
 pub type Result<T> = anyhow::Result<T>;
 
+/// How often start times and schedule windows are checked
+const SCHEDULER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 /// Trait for a struct that can handle DownloadUpdates.
 pub trait UpdateConsumer {
     fn consume(&mut self, update: DownloadUpdate);
 }
 
+/// What `DownloadManager::add` adds, any Download converts into it
+// Only ever passed to `add`, never stored
+#[allow(clippy::large_enum_variant)]
+pub enum NewDownload {
+    /// Created by the manager, hoster pages are resolved by the HosterRegistry
+    Url {
+        url: Url,
+        directory: PathBuf,
+        filename: Option<String>,
+        config: HttpDownloadConfig,
+    },
+    Download(Box<dyn Download>),
+}
+
+impl<D: Download + 'static> From<D> for NewDownload {
+    fn from(download: D) -> Self {
+        Self::Download(Box::new(download))
+    }
+}
+
+/// Where `DownloadManager::move_in_queue` puts a queued download
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "position", content = "id", rename_all = "snake_case")]
+pub enum QueuePosition {
+    Top,
+    Bottom,
+    Before(Uuid),
+    After(Uuid),
+}
+
 /// This struct takes care of storing/running/stopping downloads.
 /// Internally it uses a RwLock to allow for concurrent access,
 /// this exposes a thread-safe interface.
@@ -28,24 +77,147 @@ pub struct DownloadManager {
     inner: Arc<RwLock<ManagerInner>>,
     subscribers: Subscribers,
     pub observer: DownloadObserver,
+    /// Proxied clients, every start or resume of a download picks its client from the pool
+    pub clients: ClientPool,
+    /// Resolvers of file hosters, consulted by `add` for URLs
+    pub hosters: HosterRegistry,
+    store: Option<SqliteStore>,
+    packages: Arc<RwLock<HashMap<Uuid, Package>>>,
 }
 
 impl DownloadManager {
     /// The update_consumer is placed in a separate thread and will receive all updates from all downloads.
     pub async fn new() -> Self {
+        Self::build(None).await
+    }
+
+    /// Creates a manager that keeps every download and its last known state in the store.
+    /// Downloads and packages found in the store are added back to the manager, none of the
+    /// downloads is running.
+    /// HttpDownloads are restored by the manager itself, other kinds need a RestoreFn registered
+    /// with the store beforehand.
+    pub async fn with_persistence(store: SqliteStore) -> Result<Self> {
+        store.register_kind(HttpDownload::KIND, HttpDownload::restore);
+        let manager = Self::build(Some(store.clone())).await;
+        let downloads = store.load_downloads().await?;
+        let priorities = store.load_priorities().await?;
+        manager.clients.restore_pins(store.load_pins().await?);
+        log::info!("Restoring {} downloads from the database", downloads.len());
+        let mut inner = manager.inner.write().await;
+        for (download, state) in downloads.into_iter() {
+            let state = match state {
+                download::State::Running { .. }
+                | download::State::Queued
+                | download::State::Verifying
+                | download::State::Retrying { .. }
+                | download::State::Seeding { .. } => {
+                    download::State::Paused(download.get_bytes_on_disk().await)
+                }
+                state => state,
+            };
+            let id = inner.add(download);
+            if let Some(priority) = priorities.get(&id) {
+                inner.set_priority(&id, *priority)?;
+            }
+            if let download::State::Scheduled { at } = state {
+                inner.schedule(&id, at, true)?;
+            }
+            manager.observer.track(id, state).await;
+        }
+        let mut packages = manager.packages.write().await;
+        for mut package in store.load_packages().await?.into_iter() {
+            package
+                .download_ids
+                .retain(|id| inner.items.contains_key(id));
+            packages.insert(package.id, package);
+        }
+        drop(packages);
+        drop(inner);
+        Ok(manager)
+    }
+
+    async fn build(store: Option<SqliteStore>) -> Self {
         let observer = DownloadObserver::new();
         let buffer = DownloadUpdateBuffer::new();
-        buffer.add_subscriber(observer.clone()).await;
+        // The observer and the store must see every update
+        buffer
+            .add_subscriber(observer.clone(), LagPolicy::Unbounded)
+            .detach();
+        if let Some(store) = store.as_ref() {
+            buffer
+                .add_subscriber(store.clone(), LagPolicy::Unbounded)
+                .detach();
+        }
         let subscribers = buffer.subscribers.clone();
-        let inner = Arc::new(RwLock::new(ManagerInner::new(buffer)));
+        let (finished_sender, mut finished_recv) = mpsc::unbounded_channel();
+        let inner = ManagerInner::new(buffer, finished_sender);
+        let clients = inner.client_pool.clone();
+        clients.spawn_health_checks();
+        let inner = Arc::new(RwLock::new(inner));
+        let weak_inner = Arc::downgrade(&inner);
+        tokio::task::spawn(async move {
+            while let Some(finished) = finished_recv.recv().await {
+                let Some(inner) = weak_inner.upgrade() else {
+                    break;
+                };
+                let mut inner = inner.write().await;
+                let usage = inner.on_finished(&finished);
+                let credentials = inner.credentials.clone();
+                drop(inner);
+                if let (Some((credential, bytes)), Some(credentials)) = (usage, credentials) {
+                    if let Err(e) = credentials.record_usage(&credential, bytes).await {
+                        log::error!("Couldn't record usage of credential {}: {}", credential, e);
+                    }
+                }
+            }
+        });
+        let weak_inner = Arc::downgrade(&inner);
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(inner) = weak_inner.upgrade() else {
+                    break;
+                };
+                inner.write().await.tick();
+            }
+        });
 
         Self {
             inner,
             subscribers,
             observer,
+            clients,
+            hosters: HosterRegistry::default(),
+            store,
+            packages: Default::default(),
         }
     }
 
+    /// Credentials of the store are applied to every download when it's started or resumed.
+    /// Premium accounts are charged with the bytes a run transferred once it's over.
+    pub async fn set_credentials(&self, credentials: CredentialStore) {
+        self.inner.write().await.credentials = Some(credentials);
+    }
+
+    /// Registers a new subscriber that will receive every batch of updates flushed by the
+    /// DownloadUpdateBuffer until the returned Subscription is dropped.
+    /// Batches are dropped for a subscriber that falls too far behind, see LagPolicy.
+    pub fn add_subscriber(
+        &self,
+        subscriber: impl DownloadUpdateSubscriber + Send + Sync + 'static,
+    ) -> Subscription {
+        self.subscribers.add(subscriber, LagPolicy::default())
+    }
+
+    pub fn add_subscriber_with_policy(
+        &self,
+        subscriber: impl DownloadUpdateSubscriber + Send + Sync + 'static,
+        policy: LagPolicy,
+    ) -> Subscription {
+        self.subscribers.add(subscriber, policy)
+    }
+
     pub async fn start(&self, id: &Uuid) -> Result<()> {
         let mut inner = self.inner.write().await;
         inner.run(id, false)
@@ -58,7 +230,7 @@ impl DownloadManager {
 
     pub async fn stop(&self, id: &Uuid) -> Result<()> {
         let mut inner = self.inner.write().await;
-        inner.stop(id)
+        inner.stop(id).await
     }
 
     pub async fn start_all(&self) {
@@ -68,7 +240,125 @@ impl DownloadManager {
 
     pub async fn stop_all(&self) {
         let mut inner = self.inner.write().await;
-        inner.stop_all()
+        inner.stop_all().await
+    }
+
+    /// Limits how many downloads run at the same time, 0 means unlimited.
+    /// Downloads started while all slots are taken are queued and start as soon as a slot frees up.
+    pub async fn set_max_concurrent_downloads(&self, max_concurrent_downloads: usize) {
+        let mut inner = self.inner.write().await;
+        inner.set_max_concurrent(max_concurrent_downloads)
+    }
+
+    pub async fn max_concurrent_downloads(&self) -> usize {
+        self.inner.read().await.max_concurrent()
+    }
+
+    /// Limits the combined throughput of all downloads in bytes per second, None for no limit.
+    /// Running downloads are throttled right away, an open schedule window with a limit of its
+    /// own overrides it.
+    pub async fn set_bandwidth_limit(&self, bytes_per_second: Option<u64>) {
+        log::info!("Setting global bandwidth limit to {:?}", bytes_per_second);
+        self.inner
+            .write()
+            .await
+            .set_bandwidth_limit(bytes_per_second)
+    }
+
+    pub async fn bandwidth_limit(&self) -> Option<u64> {
+        self.inner.read().await.bandwidth_limit()
+    }
+
+    /// Downloads only run while one of the weekly windows is open, running downloads are
+    /// paused when the last one closes and resumed once the next one opens. Without windows
+    /// downloads run at any time.
+    pub async fn set_schedule(&self, windows: Vec<TimeWindow>) {
+        self.inner.write().await.set_windows(windows)
+    }
+
+    pub async fn schedule(&self) -> Vec<TimeWindow> {
+        self.inner.read().await.windows()
+    }
+
+    /// Starts (or resumes) the download at `at` (Unix timestamp in milliseconds), observers see
+    /// `State::Scheduled` until then. Starting or stopping the download cancels its start time.
+    pub async fn schedule_download(&self, id: &Uuid, at: u64) -> Result<()> {
+        self.inner.write().await.schedule(id, at, true)
+    }
+
+    /// Limits a single download in bytes per second, on top of the global limit.
+    /// The limit is stored with the download and applies to a running download right away.
+    /// Fails for downloads that don't support limits.
+    pub async fn set_download_bandwidth_limit(
+        &self,
+        id: &Uuid,
+        bytes_per_second: Option<u64>,
+    ) -> Result<()> {
+        let inner = self.inner.read().await;
+        let item = inner
+            .items
+            .get(id)
+            .ok_or_else(|| anyhow::anyhow!("Download with id {} does not exist", id))?;
+        // A running download holds a read lock as well
+        let download = item.download.read().await;
+        if !download.set_bandwidth_limit(bytes_per_second) {
+            anyhow::bail!("Download {} doesn't support bandwidth limits", id);
+        }
+        if let Some(store) = self.store.as_ref() {
+            store.update_download(download.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn priority(&self, id: &Uuid) -> i32 {
+        self.inner.read().await.priority(id)
+    }
+
+    /// Downloads have priority 0 until it's changed, queued downloads of a higher priority are
+    /// started first. With preemption enabled a running download of a lower priority is stopped
+    /// and queued again in favour of a queued one.
+    pub async fn set_priority(&self, id: &Uuid, priority: i32) -> Result<()> {
+        self.inner.write().await.set_priority(id, priority)?;
+        if let Some(store) = self.store.as_ref() {
+            store.update_priority(id, priority).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves a queued download to the top or bottom of the queue or next to another queued
+    /// download. It takes the priority of its new neighbour, so moving a download to the top
+    /// can raise its priority and preempt a running download.
+    pub async fn move_in_queue(&self, id: &Uuid, position: QueuePosition) -> Result<()> {
+        let priority = self.inner.write().await.move_in_queue(id, position)?;
+        if let Some(store) = self.store.as_ref() {
+            store.update_priority(id, priority).await?;
+        }
+        Ok(())
+    }
+
+    /// Every future run of the download uses the proxy, None removes the pin.
+    /// Pins are kept in the store and restored on the next start.
+    pub async fn pin_download(&self, id: &Uuid, proxy: Option<String>) -> Result<()> {
+        self.clients.pin_download(id, proxy.clone())?;
+        if let Some(store) = self.store.as_ref() {
+            store.update_pin(id, proxy).await?;
+        }
+        Ok(())
+    }
+
+    /// Lets queued downloads take the slot of running downloads of a lower priority, disabled by
+    /// default
+    pub async fn set_preemption(&self, preempt: bool) {
+        self.inner.write().await.set_preempt(preempt)
+    }
+
+    pub async fn preemption(&self) -> bool {
+        self.inner.read().await.preempt()
+    }
+
+    /// Ids of the queued downloads, next in line first
+    pub async fn queue(&self) -> Vec<Uuid> {
+        self.inner.read().await.queue()
     }
 
     pub async fn get_metadata(&self, id: &Uuid) -> Result<DownloadMetadata> {
@@ -81,28 +371,95 @@ impl DownloadManager {
         inner.get_metadata_all().await
     }
 
-    pub async fn add(&self, download: HttpDownload) -> Uuid {
+    /// Adds the download and returns the ids of the added downloads.
+    /// A URL is created into an HttpDownload in `directory`. If a resolver is registered for its
+    /// host, it's a hoster page: the resolver runs before anything is requested from the page and
+    /// a download is added for every file it finds, `filename` only applies to direct downloads.
+    /// The targets of the downloads of the manager count as taken for the CollisionPolicy, even
+    /// if they weren't started yet.
+    /// Downloads created by the caller, e.g. a TorrentDownload, are added as they are.
+    pub async fn add(&self, download: impl Into<NewDownload>) -> Result<Vec<Uuid>> {
+        let (url, directory, filename, config) = match download.into() {
+            NewDownload::Url {
+                url,
+                directory,
+                filename,
+                config,
+            } => (url, directory, filename, config),
+            NewDownload::Download(download) => {
+                let mut inner = self.inner.write().await;
+                return Ok(vec![self.insert(&mut inner, download).await]);
+            }
+        };
+        let client = self.clients.client_for(&url);
+        let mut downloads = match self.hosters.resolver_for(&url) {
+            Some(resolver) => {
+                log::info!("Resolving hoster page {}", url);
+                resolver.resolve(&url, &directory, &config, &client).await?
+            }
+            None => {
+                vec![HttpDownload::create(url, directory, filename, client, Some(config)).await?]
+            }
+        };
+        // Held until the downloads are added, so concurrent calls see each other's targets
         let mut inner = self.inner.write().await;
+        let mut reserved: HashSet<PathBuf> = inner
+            .get_metadata_all()
+            .await
+            .into_iter()
+            .map(|metadata| metadata.file_path)
+            .collect();
+        for download in downloads.iter_mut() {
+            download.avoid_collisions(&reserved)?;
+            reserved.insert(download.file_path());
+        }
+        let mut ids = Vec::with_capacity(downloads.len());
+        for download in downloads {
+            ids.push(self.insert(&mut inner, Box::new(download)).await);
+        }
+        Ok(ids)
+    }
+
+    async fn insert(&self, inner: &mut ManagerInner, download: Box<dyn Download>) -> Uuid {
+        let state = download::State::Paused(0);
+        if let Some(store) = self.store.as_ref() {
+            if let Err(e) = store.save_download(download.as_ref(), &state).await {
+                log::error!("Couldn't persist download {}: {}", download.id(), e);
+            }
+        }
         let id = inner.add(download);
-        self.observer.track(id, download::State::Paused(0)).await;
+        self.observer.track(id, state).await;
         id
     }
 
     pub async fn delete(&self, id: &Uuid, delete_file: bool) -> Result<()> {
         let mut inner = self.inner.write().await;
-        let _ = inner.stop(id); // ignore error
+        let _ = inner.stop(id).await; // ignore error
         if let Some(item) = inner.remove(id) {
             if delete_file {
                 let file_path = item.get_metadata().await.file_path;
-                if let Err(e) = tokio::fs::remove_file(file_path).await {
+                let _ = tokio::fs::remove_file(hyperdownload::segments_path(&file_path)).await;
+                let _ = tokio::fs::remove_file(download::part_path(&file_path)).await;
+                // Multi-file torrents are stored in a directory
+                let removed = if file_path.is_dir() {
+                    tokio::fs::remove_dir_all(file_path).await
+                } else {
+                    tokio::fs::remove_file(file_path).await
+                };
+                if let Err(e) = removed {
                     log::warn!(
                         "Couldn't delete file for httpdownload after removing from manager: {}",
                         e
                     );
                 };
             }
-            self.observer.untrack(id).await
+            self.observer.untrack(id).await;
+            if let Some(store) = self.store.as_ref() {
+                store.delete_download(id).await?;
+            }
         };
+        drop(inner);
+        self.remove_from_packages(id).await;
         Ok(())
     }
 }
@@ -110,7 +467,17 @@ impl DownloadManager {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::credentials::Credential;
+    use crate::hoster::token::TokenHosterConfig;
+    use crate::httpdownload::download::filename::CollisionPolicy;
+    use crate::p2pdownload::metainfo::Metainfo;
+    use crate::p2pdownload::{TorrentConfig, TorrentDownload};
+    use crate::proxy::{ClientPoolConfig, ProxyConfig};
+    use crate::util::mock_hoster::MockHoster;
+    use crate::util::test_server::{test_payload, TestServer};
+    use crate::util::test_tracker::TestTracker;
     use crate::util::{file_size, setup_test_download};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use test_log::test;
     use tokio::time;
 
@@ -118,12 +485,51 @@ mod test {
         "https://dl.google.com/linux/direct/google-chrome-stable_current_amd64.deb";
     type Test<T> = anyhow::Result<T>;
 
+    async fn add_local_downloads(
+        manager: &DownloadManager,
+        server: &TestServer,
+        count: usize,
+    ) -> Test<(Vec<Uuid>, tempfile::TempDir)> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let mut ids = Vec::new();
+        for i in 0..count {
+            let download = HttpDownload::create(
+                server.url.clone(),
+                tmp_dir.path().to_owned(),
+                Some(format!("file-{}.bin", i)),
+                reqwest::Client::new(),
+                None,
+            )
+            .await?;
+            ids.push(manager.add(download).await?[0]);
+        }
+        Ok((ids, tmp_dir))
+    }
+
+    async fn wait_until_complete(manager: &DownloadManager, ids: &[Uuid]) {
+        time::timeout(time::Duration::from_secs(30), async {
+            loop {
+                let mut complete = true;
+                for id in ids {
+                    let state = manager.observer.get_state(id).await;
+                    complete &= matches!(state, Some(download::State::Complete));
+                }
+                if complete {
+                    break;
+                }
+                time::sleep(time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("Downloads should complete");
+    }
+
     #[test(tokio::test)]
     async fn start_stop_delete_download() -> Test<()> {
         let manager = DownloadManager::new().await;
         let (download, _tmp_dir) = setup_test_download(TEST_DOWNLOAD_URL).await?;
-        let download_path = download.file_path();
-        let id = manager.add(download).await;
+        let download_path = download.part_path();
+        let id = manager.add(download).await?[0];
         manager.start(&id).await?;
         // check metadata as expected
         let metadata = manager.get_metadata_all().await;
@@ -147,4 +553,776 @@ mod test {
         );
         Ok(())
     }
+
+    #[test(tokio::test)]
+    async fn downloads_are_restored_from_store() -> Test<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let db_path = tmp_dir.path().join("ludownloader.db");
+        let download = HttpDownload {
+            url: reqwest::Url::parse("https://somewebsite.biz/api/v1/big-ass-file.fantasy")?,
+            id: Uuid::new_v4(),
+            directory: tmp_dir.path().to_owned(),
+            filename: "big-ass-file.fantasy".to_string(),
+            config: Default::default(),
+            content_length: Some(1024),
+            supports_byte_ranges: true,
+            etag: None,
+            last_modified: None,
+            limiter: Default::default(),
+            global_limiter: Default::default(),
+            received: Default::default(),
+            in_place: Default::default(),
+            client: reqwest::Client::new(),
+        };
+        tokio::fs::write(download.part_path(), [0u8; 100]).await?;
+        // Managers are kept alive until the end of the test, dropping one terminates its update
+        // consumer and with it the whole process.
+        let store = SqliteStore::open(&db_path).await?;
+        let first_manager = DownloadManager::with_persistence(store.clone()).await?;
+        let id = first_manager.add(download).await?[0];
+        first_manager.set_priority(&id, 5).await?;
+        first_manager.clients.configure(ClientPoolConfig {
+            proxies: vec![ProxyConfig {
+                name: "vpn".to_string(),
+                url: "http://127.0.0.1:3128".to_string(),
+            }],
+            ..Default::default()
+        })?;
+        first_manager
+            .pin_download(&id, Some("vpn".to_string()))
+            .await?;
+        // simulate a crash while the download was running
+        store
+            .update_states(&[(
+                id,
+                download::State::Running {
+                    bytes_downloaded: 100,
+                    bytes_per_second: 10,
+                    average_bytes_per_second: 10,
+                    percentage: None,
+                    eta_seconds: None,
+                },
+            )])
+            .await?;
+        let manager = DownloadManager::with_persistence(SqliteStore::open(&db_path).await?).await?;
+        let metadata = manager.get_metadata(&id).await?;
+        assert_eq!(metadata.download_size, Some(1024));
+        let state = manager.observer.get_state(&id).await;
+        assert!(matches!(state, Some(download::State::Paused(100))));
+        assert_eq!(manager.priority(&id).await, 5);
+        assert_eq!(manager.clients.pin_of(&id), Some("vpn".to_string()));
+
+        manager.delete(&id, false).await?;
+        let last_manager =
+            DownloadManager::with_persistence(SqliteStore::open(&db_path).await?).await?;
+        assert!(last_manager.get_metadata_all().await.is_empty());
+        assert_eq!(last_manager.clients.pin_of(&id), None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn downloads_beyond_limit_are_queued() -> Test<()> {
+        let server = TestServer::spawn(test_payload(4 * 1024 * 1024));
+        let manager = DownloadManager::new().await;
+        manager.set_max_concurrent_downloads(1).await;
+        let (ids, tmp_dir) = add_local_downloads(&manager, &server, 3).await?;
+        for id in ids.iter() {
+            manager.start(id).await?;
+        }
+        assert_eq!(manager.queue().await, ids[1..]);
+        assert!(
+            manager.start(&ids[1]).await.is_err(),
+            "Queued downloads can't be started twice"
+        );
+        wait_until_complete(&manager, &ids).await;
+        assert!(manager.queue().await.is_empty());
+        for i in 0..ids.len() {
+            let file_path = tmp_dir.path().join(format!("file-{}.bin", i));
+            assert_eq!(file_size(&file_path).await, server.payload.len() as u64);
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn stopping_a_queued_download_removes_it_from_the_queue() -> Test<()> {
+        let server = TestServer::spawn(test_payload(4 * 1024 * 1024));
+        let manager = DownloadManager::new().await;
+        manager.set_max_concurrent_downloads(1).await;
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 3).await?;
+        for id in ids.iter() {
+            manager.start(id).await?;
+        }
+        manager.stop(&ids[1]).await?;
+        assert_eq!(manager.queue().await, vec![ids[2]]);
+        wait_until_complete(&manager, &[ids[0], ids[2]]).await;
+        let state = manager.observer.get_state(&ids[1]).await;
+        assert!(matches!(state, Some(download::State::Paused(0))));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn queued_downloads_are_ordered_by_priority() -> Test<()> {
+        let server = TestServer::spawn(test_payload(4 * 1024 * 1024));
+        let manager = DownloadManager::new().await;
+        manager.set_max_concurrent_downloads(1).await;
+        // Keeps the first download running for the whole test
+        manager.set_bandwidth_limit(Some(256 * 1024)).await;
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 4).await?;
+        for id in ids.iter() {
+            manager.start(id).await?;
+        }
+        manager.set_priority(&ids[3], 2).await?;
+        assert_eq!(manager.queue().await, vec![ids[3], ids[1], ids[2]]);
+
+        manager.move_in_queue(&ids[2], QueuePosition::Top).await?;
+        assert_eq!(manager.queue().await, vec![ids[2], ids[3], ids[1]]);
+        assert_eq!(manager.priority(&ids[2]).await, 2);
+        manager
+            .move_in_queue(&ids[1], QueuePosition::Before(ids[3]))
+            .await?;
+        assert_eq!(manager.queue().await, vec![ids[2], ids[1], ids[3]]);
+        manager
+            .move_in_queue(&ids[2], QueuePosition::After(ids[3]))
+            .await?;
+        assert_eq!(manager.queue().await, vec![ids[1], ids[3], ids[2]]);
+        manager
+            .move_in_queue(&ids[1], QueuePosition::Bottom)
+            .await?;
+        assert_eq!(manager.queue().await, vec![ids[3], ids[2], ids[1]]);
+
+        assert!(
+            manager
+                .move_in_queue(&ids[0], QueuePosition::Top)
+                .await
+                .is_err(),
+            "Running downloads can't be moved in the queue"
+        );
+        // Without preemption the running download keeps its slot
+        manager.set_priority(&ids[1], 10).await?;
+        assert_eq!(manager.queue().await, vec![ids[1], ids[3], ids[2]]);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn higher_priority_downloads_preempt_running_ones() -> Test<()> {
+        let server = TestServer::spawn(test_payload(4 * 1024 * 1024));
+        let manager = DownloadManager::new().await;
+        manager.set_max_concurrent_downloads(1).await;
+        manager.set_bandwidth_limit(Some(256 * 1024)).await;
+        manager.set_preemption(true).await;
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 2).await?;
+        manager.start(&ids[0]).await?;
+        manager.start(&ids[1]).await?;
+        assert_eq!(manager.queue().await, vec![ids[1]]);
+
+        manager.set_priority(&ids[1], 1).await?;
+        assert_eq!(manager.queue().await, vec![ids[0]]);
+        wait_for_state(&manager, &ids[1], |state| {
+            matches!(state, download::State::Running { .. })
+        })
+        .await;
+        wait_for_state(&manager, &ids[0], |state| {
+            matches!(state, download::State::Queued)
+        })
+        .await;
+
+        manager.set_preemption(false).await;
+        manager.set_priority(&ids[0], 2).await?;
+        assert_eq!(manager.queue().await, vec![ids[0]]);
+        manager.set_preemption(true).await;
+        assert_eq!(manager.queue().await, vec![ids[1]]);
+        wait_for_state(&manager, &ids[0], |state| {
+            matches!(state, download::State::Running { .. })
+        })
+        .await;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn pending_downloads_of_the_same_url_get_their_own_file() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let manager = DownloadManager::new().await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let added = manager
+                .add(NewDownload::Url {
+                    url: server.url.clone(),
+                    directory: tmp_dir.path().to_owned(),
+                    filename: None,
+                    config: HttpDownloadConfig::default(),
+                })
+                .await?;
+            ids.extend(added);
+        }
+        let paths = [
+            manager.get_metadata(&ids[0]).await?.file_path,
+            manager.get_metadata(&ids[1]).await?.file_path,
+        ];
+        assert_eq!(
+            paths,
+            [
+                tmp_dir.path().join("file.bin"),
+                tmp_dir.path().join("file (1).bin")
+            ]
+        );
+        let config = HttpDownloadConfig {
+            on_collision: CollisionPolicy::Overwrite,
+            ..Default::default()
+        };
+        assert!(manager
+            .add(NewDownload::Url {
+                url: server.url.clone(),
+                directory: tmp_dir.path().to_owned(),
+                filename: None,
+                config,
+            })
+            .await
+            .is_err());
+
+        for id in ids.iter() {
+            manager.start(id).await?;
+        }
+        wait_until_complete(&manager, &ids).await;
+        for path in paths.iter() {
+            assert_eq!(tokio::fs::read(path).await?, *server.payload);
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn raising_the_limit_starts_queued_downloads() -> Test<()> {
+        let server = TestServer::spawn(test_payload(4 * 1024 * 1024));
+        let manager = DownloadManager::new().await;
+        manager.set_max_concurrent_downloads(1).await;
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 3).await?;
+        for id in ids.iter() {
+            manager.start(id).await?;
+        }
+        assert_eq!(manager.queue().await.len(), 2);
+        manager.set_max_concurrent_downloads(0).await;
+        assert!(manager.queue().await.is_empty());
+        assert_eq!(manager.max_concurrent_downloads().await, 0);
+        wait_until_complete(&manager, &ids).await;
+        Ok(())
+    }
+
+    async fn wait_for_state(
+        manager: &DownloadManager,
+        id: &Uuid,
+        predicate: impl Fn(&download::State) -> bool,
+    ) -> download::State {
+        time::timeout(time::Duration::from_secs(10), async {
+            loop {
+                match manager.observer.get_state(id).await {
+                    Some(state) if predicate(&state) => break state,
+                    _ => time::sleep(time::Duration::from_millis(50)).await,
+                }
+            }
+        })
+        .await
+        .expect("Download should reach the state")
+    }
+
+    fn now_millis() -> u64 {
+        chrono::Local::now().timestamp_millis() as u64
+    }
+
+    #[test(tokio::test)]
+    async fn scheduled_downloads_start_at_their_time() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let manager = DownloadManager::new().await;
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 1).await?;
+        let at = now_millis() + 1500;
+        manager.schedule_download(&ids[0], at).await?;
+        let state = wait_for_state(&manager, &ids[0], |state| {
+            matches!(state, download::State::Scheduled { .. })
+        })
+        .await;
+        assert!(matches!(state, download::State::Scheduled { at: scheduled } if scheduled == at));
+        // Scheduled downloads wait for their time even if everything is started
+        manager.start_all().await;
+        assert!(matches!(
+            manager.observer.get_state(&ids[0]).await,
+            Some(download::State::Scheduled { .. })
+        ));
+        wait_until_complete(&manager, &ids).await;
+        assert!(now_millis() >= at);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn downloads_only_run_inside_schedule_windows() -> Test<()> {
+        use chrono::{Datelike, NaiveTime};
+
+        let server = TestServer::spawn(test_payload(4 * 1024 * 1024));
+        let manager = DownloadManager::new().await;
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 1).await?;
+        let midnight = NaiveTime::MIN;
+        let closed = TimeWindow {
+            days: vec![chrono::Local::now().weekday().succ().succ()],
+            start: midnight,
+            end: NaiveTime::from_hms_opt(0, 1, 0).unwrap(),
+            bandwidth_limit: None,
+        };
+        // A window ending at its start lasts the whole day
+        let open = TimeWindow {
+            days: Vec::new(),
+            start: midnight,
+            end: midnight,
+            bandwidth_limit: Some(256 * 1024),
+        };
+        manager.set_schedule(vec![closed.clone()]).await;
+        manager.start(&ids[0]).await?;
+        assert_eq!(manager.queue().await, ids);
+        let state = wait_for_state(&manager, &ids[0], |state| {
+            matches!(state, download::State::Scheduled { .. })
+        })
+        .await;
+        assert!(matches!(state, download::State::Scheduled { at } if at > now_millis()));
+
+        manager.set_schedule(vec![open]).await;
+        assert!(manager.queue().await.is_empty());
+        assert_eq!(
+            manager.inner.read().await.global_limiter.limit(),
+            Some(256 * 1024)
+        );
+        wait_for_state(&manager, &ids[0], |state| {
+            matches!(state, download::State::Running { .. })
+        })
+        .await;
+
+        // Closing the window pauses the running download until the next one opens
+        manager.set_schedule(vec![closed]).await;
+        assert_eq!(manager.queue().await, ids);
+        assert_eq!(manager.inner.read().await.global_limiter.limit(), None);
+        wait_for_state(&manager, &ids[0], |state| {
+            matches!(state, download::State::Scheduled { .. })
+        })
+        .await;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn global_bandwidth_limit_throttles_downloads() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let manager = DownloadManager::new().await;
+        manager.set_bandwidth_limit(Some(512 * 1024)).await;
+        assert_eq!(manager.bandwidth_limit().await, Some(512 * 1024));
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 1).await?;
+        let start = time::Instant::now();
+        manager.start(&ids[0]).await?;
+        wait_until_complete(&manager, &ids).await;
+        // The first half is covered by the full bucket, the second half takes a second
+        assert!(start.elapsed() >= time::Duration::from_millis(900));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn download_bandwidth_limit_is_persisted() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024));
+        let store = SqliteStore::in_memory().await?;
+        let manager = DownloadManager::with_persistence(store.clone()).await?;
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 1).await?;
+        manager
+            .set_download_bandwidth_limit(&ids[0], Some(1000))
+            .await?;
+        let (download, _) = store.load_downloads().await?.remove(0);
+        let download = HttpDownload::from_record(download.to_record().unwrap())?;
+        assert_eq!(download.config.bandwidth_limit, Some(1000));
+        assert_eq!(download.limiter.limit(), Some(1000));
+        assert!(manager
+            .set_download_bandwidth_limit(&Uuid::new_v4(), None)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn packages_are_controlled_as_one_unit() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let tmp_dir = tempfile::TempDir::new()?;
+        let db_path = tmp_dir.path().join("ludownloader.db");
+        let manager = DownloadManager::with_persistence(SqliteStore::open(&db_path).await?).await?;
+        let package = manager
+            .create_package("archive".to_string(), tmp_dir.path().join("archive"))
+            .await?;
+        let (ids, _downloads_dir) = add_local_downloads(&manager, &server, 3).await?;
+        manager.add_to_package(&package.id, &ids[0]).await?;
+        manager.add_to_package(&package.id, &ids[1]).await?;
+        assert_eq!(
+            manager.package_index().packages_of(&ids).await,
+            HashMap::from([(ids[0], package.id), (ids[1], package.id)])
+        );
+        manager.start_package(&package.id).await?;
+        wait_until_complete(&manager, &ids[..2]).await;
+        let progress = manager.package_progress(&package.id).await?;
+        assert_eq!(progress.downloads, 2);
+        assert_eq!(progress.complete, 2);
+        assert_eq!(progress.total_bytes, Some(2 * 1024 * 1024));
+        assert_eq!(progress.bytes_downloaded, 2 * 1024 * 1024);
+        assert!(matches!(
+            manager.observer.get_state(&ids[2]).await,
+            Some(download::State::Paused(0))
+        ));
+
+        manager.delete(&ids[1], false).await?;
+        let restored =
+            DownloadManager::with_persistence(SqliteStore::open(&db_path).await?).await?;
+        assert_eq!(
+            restored.get_package(&package.id).await?.download_ids,
+            vec![ids[0]]
+        );
+
+        manager.delete_package(&package.id, true).await?;
+        assert!(manager.get_package(&package.id).await.is_err());
+        assert!(manager.get_metadata(&ids[0]).await.is_err());
+        assert!(manager.get_metadata(&ids[2]).await.is_ok());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn downloads_run_through_the_client_pool() -> Test<()> {
+        // The test server answers the absolute-form requests a plain HTTP proxy receives
+        let proxy = TestServer::spawn(test_payload(256 * 1024));
+        let manager = DownloadManager::new().await;
+        manager.clients.configure(ClientPoolConfig {
+            proxies: vec![ProxyConfig {
+                name: "local".to_string(),
+                url: format!("http://{}", proxy.url.authority()),
+            }],
+            ..Default::default()
+        })?;
+        let url = reqwest::Url::parse("http://ludownloader.invalid/file.bin")?;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let download = HttpDownload::create(
+            url.clone(),
+            tmp_dir.path().to_owned(),
+            Some("file.bin".to_string()),
+            manager.clients.client_for(&url),
+            None,
+        )
+        .await?;
+        let id = manager.add(download).await?[0];
+        manager.start(&id).await?;
+        wait_until_complete(&manager, &[id]).await;
+        assert_eq!(manager.clients.proxy_of(&id), Some("local".to_string()));
+        assert_eq!(manager.clients.status()[0].downloads, vec![id]);
+        manager.delete(&id, true).await?;
+        assert_eq!(manager.clients.proxy_of(&id), None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn premium_accounts_are_charged_with_transferred_bytes() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024 * 1024));
+        let manager = DownloadManager::new().await;
+        let (ids, tmp_dir) = add_local_downloads(&manager, &server, 2).await?;
+        let credentials = CredentialStore::new(tmp_dir.path().join("credentials.enc"));
+        credentials.unlock("password").await?;
+        credentials
+            .add(
+                server.url.host_str().unwrap(),
+                Credential::Premium {
+                    username: "user".to_string(),
+                    password: "secret".to_string(),
+                    quota: None,
+                    used: 0,
+                },
+            )
+            .await?;
+        manager.set_credentials(credentials.clone()).await;
+        let used = || credentials.list().unwrap()[0].used;
+
+        // Deleted downloads aren't charged, even if they were running
+        manager.start(&ids[0]).await?;
+        manager.delete(&ids[0], true).await?;
+        manager.start(&ids[1]).await?;
+        wait_until_complete(&manager, &ids[1..]).await;
+        let payload_len = server.payload.len() as u64;
+        time::timeout(time::Duration::from_secs(5), async {
+            while used() != Some(payload_len) {
+                time::sleep(time::Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+        manager.delete(&ids[1], true).await?;
+        time::sleep(time::Duration::from_millis(200)).await;
+        assert_eq!(used(), Some(payload_len));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn hoster_pages_are_resolved_on_add() -> Test<()> {
+        let hoster = MockHoster::spawn();
+        let manager = DownloadManager::new().await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let credentials = CredentialStore::new(tmp_dir.path().join("credentials.enc"));
+        credentials.unlock("password").await?;
+        credentials
+            .add(
+                &hoster.host(),
+                Credential::Basic {
+                    username: "user".to_string(),
+                    password: "secret".to_string(),
+                },
+            )
+            .await?;
+        manager.hosters.configure(
+            &[TokenHosterConfig {
+                hosts: vec![hoster.host()],
+                api: hoster.api_url().to_string(),
+            }],
+            &credentials,
+        )?;
+        // The page itself requires a login, only the resolver gets to see it
+        let ids = manager
+            .add(NewDownload::Url {
+                url: hoster.page_url("abc"),
+                directory: tmp_dir.path().to_owned(),
+                filename: Some("abc".to_string()),
+                config: HttpDownloadConfig::default(),
+            })
+            .await?;
+        assert_eq!(ids.len(), 2);
+        for id in ids.iter() {
+            manager.start(id).await?;
+        }
+        wait_until_complete(&manager, &ids).await;
+        for (i, id) in ids.iter().enumerate() {
+            let metadata = manager.get_metadata(id).await?;
+            assert_eq!(
+                metadata.file_path,
+                tmp_dir.path().join(format!("abc.part{}.bin", i + 1))
+            );
+            assert_eq!(tokio::fs::read(metadata.file_path).await?, *hoster.payload);
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn torrents_run_in_the_manager() -> Test<()> {
+        let tracker = TestTracker::spawn();
+        let seed_dir = tempfile::TempDir::new()?;
+        let source = seed_dir.path().join("file.bin");
+        tokio::fs::write(&source, test_payload(100 * 1024)).await?;
+        let torrent =
+            Metainfo::create(&source, 16 * 1024, vec![tracker.announce_url()])?.to_bytes()?;
+        let seed_config = TorrentConfig {
+            seed_ratio: 100.0,
+            ..Default::default()
+        };
+        let seeder =
+            TorrentDownload::from_torrent(&torrent, seed_dir.path().to_owned(), seed_config)?;
+        let leech_dir = tempfile::TempDir::new()?;
+        let leecher = TorrentDownload::from_torrent(
+            &torrent,
+            leech_dir.path().to_owned(),
+            TorrentConfig::default(),
+        )?;
+
+        let manager = DownloadManager::new().await;
+        let seeder_id = manager.add(seeder).await?[0];
+        let leecher_id = manager.add(leecher).await?[0];
+        manager.start(&seeder_id).await?;
+        manager.start(&leecher_id).await?;
+        wait_until_complete(&manager, &[leecher_id]).await;
+        let metadata = manager.get_metadata(&leecher_id).await?;
+        assert_eq!(metadata.download_size, Some(100 * 1024));
+        assert_eq!(
+            tokio::fs::read(metadata.file_path).await?,
+            tokio::fs::read(source).await?
+        );
+        time::sleep(time::Duration::from_secs(1)).await;
+        assert!(matches!(
+            manager.observer.get_state(&seeder_id).await,
+            Some(download::State::Seeding { bytes_uploaded }) if bytes_uploaded >= 100 * 1024
+        ));
+        manager.stop(&seeder_id).await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn torrents_are_restored_from_store() -> Test<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let source = tmp_dir.path().join("file.bin");
+        tokio::fs::write(&source, test_payload(40_000)).await?;
+        let torrent = Metainfo::create(&source, 16 * 1024, Vec::new())?.to_bytes()?;
+        let torrent =
+            TorrentDownload::from_torrent(&torrent, tmp_dir.path().to_owned(), Default::default())?;
+        let store = SqliteStore::in_memory().await?;
+        store.register_kind(TorrentDownload::KIND, TorrentDownload::restore);
+        let first_manager = DownloadManager::with_persistence(store.clone()).await?;
+        let id = first_manager.add(torrent).await?[0];
+        store
+            .update_states(&[(id, download::State::Seeding { bytes_uploaded: 5 })])
+            .await?;
+
+        let manager = DownloadManager::with_persistence(store).await?;
+        let metadata = manager.get_metadata(&id).await?;
+        assert_eq!(metadata.file_path, source);
+        assert_eq!(metadata.download_size, Some(40_000));
+        // Seeding was interrupted, the torrent is verified again on its next run
+        assert!(matches!(
+            manager.observer.get_state(&id).await,
+            Some(download::State::Paused(_))
+        ));
+        assert!(manager
+            .set_download_bandwidth_limit(&id, Some(10))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    /// Records when its runs start and when a stopped run lets go of it, letting go takes a while
+    #[derive(Debug, Default)]
+    struct SlowStopDownload {
+        id: Uuid,
+        events: std::sync::Mutex<Vec<&'static str>>,
+    }
+
+    impl SlowStopDownload {
+        fn events(&self) -> Vec<&'static str> {
+            self.events.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Download for Arc<SlowStopDownload> {
+        fn id(&self) -> Uuid {
+            self.id
+        }
+
+        fn get_metadata(&self) -> DownloadMetadata {
+            DownloadMetadata {
+                id: self.id,
+                url: "slow://stop".to_string(),
+                file_path: PathBuf::new(),
+                download_size: None,
+                etag: None,
+                last_modified: None,
+            }
+        }
+
+        async fn get_bytes_on_disk(&self) -> u64 {
+            time::sleep(time::Duration::from_millis(300)).await;
+            self.events.lock().unwrap().push("stopped");
+            0
+        }
+
+        async fn start(&self, _update_ch: mpsc::Sender<DownloadUpdate>) -> anyhow::Result<u64> {
+            self.events.lock().unwrap().push("started");
+            std::future::pending().await
+        }
+
+        async fn resume(&self, update_ch: mpsc::Sender<DownloadUpdate>) -> anyhow::Result<u64> {
+            self.start(update_ch).await
+        }
+
+        fn kind(&self) -> &'static str {
+            "slow_stop"
+        }
+    }
+
+    async fn wait_for_events(download: &SlowStopDownload, events: &[&'static str]) {
+        time::timeout(time::Duration::from_secs(5), async {
+            while download.events().len() < events.len() {
+                time::sleep(time::Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("Download should see the events");
+        // Give a second task the chance to show up
+        time::sleep(time::Duration::from_millis(100)).await;
+        assert_eq!(download.events(), events);
+    }
+
+    #[test(tokio::test)]
+    async fn stopping_downloads_are_not_run_twice() -> Test<()> {
+        let manager = DownloadManager::new().await;
+        let first = Arc::new(SlowStopDownload {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        });
+        let second = Arc::new(SlowStopDownload {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        });
+        let first_id = manager.add(first.clone()).await?[0];
+        let second_id = manager.add(second.clone()).await?[0];
+
+        manager.start(&first_id).await?;
+        wait_for_events(&first, &["started"]).await;
+        manager.stop(&first_id).await?;
+        // The stopped task is still around, the new run waits for it
+        manager.start(&first_id).await?;
+        assert_eq!(manager.queue().await, vec![first_id]);
+        wait_for_events(&first, &["started", "stopped", "started"]).await;
+        assert!(manager.queue().await.is_empty());
+        assert!(manager.start(&first_id).await.is_err());
+
+        // Moving a download back to the queue and freeing its slot right away doesn't run it twice
+        manager.start(&second_id).await?;
+        wait_for_events(&second, &["started"]).await;
+        manager.set_max_concurrent_downloads(1).await;
+        manager.set_max_concurrent_downloads(0).await;
+        wait_for_events(&second, &["started", "stopped", "started"]).await;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn preempted_downloads_wait_for_their_task() -> Test<()> {
+        let manager = DownloadManager::new().await;
+        manager.set_max_concurrent_downloads(1).await;
+        manager.set_preemption(true).await;
+        let low = Arc::new(SlowStopDownload {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        });
+        let high = Arc::new(SlowStopDownload {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        });
+        let low_id = manager.add(low.clone()).await?[0];
+        let high_id = manager.add(high.clone()).await?[0];
+        manager.start(&low_id).await?;
+        wait_for_events(&low, &["started"]).await;
+        manager.start(&high_id).await?;
+
+        manager.set_priority(&high_id, 1).await?;
+        assert_eq!(manager.queue().await, vec![low_id]);
+        // The preempted download outranks the running one again, but its task is still stopping
+        manager.set_priority(&high_id, -1).await?;
+        assert_eq!(manager.queue().await, vec![low_id]);
+        wait_for_events(&low, &["started", "stopped", "started"]).await;
+        assert_eq!(manager.queue().await, vec![high_id]);
+        Ok(())
+    }
+
+    struct CountingSubscriber(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl DownloadUpdateSubscriber for CountingSubscriber {
+        async fn update(&self, updates: &[(Uuid, download::State)]) {
+            self.0.fetch_add(updates.len(), Ordering::SeqCst);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn dropped_subscriptions_receive_no_updates() -> Test<()> {
+        let server = TestServer::spawn(test_payload(1024));
+        let manager = DownloadManager::new().await;
+        let kept = Arc::new(AtomicUsize::new(0));
+        let removed = Arc::new(AtomicUsize::new(0));
+        let _kept = manager.add_subscriber(CountingSubscriber(kept.clone()));
+        drop(manager.add_subscriber(CountingSubscriber(removed.clone())));
+
+        let (ids, _tmp_dir) = add_local_downloads(&manager, &server, 1).await?;
+        manager.start(&ids[0]).await?;
+        wait_until_complete(&manager, &ids).await;
+        time::sleep(time::Duration::from_millis(100)).await;
+        assert!(kept.load(Ordering::SeqCst) > 0);
+        assert_eq!(removed.load(Ordering::SeqCst), 0);
+        Ok(())
+    }
 }
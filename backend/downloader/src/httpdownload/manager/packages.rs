@@ -0,0 +1,201 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::{DownloadManager, Result};
+use crate::httpdownload::package::{Package, PackageProgress};
+
+/// Read access to the packages of a DownloadManager for subscribers of its updates, which can't
+/// hold the manager itself. Clones share the packages of the manager.
+#[derive(Debug, Clone)]
+pub struct PackageIndex {
+    packages: Arc<RwLock<HashMap<Uuid, Package>>>,
+}
+
+impl PackageIndex {
+    /// Package of every download in `ids` that belongs to one
+    pub async fn packages_of<'a>(
+        &self,
+        ids: impl IntoIterator<Item = &'a Uuid>,
+    ) -> HashMap<Uuid, Uuid> {
+        let ids: HashSet<&Uuid> = ids.into_iter().collect();
+        let mut packages = HashMap::new();
+        for package in self.packages.read().await.values() {
+            for id in package.download_ids.iter().filter(|id| ids.contains(id)) {
+                packages.insert(*id, package.id);
+            }
+        }
+        packages
+    }
+}
+
+impl DownloadManager {
+    pub fn package_index(&self) -> PackageIndex {
+        PackageIndex {
+            packages: self.packages.clone(),
+        }
+    }
+
+    /// Creates an empty package, its directory is created if it doesn't exist yet
+    pub async fn create_package(&self, name: String, directory: PathBuf) -> Result<Package> {
+        tokio::fs::create_dir_all(&directory).await?;
+        let package = Package::new(name, directory);
+        log::info!("Creating package {}: {:?}", package.id, package.name);
+        self.save_package(&package).await;
+        self.packages
+            .write()
+            .await
+            .insert(package.id, package.clone());
+        Ok(package)
+    }
+
+    pub async fn get_package(&self, id: &Uuid) -> Result<Package> {
+        self.packages
+            .read()
+            .await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Package with id {} does not exist", id))
+    }
+
+    pub async fn get_packages(&self) -> Vec<Package> {
+        self.packages.read().await.values().cloned().collect()
+    }
+
+    /// Moves a download into the package, a download belongs to at most one package.
+    /// The file of the download stays where it is.
+    pub async fn add_to_package(&self, package_id: &Uuid, download_id: &Uuid) -> Result<()> {
+        self.get_metadata(download_id).await?;
+        let mut packages = self.packages.write().await;
+        if !packages.contains_key(package_id) {
+            return Err(anyhow!("Package with id {} does not exist", package_id));
+        }
+        let mut changed = Vec::new();
+        for package in packages.values_mut() {
+            if package.id == *package_id {
+                if !package.download_ids.contains(download_id) {
+                    package.download_ids.push(*download_id);
+                    changed.push(package.clone());
+                }
+            } else if package.download_ids.contains(download_id) {
+                package.download_ids.retain(|id| id != download_id);
+                changed.push(package.clone());
+            }
+        }
+        drop(packages);
+        for package in changed.iter() {
+            self.save_package(package).await;
+        }
+        Ok(())
+    }
+
+    /// Starts every download of the package that isn't running yet, downloads with bytes on
+    /// disk are resumed
+    pub async fn start_package(&self, id: &Uuid) -> Result<()> {
+        let package = self.get_package(id).await?;
+        log::info!("Starting package {}", id);
+        let mut inner = self.inner.write().await;
+        for download_id in package.download_ids.iter() {
+            if let Err(e) = inner.run(download_id, true) {
+                log::info!("Download {} of package {} skipped: {}", download_id, id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Schedules every download of the package that isn't running, see `schedule_download`
+    pub async fn schedule_package(&self, id: &Uuid, at: u64) -> Result<()> {
+        let package = self.get_package(id).await?;
+        log::info!("Scheduling package {} at {}", id, at);
+        let mut inner = self.inner.write().await;
+        for download_id in package.download_ids.iter() {
+            if let Err(e) = inner.schedule(download_id, at, true) {
+                log::info!("Download {} of package {} skipped: {}", download_id, id, e);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn stop_package(&self, id: &Uuid) -> Result<()> {
+        let package = self.get_package(id).await?;
+        log::info!("Stopping package {}", id);
+        let mut inner = self.inner.write().await;
+        for download_id in package.download_ids.iter() {
+            let _ = inner.stop(download_id).await;
+        }
+        Ok(())
+    }
+
+    /// Deletes the package together with all of its downloads. A download that fails to delete
+    /// doesn't keep the others or the package around, the first error is returned at the end
+    pub async fn delete_package(&self, id: &Uuid, delete_files: bool) -> Result<()> {
+        let package = self.get_package(id).await?;
+        log::info!("Deleting package {}", id);
+        let mut errors = Vec::new();
+        for download_id in package.download_ids.iter() {
+            if let Err(e) = self.delete(download_id, delete_files).await {
+                log::error!(
+                    "Couldn't delete download {} of package {}: {}",
+                    download_id,
+                    id,
+                    e
+                );
+                errors.push(e);
+            }
+        }
+        self.packages.write().await.remove(id);
+        if let Some(store) = self.store.as_ref() {
+            if let Err(e) = store.delete_package(id).await {
+                errors.push(e.into());
+            }
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Progress of all downloads of the package combined, based on the states of the observer
+    pub async fn package_progress(&self, id: &Uuid) -> Result<PackageProgress> {
+        let package = self.get_package(id).await?;
+        let mut downloads = Vec::new();
+        for download_id in package.download_ids.iter() {
+            let (Ok(metadata), Some(state)) = (
+                self.get_metadata(download_id).await,
+                self.observer.get_state(download_id).await,
+            ) else {
+                continue;
+            };
+            downloads.push((metadata.download_size, state));
+        }
+        Ok(PackageProgress::aggregate(
+            downloads.iter().map(|(size, state)| (*size, state)),
+        ))
+    }
+
+    /// Drops a deleted download from the package it belonged to
+    pub(super) async fn remove_from_packages(&self, download_id: &Uuid) {
+        let mut changed = Vec::new();
+        for package in self.packages.write().await.values_mut() {
+            if package.download_ids.contains(download_id) {
+                package.download_ids.retain(|id| id != download_id);
+                changed.push(package.clone());
+            }
+        }
+        for package in changed.iter() {
+            self.save_package(package).await;
+        }
+    }
+
+    async fn save_package(&self, package: &Package) {
+        if let Some(store) = self.store.as_ref() {
+            if let Err(e) = store.save_package(package).await {
+                log::error!("Couldn't persist package {}: {}", package.id, e);
+            }
+        }
+    }
+}
@@ -1,20 +1,85 @@
-use std::{path::PathBuf, sync::Arc};
+use std::path::PathBuf;
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
 
+use crate::bandwidth::RateLimiter;
+use crate::credentials::CredentialStore;
+use crate::proxy::ClientPool;
+
+use self::download::DownloadUpdate;
+
 pub mod download;
 pub mod manager;
 pub mod observer;
+pub mod package;
+pub mod subscribers;
+
+pub use self::subscribers::{LagPolicy, Subscribers, Subscription};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DownloadMetadata {
     pub id: Uuid,
     pub url: String,
     pub file_path: PathBuf,
-    pub download_size: u64,
+    /// None if the server didn't report the size of the file
+    pub download_size: Option<u64>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Common interface of the downloads run by the DownloadManager, e.g. HttpDownload and
+/// TorrentDownload
+#[async_trait]
+pub trait Download: std::fmt::Debug + Send + Sync {
+    fn id(&self) -> Uuid;
+
+    fn get_metadata(&self) -> DownloadMetadata;
+
+    async fn get_bytes_on_disk(&self) -> u64;
+
+    /// Downloads from scratch, returns the downloaded bytes once complete
+    async fn start(&self, update_ch: Sender<DownloadUpdate>) -> anyhow::Result<u64>;
+
+    /// Continues from the data on disk
+    async fn resume(&self, update_ch: Sender<DownloadUpdate>) -> anyhow::Result<u64>;
+
+    /// Name of the kind of download, the persistence layer restores downloads by their kind
+    fn kind(&self) -> &'static str;
+
+    /// Everything needed to restore the download, None if it can't be persisted
+    fn to_record(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Called when the download is added to a manager, downloads supporting bandwidth limits
+    /// share the global limiter of the manager
+    fn set_global_limiter(&mut self, _limiter: RateLimiter) {}
+
+    /// Called by the manager before every run. Downloads fetching from a URL take their client
+    /// from the pool and put matching credentials into their requests, the id of the applied
+    /// credential is returned so the run can be charged to it.
+    fn prepare_run(
+        &mut self,
+        _clients: &ClientPool,
+        _credentials: Option<&CredentialStore>,
+    ) -> Option<Uuid> {
+        None
+    }
+
+    /// Limits the download in bytes per second on top of the global limit, applies to a
+    /// running download right away. Returns false if the download doesn't support limits.
+    fn set_bandwidth_limit(&self, _bytes_per_second: Option<u64>) -> bool {
+        false
+    }
+
+    /// Bytes received by all runs, the manager charges the difference of a run to its
+    /// credential
+    fn bytes_received(&self) -> u64 {
+        0
+    }
 }
 
 /// This trait is used to subscribe to state updates of downloads
@@ -23,11 +88,6 @@ pub trait DownloadUpdateSubscriber {
     async fn update(&self, updates: &[(Uuid, download::State)]);
 }
 
-// Fuck this type, later on just remove the wrapping Arc<Mutex> and instead create a simple channel
-// over which new subscribers are sent, whenever the publisher is ready to publish a new batch he
-// first checks the channel for new subscribers which will be added to the internal vector.
-pub type Subscribers = Arc<Mutex<Vec<Arc<dyn DownloadUpdateSubscriber + Send + Sync>>>>;
-
 #[cfg(test)]
 mod test {
     use crate::{
@@ -45,7 +105,7 @@ mod test {
     async fn test_download_with_observability() -> TestResult<()> {
         let manager = DownloadManager::new().await;
         let (download, _tmp_dir) = setup_test_download(TEST_DOWNLOAD_URL).await?;
-        let id = manager.add(download).await;
+        let id = manager.add(download).await?[0];
 
         manager.start(&id).await?;
         manager.stop(&id).await?;
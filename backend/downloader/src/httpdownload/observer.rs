@@ -2,7 +2,7 @@ use std::{collections::HashMap, sync::Arc};
 
 use async_trait::async_trait;
 use tokio::{
-    sync::{Mutex, RwLock, RwLockReadGuard},
+    sync::{RwLock, RwLockReadGuard},
     time::Instant,
 };
 use uuid::Uuid;
@@ -12,7 +12,7 @@ use crate::util::HALF_SECOND;
 use super::{
     download::{self, DownloadUpdate, State},
     manager::UpdateConsumer,
-    DownloadUpdateSubscriber, Subscribers,
+    DownloadUpdateSubscriber, LagPolicy, Subscribers, Subscription,
 };
 
 /// This struct is responsible for keeping the state of all running downloads
@@ -24,13 +24,19 @@ pub struct DownloadObserver {
     pub state: Arc<RwLock<HashMap<Uuid, download::State>>>,
 }
 
+impl Default for DownloadObserver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DownloadObserver {
     pub fn new() -> Self {
         Self {
             state: Arc::new(RwLock::new(HashMap::new())),
         }
     }
-    pub async fn read_state(&self) -> RwLockReadGuard<HashMap<Uuid, download::State>> {
+    pub async fn read_state(&self) -> RwLockReadGuard<'_, HashMap<Uuid, download::State>> {
         self.state.read().await
     }
 
@@ -88,54 +94,44 @@ pub struct DownloadUpdateBuffer {
     cache: HashMap<Uuid, State>,
 }
 
+impl Default for DownloadUpdateBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DownloadUpdateBuffer {
     pub fn new() -> Self {
         Self {
-            subscribers: Arc::new(Mutex::new(Vec::new())),
+            subscribers: Subscribers::new(),
             cache: HashMap::new(),
             last_flush: Instant::now(),
         }
     }
-    pub async fn add_subscriber(
+
+    pub fn add_subscriber(
         &self,
         subscriber: impl DownloadUpdateSubscriber + Send + 'static + Sync,
-    ) {
-        let mut guard = self.subscribers.lock().await;
-        guard.push(Arc::new(subscriber));
+        policy: LagPolicy,
+    ) -> Subscription {
+        self.subscribers.add(subscriber, policy)
     }
 }
 
 impl UpdateConsumer for DownloadUpdateBuffer {
     fn consume(&mut self, update: DownloadUpdate) {
         let flush = self.last_flush.elapsed() > HALF_SECOND
-            && !matches!(update.state, State::Running { .. });
+            || !matches!(update.state, State::Running { .. });
         let state = update.state;
         self.cache.insert(update.id, state);
         // If more than HALF_SECOND has elapsed or the download triggered an event
-        // The cached state is flushed to all subscribers, this operation shouldn't block the
-        // thread that called consume for too long (just the time to create an update array, wrap
-        // it in Arc and spawn the tokio task).
+        // The cached state is flushed to all subscribers, publishing never blocks the thread
+        // that called consume, every subscriber consumes the batch in its own task.
         if flush {
-            let updates: Arc<[(Uuid, download::State)]> =
-                self.cache.drain().map(|(id, state)| (id, state)).collect();
-            let subscribers = self.subscribers.clone();
-            tokio::task::spawn(async move {
-                log::info!(
-                    "Flushing updates from SendingUpdateConsumer to subscribers! Acquiring Lock..."
-                );
-                let guard = subscribers.lock().await;
-                log::info!("Lock on subscribers acquired! Spawning update sender threads...");
-                guard.iter().for_each(|subscriber| {
-                    let subscriber = subscriber.clone();
-                    tokio::spawn({
-                        let updates = updates.clone();
-                        async move {
-                            log::info!("Sending updates to subscriber!");
-                            subscriber.update(&updates).await;
-                        }
-                    });
-                });
-            });
+            self.last_flush = Instant::now();
+            let updates: Arc<[(Uuid, download::State)]> = self.cache.drain().collect();
+            log::debug!("Flushing {} updates to subscribers", updates.len());
+            self.subscribers.publish(updates);
         }
     }
 }
@@ -0,0 +1,135 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::download::State;
+
+/// Group of downloads that is controlled as one unit, e.g. the parts of a multi-part archive.
+/// Downloads created for a package are placed in its directory.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Package {
+    pub id: Uuid,
+    pub name: String,
+    pub directory: PathBuf,
+    pub download_ids: Vec<Uuid>,
+}
+
+impl Package {
+    pub fn new(name: String, directory: PathBuf) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            name,
+            directory,
+            download_ids: Vec::new(),
+        }
+    }
+}
+
+/// Progress of all downloads of a package combined
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PackageProgress {
+    /// None if the size of at least one download is unknown
+    pub total_bytes: Option<u64>,
+    pub bytes_downloaded: u64,
+    pub bytes_per_second: u64,
+    pub downloads: usize,
+    pub complete: usize,
+    pub running: usize,
+    pub failed: usize,
+    /// Estimated time until all downloads are complete at the combined speed of the running ones
+    pub eta_seconds: Option<u64>,
+}
+
+impl PackageProgress {
+    /// Aggregates the size and the current state of every download of a package
+    pub fn aggregate<'a>(downloads: impl IntoIterator<Item = (Option<u64>, &'a State)>) -> Self {
+        let mut progress = PackageProgress {
+            total_bytes: Some(0),
+            ..Default::default()
+        };
+        for (download_size, state) in downloads {
+            progress.downloads += 1;
+            progress.total_bytes = progress
+                .total_bytes
+                .zip(download_size)
+                .map(|(total, size)| total + size);
+            match state {
+                State::Complete | State::Seeding { .. } => {
+                    progress.complete += 1;
+                    progress.bytes_downloaded += download_size.unwrap_or_default();
+                }
+                // Checksums are verified once the whole file is on disk
+                State::Verifying => progress.bytes_downloaded += download_size.unwrap_or_default(),
+                State::Paused(bytes_downloaded) => progress.bytes_downloaded += bytes_downloaded,
+                State::Running {
+                    bytes_downloaded,
+                    bytes_per_second,
+                    ..
+                } => {
+                    progress.running += 1;
+                    progress.bytes_downloaded += bytes_downloaded;
+                    progress.bytes_per_second += bytes_per_second;
+                }
+                State::Error(_) => progress.failed += 1,
+                State::Queued | State::Scheduled { .. } | State::Retrying { .. } => {}
+            }
+        }
+        progress.eta_seconds = progress
+            .total_bytes
+            .filter(|_| progress.bytes_per_second > 0)
+            .map(|total| {
+                total
+                    .saturating_sub(progress.bytes_downloaded)
+                    .div_ceil(progress.bytes_per_second)
+            });
+        progress
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn progress_is_aggregated() {
+        let states = [
+            (Some(100), State::Complete),
+            (
+                Some(200),
+                State::Running {
+                    bytes_downloaded: 50,
+                    bytes_per_second: 10,
+                    average_bytes_per_second: 8,
+                    percentage: Some(25.0),
+                    eta_seconds: Some(15),
+                },
+            ),
+            (Some(300), State::Paused(20)),
+            (Some(400), State::Error("connection reset".to_string())),
+            (Some(500), State::Verifying),
+        ];
+        let progress =
+            PackageProgress::aggregate(states.iter().map(|(size, state)| (*size, state)));
+        assert_eq!(
+            progress,
+            PackageProgress {
+                total_bytes: Some(1500),
+                bytes_downloaded: 670,
+                bytes_per_second: 10,
+                downloads: 5,
+                complete: 1,
+                running: 1,
+                failed: 1,
+                eta_seconds: Some(83),
+            }
+        );
+        let unknown_size = [(None, State::Paused(10)), (Some(10), State::Complete)];
+        let progress =
+            PackageProgress::aggregate(unknown_size.iter().map(|(size, state)| (*size, state)));
+        assert_eq!(progress.total_bytes, None);
+        assert_eq!(progress.bytes_downloaded, 20);
+        assert_eq!(progress.eta_seconds, None);
+    }
+}
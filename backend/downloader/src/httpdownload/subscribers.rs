@@ -0,0 +1,250 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use super::download::State;
+use super::DownloadUpdateSubscriber;
+
+pub type UpdateBatch = Arc<[(Uuid, State)]>;
+
+/// Batches a subscriber with the default LagPolicy can fall behind
+pub const DEFAULT_LAG_CAPACITY: usize = 64;
+
+/// What happens to a subscriber that doesn't keep up with the flushed batches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Batches are queued without limit, for subscribers that must see every update
+    Unbounded,
+    /// Up to the given number of batches are queued, newer batches are dropped while it's full
+    DropNewest(usize),
+    /// The subscriber is removed once the given number of batches are queued
+    Disconnect(usize),
+}
+
+impl Default for LagPolicy {
+    fn default() -> Self {
+        LagPolicy::DropNewest(DEFAULT_LAG_CAPACITY)
+    }
+}
+
+struct Entry {
+    id: u64,
+    policy: LagPolicy,
+    sender: mpsc::UnboundedSender<UpdateBatch>,
+    /// Batches sent to the subscriber that it hasn't consumed yet
+    pending: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    entries: Vec<Entry>,
+}
+
+/// Subscribers of the DownloadUpdateBuffer. Every subscriber is fed by its own task, so it sees
+/// the batches in the order they were flushed and a slow subscriber only delays itself.
+/// Cheap to clone, clones share the registered subscribers.
+#[derive(Clone, Default)]
+pub struct Subscribers {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Subscribers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the subscriber until the returned Subscription is dropped
+    pub fn add(
+        &self,
+        subscriber: impl DownloadUpdateSubscriber + Send + Sync + 'static,
+        policy: LagPolicy,
+    ) -> Subscription {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<UpdateBatch>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let consumed = pending.clone();
+        tokio::spawn(async move {
+            while let Some(batch) = receiver.recv().await {
+                subscriber.update(&batch).await;
+                consumed.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        let mut registry = lock(&self.registry);
+        registry.next_id += 1;
+        let id = registry.next_id;
+        registry.entries.push(Entry {
+            id,
+            policy,
+            sender,
+            pending,
+        });
+        Subscription {
+            id,
+            registry: Arc::downgrade(&self.registry),
+            detached: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        lock(&self.registry).entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hands the batch to every subscriber according to its LagPolicy, never blocks
+    pub fn publish(&self, batch: UpdateBatch) {
+        lock(&self.registry).entries.retain(|entry| {
+            let pending = entry.pending.load(Ordering::SeqCst);
+            match entry.policy {
+                LagPolicy::DropNewest(capacity) if pending >= capacity => {
+                    log::warn!(
+                        "Subscriber {} is {} batches behind, dropping a batch",
+                        entry.id,
+                        pending
+                    );
+                    return true;
+                }
+                LagPolicy::Disconnect(capacity) if pending >= capacity => {
+                    log::warn!(
+                        "Subscriber {} is {} batches behind, unsubscribing it",
+                        entry.id,
+                        pending
+                    );
+                    return false;
+                }
+                _ => {}
+            }
+            entry.pending.fetch_add(1, Ordering::SeqCst);
+            // Only fails if the task of the subscriber is gone, e.g. because it panicked
+            entry.sender.send(batch.clone()).is_ok()
+        });
+    }
+}
+
+fn lock(registry: &Mutex<Registry>) -> MutexGuard<'_, Registry> {
+    registry
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Keeps a subscriber registered, dropping it unsubscribes
+#[must_use = "dropping the Subscription unsubscribes right away"]
+pub struct Subscription {
+    id: u64,
+    registry: Weak<Mutex<Registry>>,
+    detached: bool,
+}
+
+impl Subscription {
+    /// Keeps the subscriber registered for as long as the Subscribers live
+    pub fn detach(mut self) {
+        self.detached = true;
+    }
+
+    /// False once the subscriber was removed for lagging behind
+    pub fn is_active(&self) -> bool {
+        self.registry
+            .upgrade()
+            .is_some_and(|registry| lock(&registry).entries.iter().any(|e| e.id == self.id))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if self.detached {
+            return;
+        }
+        if let Some(registry) = self.registry.upgrade() {
+            lock(&registry).entries.retain(|entry| entry.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_trait::async_trait;
+    use std::time::Duration;
+    use test_log::test;
+
+    /// Records the batch sizes it receives, taking `delay` for every batch
+    struct Recorder {
+        received: Arc<Mutex<Vec<usize>>>,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl DownloadUpdateSubscriber for Recorder {
+        async fn update(&self, updates: &[(Uuid, State)]) {
+            tokio::time::sleep(self.delay).await;
+            self.received.lock().unwrap().push(updates.len());
+        }
+    }
+
+    fn recorder(delay: Duration) -> (Recorder, Arc<Mutex<Vec<usize>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Recorder {
+            received: received.clone(),
+            delay,
+        };
+        (recorder, received)
+    }
+
+    fn batch(len: usize) -> UpdateBatch {
+        (0..len)
+            .map(|_| (Uuid::new_v4(), State::Complete))
+            .collect()
+    }
+
+    #[test(tokio::test)]
+    async fn batches_arrive_in_order_until_unsubscribed() {
+        let subscribers = Subscribers::new();
+        let (subscriber, received) = recorder(Duration::ZERO);
+        let subscription = subscribers.add(subscriber, LagPolicy::Unbounded);
+        for len in 1..=3 {
+            subscribers.publish(batch(len));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*received.lock().unwrap(), vec![1, 2, 3]);
+
+        drop(subscription);
+        assert!(subscribers.is_empty());
+        subscribers.publish(batch(4));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[test(tokio::test)]
+    async fn detached_subscriptions_stay_registered() {
+        let subscribers = Subscribers::new();
+        let (subscriber, _) = recorder(Duration::ZERO);
+        subscribers.add(subscriber, LagPolicy::default()).detach();
+        assert_eq!(subscribers.len(), 1);
+    }
+
+    #[test(tokio::test)]
+    async fn slow_subscribers_follow_their_lag_policy() {
+        let subscribers = Subscribers::new();
+        let delay = Duration::from_millis(100);
+        let (dropping, dropping_received) = recorder(delay);
+        let (disconnecting, disconnecting_received) = recorder(delay);
+        let (unbounded, unbounded_received) = recorder(delay);
+        let _dropping = subscribers.add(dropping, LagPolicy::DropNewest(2));
+        let disconnecting = subscribers.add(disconnecting, LagPolicy::Disconnect(2));
+        let _unbounded = subscribers.add(unbounded, LagPolicy::Unbounded);
+        for len in 1..=5 {
+            subscribers.publish(batch(len));
+        }
+        assert!(!disconnecting.is_active());
+        assert_eq!(subscribers.len(), 2);
+
+        tokio::time::sleep(delay * 7).await;
+        assert_eq!(*dropping_received.lock().unwrap(), vec![1, 2]);
+        assert_eq!(*disconnecting_received.lock().unwrap(), vec![1, 2]);
+        assert_eq!(*unbounded_received.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+    }
+}
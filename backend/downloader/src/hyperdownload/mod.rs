@@ -0,0 +1,526 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::future::try_join_all;
+use futures_util::StreamExt;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+
+use crate::httpdownload::download::disk::{self, Preallocation};
+use crate::httpdownload::download::progress::ProgressTracker;
+use crate::httpdownload::download::{DownloadUpdate, Error, HttpDownload, Result};
+use crate::util::{file_size, mb, HALF_SECOND};
+
+/// Segments are never split below this size, small files are downloaded over fewer connections
+pub const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+const SEGMENTS_EXTENSION: &str = "ludl.segments";
+
+/// Byte range `[start, end)` of the target file that is downloaded over its own connection.
+/// `downloaded` counts the bytes of the range that are already written to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub start: u64,
+    pub end: u64,
+    pub downloaded: u64,
+}
+
+impl Segment {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Offset in the file at which the next byte of this segment is written
+    pub fn position(&self) -> u64 {
+        self.start + self.downloaded
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.downloaded >= self.len()
+    }
+}
+
+/// Splits the byte range `[start, end)` into at most `count` contiguous segments, none of them
+/// (but the last) smaller than MIN_SEGMENT_SIZE.
+pub fn split(start: u64, end: u64, count: usize) -> Vec<Segment> {
+    let len = end.saturating_sub(start);
+    if len == 0 {
+        return Vec::new();
+    }
+    let max_count = len.div_ceil(MIN_SEGMENT_SIZE);
+    let count = (count.max(1) as u64).min(max_count);
+    let segment_len = len / count;
+    (0..count)
+        .map(|i| {
+            let segment_start = start + i * segment_len;
+            let segment_end = if i == count - 1 {
+                end
+            } else {
+                segment_start + segment_len
+            };
+            Segment {
+                start: segment_start,
+                end: segment_end,
+                downloaded: 0,
+            }
+        })
+        .collect()
+}
+
+/// Sidecar file next to the target file that records the progress of every segment
+pub fn segments_path(file_path: &Path) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(SEGMENTS_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Reads the segment progress of a download, None if the download isn't (or no longer) segmented
+pub async fn load_segments(file_path: &Path) -> Option<Vec<Segment>> {
+    let bytes = tokio::fs::read(segments_path(file_path)).await.ok()?;
+    match serde_json::from_slice(&bytes) {
+        Ok(segments) => Some(segments),
+        Err(e) => {
+            log::warn!(
+                "Ignoring corrupted segments file for {:?}: {}",
+                file_path,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Bytes written to disk by a segmented download, None if there is no segment progress on disk
+pub async fn bytes_downloaded(file_path: &Path) -> Option<u64> {
+    let segments = load_segments(file_path).await?;
+    Some(segments.iter().map(|s| s.downloaded.min(s.len())).sum())
+}
+
+/// Writes the segment progress to a temporary file first so a crash never leaves a half written
+/// segments file behind.
+async fn save_segments(file_path: &Path, segments: &[Segment]) -> Result<()> {
+    let path = segments_path(file_path);
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let bytes = serde_json::to_vec(segments).expect("Segments are always serializable");
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+/// Flushes the part file to disk before recording the segment progress, otherwise a crash could
+/// leave a segments file behind that counts bytes the part file never got.
+/// `segments` has to be taken before, bytes written in the meantime are simply downloaded again.
+async fn checkpoint(part_file: &File, file_path: &Path, segments: &[Segment]) -> Result<()> {
+    part_file.sync_data().await?;
+    save_segments(file_path, segments).await
+}
+
+/// Segments can only be computed for downloads of known size
+fn required_content_length(download: &HttpDownload) -> Result<u64> {
+    download
+        .content_length
+        .ok_or_else(|| Error::MissingContentLength(download.url.clone()))
+}
+
+/// Starts a segmented download from scratch, the part file is allocated upfront so every
+/// segment can write at its own offset.
+pub async fn start(download: &HttpDownload, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+    let part_path = download.part_path();
+    log::info!(
+        "Starting new segmented download for url {}, creating file at {:?}",
+        download.url,
+        part_path
+    );
+    let content_length = required_content_length(download)?;
+    let file = File::create(&part_path).await?;
+    preallocate(&file, content_length, download.config.preallocation).await?;
+    let segments = split(0, content_length, download.config.segments);
+    run(download, segments, update_ch).await
+}
+
+/// Resumes every unfinished segment from its own position.
+/// Without a segments file the bytes on disk are treated as a contiguous prefix that becomes a
+/// completed segment, this way a download that was started over a single connection can continue
+/// in segments. Part files allocated by `start` have the full length from the beginning, without
+/// their segments file nothing on disk can be trusted and the download restarts.
+pub async fn resume(download: &HttpDownload, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+    let content_length = required_content_length(download)?;
+    let part_path = download.part_path();
+    let bytes_on_disk = file_size(&part_path).await;
+    let segments = match load_segments(&download.file_path()).await {
+        Some(segments) => segments,
+        None if download.config.preallocation != Preallocation::Disabled
+            || bytes_on_disk >= content_length =>
+        {
+            log::warn!(
+                "No segment progress for the allocated part file of {}, restarting",
+                download.url
+            );
+            return start(download, update_ch).await;
+        }
+        None => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&part_path)
+                .await?;
+            preallocate(&file, content_length, download.config.preallocation).await?;
+            let prefix = Segment {
+                start: 0,
+                end: bytes_on_disk,
+                downloaded: bytes_on_disk,
+            };
+            std::iter::once(prefix)
+                .filter(|prefix| !prefix.is_empty())
+                .chain(split(
+                    bytes_on_disk,
+                    content_length,
+                    download.config.segments,
+                ))
+                .collect()
+        }
+    };
+    log::info!(
+        "Resuming segmented download for url {}, {} of {} segments left",
+        download.url,
+        segments.iter().filter(|s| !s.is_complete()).count(),
+        segments.len()
+    );
+    run(download, segments, update_ch).await
+}
+
+/// Segments write at their own offsets, so the file has at least to be sparse
+async fn preallocate(file: &File, len: u64, mode: Preallocation) -> Result<()> {
+    let mode = match mode {
+        Preallocation::Disabled => Preallocation::Sparse,
+        mode => mode,
+    };
+    disk::preallocate(file, len, mode).await
+}
+
+fn snapshot(segments: &[Segment], progress: &[AtomicU64]) -> Vec<Segment> {
+    segments
+        .iter()
+        .zip(progress.iter())
+        .map(|(segment, downloaded)| Segment {
+            downloaded: downloaded.load(Ordering::Relaxed),
+            ..*segment
+        })
+        .collect()
+}
+
+async fn run(
+    download: &HttpDownload,
+    segments: Vec<Segment>,
+    update_ch: Sender<DownloadUpdate>,
+) -> Result<u64> {
+    let file_path = download.file_path();
+    let part_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(download.part_path())
+        .await?;
+    checkpoint(&part_file, &file_path, &segments).await?;
+    let progress: Vec<AtomicU64> = segments
+        .iter()
+        .map(|s| AtomicU64::new(s.downloaded))
+        .collect();
+    let tasks = try_join_all(
+        segments
+            .iter()
+            .zip(progress.iter())
+            .filter(|(segment, _)| !segment.is_complete())
+            .map(|(segment, downloaded)| download_segment(download, *segment, downloaded)),
+    );
+    tokio::pin!(tasks);
+
+    let total = |progress: &[AtomicU64]| -> u64 {
+        progress.iter().map(|p| p.load(Ordering::Relaxed)).sum()
+    };
+    let mut tracker = ProgressTracker::new(download.content_length, total(&progress));
+    let mut interval = tokio::time::interval(HALF_SECOND);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            result = &mut tasks => {
+                if let Err(e) = result {
+                    // Keep what was downloaded so far, resume picks up from here
+                    checkpoint(&part_file, &file_path, &snapshot(&segments, &progress)).await?;
+                    return Err(e);
+                }
+                break;
+            }
+            _ = interval.tick() => {
+                checkpoint(&part_file, &file_path, &snapshot(&segments, &progress)).await?;
+                let _ = update_ch.try_send(DownloadUpdate {
+                    id: download.id,
+                    state: tracker.update(total(&progress)),
+                });
+            }
+        }
+    }
+    let downloaded_bytes = total(&progress);
+    tokio::fs::remove_file(segments_path(&file_path)).await?;
+    log::info!(
+        "Segmented download completed successfully: {}, {}MB over {} segments",
+        download.url,
+        mb(downloaded_bytes),
+        segments.len()
+    );
+    Ok(downloaded_bytes)
+}
+
+/// Downloads the remainder of a single segment and writes it at the segment's offset in the
+/// part file.
+/// `downloaded` is kept up to date with the bytes of the segment written so far.
+async fn download_segment(
+    download: &HttpDownload,
+    segment: Segment,
+    downloaded: &AtomicU64,
+) -> Result<()> {
+    let mut position = segment.position();
+    let resp = download
+        .range_request(format!("bytes={}-{}", position, segment.end - 1))
+        .send()
+        .await?;
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        download.ensure_unchanged(resp.headers())?;
+        return Err(Error::RangeNotSupported(resp.status()));
+    }
+    // Writing another range at the offset of the segment would corrupt the file
+    download.ensure_range(resp.headers(), position, Some(segment.end - 1))?;
+    // The part file is missing if it was deleted after the segments file was written
+    let mut file_handler = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(download.part_path())
+        .await?;
+    file_handler.seek(SeekFrom::Start(position)).await?;
+    let mut stream = resp.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        // Never write past the end of the segment, even if the server sends more than requested
+        let remaining = (segment.end - position) as usize;
+        let chunk = &chunk[..chunk.len().min(remaining)];
+        file_handler.write_all(chunk).await?;
+        // Only count bytes the OS has, a checkpoint can't flush writes still queued by tokio
+        file_handler.flush().await?;
+        download
+            .received
+            .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        download.throttle(chunk.len() as u64).await;
+        position += chunk.len() as u64;
+        downloaded.store(position - segment.start, Ordering::Relaxed);
+        if position >= segment.end {
+            break;
+        }
+    }
+    file_handler.flush().await?;
+    if position < segment.end {
+        log::error!(
+            "Segment stream ended before completion, segment: {:?}, position: {}",
+            segment,
+            position
+        );
+        return Err(Error::StreamEndedBeforeCompletion(position - segment.start));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::httpdownload::download::config::HttpDownloadConfig;
+    use crate::httpdownload::download::State as DownloadState;
+    use crate::util::test_server::{test_payload, TestServer};
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use reqwest::Client;
+    use tempfile::TempDir;
+    use test_log::test;
+    use tokio::sync::mpsc;
+
+    const PAYLOAD_SIZE: usize = 5 * MIN_SEGMENT_SIZE as usize + 1234;
+
+    async fn setup(segments: usize) -> TestResult<(HttpDownload, TestServer, TempDir)> {
+        let server = TestServer::spawn(test_payload(PAYLOAD_SIZE));
+        let tmp_dir = TempDir::new()?;
+        let config = HttpDownloadConfig {
+            segments,
+            ..Default::default()
+        };
+        let download = HttpDownload::create(
+            server.url.clone(),
+            tmp_dir.path().to_owned(),
+            Some("file.bin".to_string()),
+            Client::new(),
+            Some(config),
+        )
+        .await?;
+        Ok((download, server, tmp_dir))
+    }
+
+    #[test]
+    fn split_test() {
+        let segments = split(0, 10 * MIN_SEGMENT_SIZE + 3, 4);
+        assert_eq!(segments.len(), 4);
+        assert_eq!(segments[0].start, 0);
+        assert_eq!(segments.last().unwrap().end, 10 * MIN_SEGMENT_SIZE + 3);
+        for pair in segments.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start, "Segments must be contiguous");
+        }
+        // Small ranges are not split below MIN_SEGMENT_SIZE
+        assert_eq!(split(100, 100 + MIN_SEGMENT_SIZE, 8).len(), 1);
+        assert!(split(100, 100, 8).is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn segmented_download_test() -> TestResult<()> {
+        let (download, server, _tmp_dir) = setup(4).await?;
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        let downloaded_bytes = download.start(update_sender).await?;
+        assert_eq!(downloaded_bytes, PAYLOAD_SIZE as u64);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        assert_eq!(
+            server.ranges().len(),
+            4,
+            "Every segment uses its own request"
+        );
+        assert!(!segments_path(&download.file_path()).exists());
+        assert!(!download.part_path().exists());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn partial_file_without_segments_is_resumed_test() -> TestResult<()> {
+        let (download, server, _tmp_dir) = setup(3).await?;
+        let prefix = 2 * MIN_SEGMENT_SIZE as usize + 17;
+        tokio::fs::write(download.part_path(), &server.payload[..prefix]).await?;
+
+        let (update_sender, mut update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        let downloaded_bytes = download.resume(update_sender).await?;
+        assert_eq!(downloaded_bytes, PAYLOAD_SIZE as u64);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        assert!(server
+            .ranges()
+            .iter()
+            .all(|range| !range.starts_with("bytes=0-")));
+        // Progress includes the bytes that were on disk before resuming
+        while let Ok(update) = update_recv.try_recv() {
+            if let DownloadState::Running {
+                bytes_downloaded, ..
+            } = update.state
+            {
+                assert!(bytes_downloaded >= prefix as u64);
+            }
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn allocated_file_without_segments_is_restarted_test() -> TestResult<()> {
+        let (download, server, _tmp_dir) = setup(3).await?;
+        let file = File::create(download.part_path()).await?;
+        file.set_len(download.content_length.unwrap()).await?;
+
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.resume(update_sender).await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        assert!(server
+            .ranges()
+            .iter()
+            .any(|range| range.starts_with("bytes=0-")));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn missing_part_file_is_recreated_test() -> TestResult<()> {
+        let (download, server, _tmp_dir) = setup(3).await?;
+        let segments = split(0, download.content_length.unwrap(), 3);
+        save_segments(&download.file_path(), &segments).await?;
+
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.resume(update_sender).await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn segments_resume_independently_test() -> TestResult<()> {
+        let (download, server, _tmp_dir) = setup(3).await?;
+        let file_path = download.file_path();
+        // Simulate a segmented download that was stopped with every segment partially written
+        let content_length = download.content_length.unwrap();
+        let mut segments = split(0, content_length, 3);
+        let mut file = File::create(download.part_path()).await?;
+        file.set_len(content_length).await?;
+        for (i, segment) in segments.iter_mut().enumerate() {
+            segment.downloaded = (i as u64 + 1) * 1000;
+            let start = segment.start as usize;
+            let end = segment.position() as usize;
+            file.seek(SeekFrom::Start(segment.start)).await?;
+            file.write_all(&server.payload[start..end]).await?;
+        }
+        file.flush().await?;
+        save_segments(&file_path, &segments).await?;
+        assert_eq!(download.get_bytes_on_disk().await, 6000);
+
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        download.resume(update_sender).await?;
+        assert_eq!(tokio::fs::read(&file_path).await?, *server.payload);
+        let expected_ranges: Vec<String> = segments
+            .iter()
+            .map(|s| format!("bytes={}-{}", s.position(), s.end - 1))
+            .collect();
+        let mut ranges = server.ranges();
+        ranges.sort();
+        let mut expected = expected_ranges.clone();
+        expected.sort();
+        assert_eq!(ranges, expected);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn unexpected_range_fails_segment_test() -> TestResult<()> {
+        let (download, server, _tmp_dir) = setup(3).await?;
+        server.shift_next_ranges(1);
+        let (update_sender, _update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        let result = download.start(update_sender.clone()).await;
+        assert!(
+            matches!(result, Err(Error::UnexpectedRange { .. })),
+            "Expected UnexpectedRange, got {:?}",
+            result
+        );
+        // The failed segment is downloaded again on resume
+        download.resume(update_sender).await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload
+        );
+        Ok(())
+    }
+}
@@ -1,2 +1,10 @@
+pub mod bandwidth;
+pub mod credentials;
+pub mod hoster;
 pub mod httpdownload;
+pub mod hyperdownload;
+pub mod p2pdownload;
+pub mod persistence;
+pub mod proxy;
+pub mod schedule;
 pub mod util;
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+
+use super::{Error, Result};
+
+/// Decoded bencode value, dictionary keys are kept sorted which makes encoding canonical
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    pub fn bytes(bytes: impl AsRef<[u8]>) -> Value {
+        Value::Bytes(bytes.as_ref().to_vec())
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::Bencode(reason.to_string())
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Result<u8> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| invalid("unexpected end of data"))
+    }
+
+    /// Reads up to (excluding) `end` and skips it
+    fn read_until(&mut self, end: u8) -> Result<&'a [u8]> {
+        let start = self.pos;
+        let len = self.data[start..]
+            .iter()
+            .position(|b| *b == end)
+            .ok_or_else(|| invalid("unterminated value"))?;
+        self.pos = start + len + 1;
+        Ok(&self.data[start..start + len])
+    }
+
+    fn parse_int(digits: &[u8]) -> Result<i64> {
+        std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid("invalid integer"))
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                Ok(Value::Int(Self::parse_int(self.read_until(b'e')?)?))
+            }
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value()?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.byte_string()?.to_vec();
+                    let value = self.value()?;
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.byte_string()?.to_vec())),
+            _ => Err(invalid("unexpected token")),
+        }
+    }
+
+    fn byte_string(&mut self) -> Result<&'a [u8]> {
+        let len = Self::parse_int(self.read_until(b':')?)?;
+        let len = usize::try_from(len).map_err(|_| invalid("negative length"))?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| invalid("byte string exceeds data"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+/// Decodes a single value that has to span all of `data`
+pub fn decode(data: &[u8]) -> Result<Value> {
+    let (value, len) = decode_prefix(data)?;
+    if len != data.len() {
+        return Err(invalid("trailing data"));
+    }
+    Ok(value)
+}
+
+/// Decodes the value at the start of `data` and returns how many bytes it took
+pub fn decode_prefix(data: &[u8]) -> Result<(Value, usize)> {
+    let mut parser = Parser { data, pos: 0 };
+    let value = parser.value()?;
+    Ok((value, parser.pos))
+}
+
+/// Raw bytes of the value stored under `key` in the top level dictionary of `data`, needed to
+/// hash the info dictionary exactly as it was encoded
+pub fn raw_value<'a>(data: &'a [u8], key: &str) -> Result<&'a [u8]> {
+    let mut parser = Parser { data, pos: 0 };
+    if parser.peek()? != b'd' {
+        return Err(invalid("expected a dictionary"));
+    }
+    parser.pos += 1;
+    while parser.peek()? != b'e' {
+        let current = parser.byte_string()?;
+        let start = parser.pos;
+        parser.value()?;
+        if current == key.as_bytes() {
+            return Ok(&data[start..parser.pos]);
+        }
+    }
+    Err(invalid("key not found"))
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+fn encode_into(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Int(i) => out.extend_from_slice(format!("i{}e", i).as_bytes()),
+        Value::Bytes(bytes) => {
+            out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+            out.extend_from_slice(bytes);
+        }
+        Value::List(list) => {
+            out.push(b'l');
+            for item in list {
+                encode_into(item, out);
+            }
+            out.push(b'e');
+        }
+        Value::Dict(dict) => {
+            out.push(b'd');
+            for (key, item) in dict {
+                out.extend_from_slice(format!("{}:", key.len()).as_bytes());
+                out.extend_from_slice(key);
+                encode_into(item, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn values_round_trip() {
+        let data = b"d4:infod6:lengthi42e4:name4:spame4:listl3:abci-7eee";
+        let value = decode(data).unwrap();
+        assert_eq!(
+            value.get("info").unwrap().get("length").unwrap().as_int(),
+            Some(42)
+        );
+        assert_eq!(
+            value.get("list").unwrap().as_list().unwrap()[1],
+            Value::Int(-7)
+        );
+        assert_eq!(encode(&value), data.to_vec());
+        assert_eq!(
+            raw_value(data, "info").unwrap(),
+            b"d6:lengthi42e4:name4:spame"
+        );
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        assert!(decode(b"i42").is_err());
+        assert!(decode(b"5:abc").is_err());
+        assert!(decode(b"i1ei2e").is_err());
+        assert!(decode(b"d3:key").is_err());
+        assert_eq!(decode_prefix(b"i1ei2e").unwrap(), (Value::Int(1), 3));
+    }
+}
@@ -0,0 +1,440 @@
+use sha1::{Digest, Sha1};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+
+use super::metainfo::Metainfo;
+use super::peer::{
+    self, Handshake, Message, MetadataMessage, BLOCK_SIZE, METADATA_PIECE_SIZE, UT_METADATA_ID,
+};
+use super::swarm::{Event, Swarm};
+use super::{Error, Result};
+
+/// Requests kept in flight per peer
+const PIPELINE: u32 = 5;
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// Largest block a peer may request
+const MAX_REQUEST: u32 = 128 * 1024;
+
+/// Piece currently downloaded from the peer
+#[derive(Debug)]
+struct PieceJob {
+    index: u32,
+    data: Vec<u8>,
+    requested: u32,
+    received: u32,
+}
+
+#[derive(Debug, Default)]
+struct MetadataJob {
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+/// One connection to a peer, we download what the peer has and we want, and we serve every
+/// request of an interested peer (there is no choking algorithm, every peer is unchoked)
+struct Connection {
+    swarm: Arc<Swarm>,
+    addr: SocketAddr,
+    writer: OwnedWriteHalf,
+    /// Raw bitfield of the peer, it may arrive before we know the number of pieces
+    bitfield: Vec<u8>,
+    peer_choking: bool,
+    am_interested: bool,
+    am_choking: bool,
+    /// Id the peer accepts ut_metadata messages with
+    ut_metadata: Option<u8>,
+    metadata: Option<MetadataJob>,
+    job: Option<PieceJob>,
+    in_flight: u32,
+}
+
+/// Connects to the peer and runs the connection until it breaks
+pub async fn connect(swarm: Arc<Swarm>, addr: SocketAddr) {
+    let result = match tokio::time::timeout(Duration::from_secs(10), TcpStream::connect(addr)).await
+    {
+        Ok(Ok(stream)) => run(swarm.clone(), stream, addr).await,
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(Error::Peer("connect timed out".to_string())),
+    };
+    if let Err(e) = result {
+        log::debug!("Connection to peer {} ended: {}", addr, e);
+    }
+    swarm.remove_peer(&addr);
+}
+
+/// Runs a connection a peer opened to us
+pub async fn accept(swarm: Arc<Swarm>, stream: TcpStream, addr: SocketAddr) {
+    if let Err(e) = run(swarm.clone(), stream, addr).await {
+        log::debug!("Connection from peer {} ended: {}", addr, e);
+    }
+    swarm.remove_peer(&addr);
+}
+
+async fn run(swarm: Arc<Swarm>, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+    let ours = Handshake {
+        info_hash: swarm.info_hash,
+        peer_id: swarm.peer_id,
+        extensions: true,
+    };
+    let theirs = tokio::time::timeout(Duration::from_secs(10), peer::handshake(&mut stream, &ours))
+        .await
+        .map_err(|_| Error::Peer("handshake timed out".to_string()))??;
+    if theirs.peer_id == swarm.peer_id {
+        return Err(Error::Peer("connected to ourselves".to_string()));
+    }
+    log::debug!("Connected to peer {}", addr);
+
+    let (mut reader, writer) = stream.into_split();
+    let (message_tx, mut messages) = mpsc::channel(64);
+    // The reader runs on its own so reading is never interrupted halfway through a message
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let message = peer::read_message(&mut reader).await;
+            let failed = message.is_err();
+            if message_tx.send(message).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    let mut connection = Connection {
+        swarm: swarm.clone(),
+        addr,
+        writer,
+        bitfield: Vec::new(),
+        peer_choking: true,
+        am_interested: false,
+        am_choking: true,
+        ut_metadata: None,
+        metadata: None,
+        job: None,
+        in_flight: 0,
+    };
+    let mut events = swarm.subscribe();
+    let result = connection.serve(theirs, &mut messages, &mut events).await;
+    reader_task.abort();
+    if let Some(job) = connection.job.take() {
+        swarm.release_piece(job.index);
+    }
+    result
+}
+
+impl Connection {
+    async fn serve(
+        &mut self,
+        theirs: Handshake,
+        messages: &mut mpsc::Receiver<Result<Message>>,
+        events: &mut broadcast::Receiver<Event>,
+    ) -> Result<()> {
+        if theirs.extensions {
+            let size = self.swarm.metainfo().map(|m| m.info_bytes.len());
+            self.send(&peer::extended_handshake(size)).await?;
+        }
+        if let Some(bitfield) = self.swarm.bitfield() {
+            self.send(&Message::Bitfield(bitfield)).await?;
+        }
+        let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+        loop {
+            tokio::select! {
+                message = messages.recv() => match message {
+                    Some(message) => self.handle(message?).await?,
+                    None => return Ok(()),
+                },
+                event = events.recv() => match event {
+                    Ok(Event::Have(index)) => self.send(&Message::Have(index)).await?,
+                    Ok(Event::Metadata) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = keep_alive.tick() => self.send(&Message::KeepAlive).await?,
+            }
+            self.update_interest().await?;
+            self.request_metadata().await?;
+            self.request_blocks().await?;
+        }
+    }
+
+    async fn send(&mut self, message: &Message) -> Result<()> {
+        peer::write_message(&mut self.writer, message).await
+    }
+
+    fn peer_has(bitfield: &[u8], index: u32) -> bool {
+        bitfield
+            .get(index as usize / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    fn set_have(&mut self, index: u32) {
+        let byte = index as usize / 8;
+        if self.bitfield.len() <= byte {
+            self.bitfield.resize(byte + 1, 0);
+        }
+        self.bitfield[byte] |= 0x80 >> (index % 8);
+    }
+
+    async fn handle(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::KeepAlive | Message::Unknown(_) | Message::Cancel { .. } => {}
+            Message::Choke => {
+                self.peer_choking = true;
+                // Pending requests are dropped by the peer, the piece is started over
+                if let Some(job) = self.job.take() {
+                    self.swarm.release_piece(job.index);
+                }
+                self.in_flight = 0;
+            }
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => {
+                if self.am_choking {
+                    self.am_choking = false;
+                    self.send(&Message::Unchoke).await?;
+                }
+            }
+            Message::NotInterested => {}
+            Message::Have(index) => self.set_have(index),
+            Message::Bitfield(bitfield) => self.bitfield = bitfield,
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => self.upload(index, begin, length).await?,
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => self.receive_block(index, begin, block).await?,
+            Message::Extended { id: 0, payload } => {
+                let (id, size) = peer::parse_extended_handshake(&payload)?;
+                self.ut_metadata = id;
+                if let (Some(size), None) = (size, self.swarm.metainfo()) {
+                    if size > 0 && size <= 16 * 1024 * 1024 {
+                        self.metadata = Some(MetadataJob {
+                            size,
+                            pieces: Vec::new(),
+                        });
+                    }
+                }
+            }
+            Message::Extended {
+                id: UT_METADATA_ID,
+                payload,
+            } => {
+                self.handle_metadata(MetadataMessage::decode(&payload)?)
+                    .await?
+            }
+            Message::Extended { .. } => {}
+        }
+        Ok(())
+    }
+
+    async fn update_interest(&mut self) -> Result<()> {
+        if !self.am_interested && self.swarm.wants_from(|i| Self::peer_has(&self.bitfield, i)) {
+            self.am_interested = true;
+            self.send(&Message::Interested).await?;
+        }
+        Ok(())
+    }
+
+    async fn upload(&mut self, index: u32, begin: u32, length: u32) -> Result<()> {
+        let (Some(metainfo), Some(storage)) = (self.swarm.metainfo(), self.swarm.storage()) else {
+            return Ok(());
+        };
+        let piece_size = metainfo.piece_size(index) as u32;
+        if self.am_choking
+            || length > MAX_REQUEST
+            || begin.saturating_add(length) > piece_size
+            || !self.swarm.has_piece(index)
+        {
+            log::debug!("Ignoring request of peer {} for piece {}", self.addr, index);
+            return Ok(());
+        }
+        let offset = metainfo.piece_range(index).0 + begin as u64;
+        let block = tokio::task::spawn_blocking(move || storage.read(offset, length as u64))
+            .await
+            .map_err(|e| Error::Peer(e.to_string()))??;
+        self.send(&Message::Piece {
+            index,
+            begin,
+            block,
+        })
+        .await?;
+        self.swarm
+            .uploaded
+            .fetch_add(length as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn receive_block(&mut self, index: u32, begin: u32, block: Vec<u8>) -> Result<()> {
+        let Some(job) = self.job.as_mut().filter(|job| job.index == index) else {
+            return Ok(());
+        };
+        let end = begin as usize + block.len();
+        if end > job.data.len() {
+            return Err(Error::Peer(format!(
+                "block out of bounds for piece {}",
+                index
+            )));
+        }
+        job.data[begin as usize..end].copy_from_slice(&block);
+        job.received += block.len() as u32;
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.swarm
+            .downloaded
+            .fetch_add(block.len() as u64, Ordering::SeqCst);
+        if job.received as usize >= job.data.len() {
+            let job = self.job.take().expect("job is set");
+            self.finish_piece(job).await?;
+        }
+        Ok(())
+    }
+
+    /// Verifies the piece and writes it to disk, a piece with a wrong hash is downloaded again
+    async fn finish_piece(&mut self, job: PieceJob) -> Result<()> {
+        let (Some(metainfo), Some(storage)) = (self.swarm.metainfo(), self.swarm.storage()) else {
+            return Ok(());
+        };
+        if Sha1::digest(&job.data).as_slice() != metainfo.pieces[job.index as usize] {
+            log::warn!(
+                "Piece {} from peer {} failed verification",
+                job.index,
+                self.addr
+            );
+            self.swarm.release_piece(job.index);
+            return Ok(());
+        }
+        let (offset, size) = metainfo.piece_range(job.index);
+        let data = job.data;
+        let written = tokio::task::spawn_blocking(move || storage.write(offset, &data)).await;
+        match written {
+            Ok(Ok(())) => {
+                self.swarm.complete_piece(job.index, size);
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                self.swarm.release_piece(job.index);
+                Err(e.into())
+            }
+            Err(e) => {
+                self.swarm.release_piece(job.index);
+                Err(Error::Peer(e.to_string()))
+            }
+        }
+    }
+
+    /// Keeps up to PIPELINE block requests in flight
+    async fn request_blocks(&mut self) -> Result<()> {
+        if self.peer_choking || !self.am_interested {
+            return Ok(());
+        }
+        let Some(metainfo) = self.swarm.metainfo() else {
+            return Ok(());
+        };
+        if self.job.is_none() {
+            let bitfield = &self.bitfield;
+            let Some(index) = self.swarm.pick_piece(|i| Self::peer_has(bitfield, i)) else {
+                return Ok(());
+            };
+            self.job = Some(PieceJob {
+                index,
+                data: vec![0; metainfo.piece_size(index) as usize],
+                requested: 0,
+                received: 0,
+            });
+        }
+        while self.in_flight < PIPELINE {
+            let Some(job) = self.job.as_mut() else {
+                break;
+            };
+            let size = job.data.len() as u32;
+            if job.requested >= size {
+                break;
+            }
+            let request = Message::Request {
+                index: job.index,
+                begin: job.requested,
+                length: BLOCK_SIZE.min(size - job.requested),
+            };
+            job.requested += BLOCK_SIZE.min(size - job.requested);
+            self.in_flight += 1;
+            self.send(&request).await?;
+        }
+        Ok(())
+    }
+
+    /// Requests the missing metadata pieces of a magnet link all at once
+    async fn request_metadata(&mut self) -> Result<()> {
+        let (Some(id), Some(job)) = (self.ut_metadata, self.metadata.as_mut()) else {
+            return Ok(());
+        };
+        if self.swarm.metainfo().is_some() {
+            self.metadata = None;
+            return Ok(());
+        }
+        if !job.pieces.is_empty() {
+            return Ok(());
+        }
+        let count = job.size.div_ceil(METADATA_PIECE_SIZE);
+        job.pieces = vec![None; count];
+        for piece in 0..count {
+            self.send(&Message::Extended {
+                id,
+                payload: MetadataMessage::Request(piece).encode(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_metadata(&mut self, message: MetadataMessage) -> Result<()> {
+        match message {
+            MetadataMessage::Request(piece) => {
+                let Some(id) = self.ut_metadata else {
+                    return Ok(());
+                };
+                let data = self.swarm.metainfo().and_then(|metainfo| {
+                    // The piece index comes from the peer, an absurd one is rejected
+                    let start = piece.checked_mul(METADATA_PIECE_SIZE)?;
+                    let info = &metainfo.info_bytes;
+                    (start < info.len())
+                        .then(|| info[start..info.len().min(start + METADATA_PIECE_SIZE)].to_vec())
+                });
+                let reply = match data {
+                    Some(data) => MetadataMessage::Data { piece, data },
+                    None => MetadataMessage::Reject(piece),
+                };
+                self.send(&Message::Extended {
+                    id,
+                    payload: reply.encode(),
+                })
+                .await?;
+            }
+            MetadataMessage::Data { piece, data } => {
+                let Some(job) = self.metadata.as_mut() else {
+                    return Ok(());
+                };
+                if let Some(slot) = job.pieces.get_mut(piece) {
+                    *slot = Some(data);
+                }
+                if job.pieces.iter().all(Option::is_some) {
+                    let job = self.metadata.take().expect("metadata job is set");
+                    let info: Vec<u8> = job.pieces.into_iter().flatten().flatten().collect();
+                    let hash: [u8; 20] = Sha1::digest(&info).into();
+                    if info.len() != job.size || hash != self.swarm.info_hash {
+                        return Err(Error::Peer("received invalid metadata".to_string()));
+                    }
+                    let trackers = Vec::new();
+                    self.swarm
+                        .set_metainfo(Metainfo::from_info(info, trackers)?);
+                }
+            }
+            MetadataMessage::Reject(_) => {
+                self.metadata = None;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use reqwest::Url;
+
+use super::metainfo::InfoHash;
+use super::tracker::url_encode;
+use super::{Error, Result};
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Magnet link of a BitTorrent v1 torrent, the metadata is fetched from peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// Display name (`dn`)
+    pub name: Option<String>,
+    /// Trackers (`tr`)
+    pub trackers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidMagnet(format!("{}: {}", reason, uri));
+        let url = Url::parse(uri).map_err(|_| invalid("not a URI"))?;
+        if url.scheme() != "magnet" {
+            return Err(invalid("not a magnet link"));
+        }
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash =
+                            Some(parse_info_hash(hash).ok_or_else(|| invalid("invalid btih"))?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            info_hash: info_hash.ok_or_else(|| invalid("missing urn:btih"))?,
+            name,
+            trackers,
+        })
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!("magnet:?xt=urn:btih:{}", hex::encode(self.info_hash));
+        if let Some(name) = self.name.as_ref() {
+            uri.push_str("&dn=");
+            uri.push_str(&url_encode(name.as_bytes()));
+        }
+        for tracker in self.trackers.iter() {
+            uri.push_str("&tr=");
+            uri.push_str(&url_encode(tracker.as_bytes()));
+        }
+        uri
+    }
+}
+
+/// Info hashes are either hex (40 characters) or base32 (32 characters) encoded
+fn parse_info_hash(hash: &str) -> Option<InfoHash> {
+    match hash.len() {
+        40 => hex::decode(hash).ok()?.try_into().ok(),
+        32 => {
+            let mut bits = 0u64;
+            let mut bit_count = 0;
+            let mut bytes = Vec::with_capacity(20);
+            for c in hash.bytes() {
+                let value = BASE32_ALPHABET
+                    .iter()
+                    .position(|a| *a == c.to_ascii_uppercase())?;
+                bits = (bits << 5) | value as u64;
+                bit_count += 5;
+                if bit_count >= 8 {
+                    bit_count -= 8;
+                    bytes.push((bits >> bit_count) as u8);
+                }
+            }
+            bytes.try_into().ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn magnet_links_are_parsed() {
+        let hex = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+        let magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=some%20file&tr=http%3A%2F%2Ftracker.invalid%2Fannounce",
+            hex
+        ))
+        .unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), hex);
+        assert_eq!(magnet.name.as_deref(), Some("some file"));
+        assert_eq!(magnet.trackers, vec!["http://tracker.invalid/announce"]);
+        assert_eq!(MagnetLink::parse(&magnet.to_uri()).unwrap(), magnet);
+
+        let base32 =
+            MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+
+        assert!(MagnetLink::parse("magnet:?dn=missing").is_err());
+        assert!(MagnetLink::parse("https://example.com/?xt=urn:btih:abc").is_err());
+    }
+}
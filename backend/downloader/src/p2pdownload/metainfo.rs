@@ -0,0 +1,371 @@
+use sha1::{Digest, Sha1};
+use std::path::{Component, Path, PathBuf};
+
+use super::bencode::{self, Value};
+use super::{Error, Result};
+
+pub type InfoHash = [u8; 20];
+
+/// File of a torrent, `offset` is its position in the concatenated data of all files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// Relative to the directory of the torrent
+    pub path: PathBuf,
+    pub length: u64,
+    pub offset: u64,
+}
+
+/// Parsed torrent file (BEP 3)
+#[derive(Debug, Clone)]
+pub struct Metainfo {
+    pub info_hash: InfoHash,
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<[u8; 20]>,
+    pub files: Vec<FileInfo>,
+    pub total_length: u64,
+    /// Multi-file torrents are stored in a directory named after the torrent
+    pub multi_file: bool,
+    pub trackers: Vec<String>,
+    /// Bencoded info dictionary, served to peers that fetch the metadata of a magnet link
+    pub info_bytes: Vec<u8>,
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidTorrent(reason.into())
+}
+
+/// Path components of a torrent must not escape the download directory
+fn sanitized_component(component: &str) -> Result<&str> {
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(component),
+        _ => Err(invalid(format!("invalid path component '{}'", component))),
+    }
+}
+
+impl Metainfo {
+    /// Parses the content of a .torrent file
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let torrent = bencode::decode(data)?;
+        let info_bytes = bencode::raw_value(data, "info")?.to_vec();
+        let mut trackers = Vec::new();
+        if let Some(tiers) = torrent.get("announce-list").and_then(Value::as_list) {
+            for tracker in tiers.iter().filter_map(Value::as_list).flatten() {
+                if let Some(tracker) = tracker.as_str() {
+                    trackers.push(tracker.to_string());
+                }
+            }
+        }
+        if let Some(tracker) = torrent.get("announce").and_then(Value::as_str) {
+            if !trackers.iter().any(|t| t == tracker) {
+                trackers.insert(0, tracker.to_string());
+            }
+        }
+        Self::from_info(info_bytes, trackers)
+    }
+
+    /// Parses a bencoded info dictionary, e.g. the metadata received from peers
+    pub fn from_info(info_bytes: Vec<u8>, trackers: Vec<String>) -> Result<Self> {
+        let info = bencode::decode(&info_bytes)?;
+        let info_hash: InfoHash = Sha1::digest(&info_bytes).into();
+        let name = info
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("missing name"))?;
+        let name = sanitized_component(name)?.to_string();
+        let piece_length = info
+            .get("piece length")
+            .and_then(Value::as_int)
+            .filter(|len| *len > 0)
+            .ok_or_else(|| invalid("missing piece length"))? as u64;
+        let pieces = info
+            .get("pieces")
+            .and_then(Value::as_bytes)
+            .filter(|pieces| pieces.len() % 20 == 0)
+            .ok_or_else(|| invalid("missing piece hashes"))?
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().expect("chunks have 20 bytes"))
+            .collect::<Vec<[u8; 20]>>();
+
+        let mut files = Vec::new();
+        let mut offset = 0;
+        let multi_file = match (info.get("length"), info.get("files")) {
+            (Some(length), None) => {
+                let length = length
+                    .as_int()
+                    .filter(|len| *len >= 0)
+                    .ok_or_else(|| invalid("invalid length"))? as u64;
+                files.push(FileInfo {
+                    path: PathBuf::from(&name),
+                    length,
+                    offset,
+                });
+                offset += length;
+                false
+            }
+            (None, Some(list)) => {
+                for file in list.as_list().ok_or_else(|| invalid("invalid files"))? {
+                    let length = file
+                        .get("length")
+                        .and_then(Value::as_int)
+                        .filter(|len| *len >= 0)
+                        .ok_or_else(|| invalid("invalid file length"))?
+                        as u64;
+                    let mut path = PathBuf::new();
+                    for component in file
+                        .get("path")
+                        .and_then(Value::as_list)
+                        .ok_or_else(|| invalid("missing file path"))?
+                    {
+                        let component = component
+                            .as_str()
+                            .ok_or_else(|| invalid("invalid file path"))?;
+                        path.push(sanitized_component(component)?);
+                    }
+                    if path.as_os_str().is_empty() {
+                        return Err(invalid("empty file path"));
+                    }
+                    files.push(FileInfo {
+                        path,
+                        length,
+                        offset,
+                    });
+                    offset += length;
+                }
+                true
+            }
+            _ => return Err(invalid("either length or files is required")),
+        };
+        if pieces.len() as u64 != offset.div_ceil(piece_length) {
+            return Err(invalid("piece count doesn't match the size"));
+        }
+        Ok(Self {
+            info_hash,
+            name,
+            piece_length,
+            pieces,
+            files,
+            total_length: offset,
+            multi_file,
+            trackers,
+            info_bytes,
+        })
+    }
+
+    /// Creates a torrent for the file or directory at `path`, files of a directory are added in
+    /// alphabetical order
+    pub fn create(path: &Path, piece_length: u64, trackers: Vec<String>) -> Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| invalid("path has no name"))?;
+        let mut files = Vec::new();
+        if path.is_dir() {
+            collect_files(path, PathBuf::new(), &mut files)?;
+        } else {
+            files.push(PathBuf::new());
+        }
+        let mut hasher = PieceHasher::new(piece_length);
+        let mut entries = Vec::new();
+        for relative in files.iter() {
+            let data = match relative.as_os_str().is_empty() {
+                true => std::fs::read(path)?,
+                false => std::fs::read(path.join(relative))?,
+            };
+            hasher.update(&data);
+            let components = relative
+                .components()
+                .map(|c| Value::bytes(c.as_os_str().to_string_lossy().as_bytes()))
+                .collect();
+            entries.push(Value::dict([
+                ("length", Value::Int(data.len() as i64)),
+                ("path", Value::List(components)),
+            ]));
+        }
+        let mut info = vec![
+            ("name", Value::bytes(name)),
+            ("piece length", Value::Int(piece_length as i64)),
+            ("pieces", Value::Bytes(hasher.finish())),
+        ];
+        if path.is_dir() {
+            info.push(("files", Value::List(entries)));
+        } else {
+            let length = entries[0].get("length").cloned().expect("length is set");
+            info.push(("length", length));
+        }
+        Self::from_info(bencode::encode(&Value::dict(info)), trackers)
+    }
+
+    /// Bencoded .torrent file
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut torrent = vec![("info", bencode::decode(&self.info_bytes)?)];
+        if let Some(tracker) = self.trackers.first() {
+            torrent.push(("announce", Value::bytes(tracker)));
+            let tiers = self
+                .trackers
+                .iter()
+                .map(|tracker| Value::List(vec![Value::bytes(tracker)]))
+                .collect();
+            torrent.push(("announce-list", Value::List(tiers)));
+        }
+        Ok(bencode::encode(&Value::dict(torrent)))
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    /// Pieces are `piece_length` long except for the last one
+    pub fn piece_size(&self, index: u32) -> u64 {
+        let start = index as u64 * self.piece_length;
+        self.piece_length
+            .min(self.total_length.saturating_sub(start))
+    }
+
+    /// Range of the data of the piece as (offset, length)
+    pub fn piece_range(&self, index: u32) -> (u64, u64) {
+        (index as u64 * self.piece_length, self.piece_size(index))
+    }
+
+    /// Marks the pieces holding data of the selected files, all files are selected if None.
+    /// Indices without a file are ignored.
+    pub fn wanted_pieces(&self, selection: Option<&[usize]>) -> Vec<bool> {
+        let mut wanted = vec![false; self.pieces.len()];
+        for (index, file) in self.files.iter().enumerate() {
+            if selection.is_none_or(|selection| selection.contains(&index)) {
+                for piece in self.file_pieces(file) {
+                    wanted[piece as usize] = true;
+                }
+            }
+        }
+        wanted
+    }
+
+    /// Combined size of the marked pieces
+    pub fn pieces_size(&self, pieces: &[bool]) -> u64 {
+        (0..self.piece_count())
+            .filter(|index| pieces[*index as usize])
+            .map(|index| self.piece_size(index))
+            .sum()
+    }
+
+    /// Pieces holding data of the file, the first and last one may be shared with neighbours
+    pub fn file_pieces(&self, file: &FileInfo) -> std::ops::Range<u32> {
+        if file.length == 0 {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length;
+        let last = (file.offset + file.length - 1) / self.piece_length;
+        first as u32..last as u32 + 1
+    }
+}
+
+fn collect_files(root: &Path, relative: PathBuf, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(root.join(&relative))?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        let path = relative.join(entry);
+        if root.join(&path).is_dir() {
+            collect_files(root, path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Hashes a stream of data in pieces of a fixed length
+struct PieceHasher {
+    piece_length: u64,
+    current: Sha1,
+    current_len: u64,
+    hashes: Vec<u8>,
+}
+
+impl PieceHasher {
+    fn new(piece_length: u64) -> Self {
+        Self {
+            piece_length,
+            current: Sha1::new(),
+            current_len: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = ((self.piece_length - self.current_len) as usize).min(data.len());
+            self.current.update(&data[..take]);
+            self.current_len += take as u64;
+            data = &data[take..];
+            if self.current_len == self.piece_length {
+                let hash = std::mem::replace(&mut self.current, Sha1::new()).finalize();
+                self.hashes.extend_from_slice(&hash);
+                self.current_len = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.current_len > 0 {
+            self.hashes.extend_from_slice(&self.current.finalize());
+        }
+        self.hashes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn torrents_round_trip() -> TestResult<()> {
+        let tmp_dir = TempDir::new()?;
+        let root = tmp_dir.path().join("album");
+        std::fs::create_dir_all(root.join("cd2"))?;
+        std::fs::write(root.join("a.bin"), vec![1u8; 40_000])?;
+        std::fs::write(root.join("cd2").join("b.bin"), vec![2u8; 10_000])?;
+        let trackers = vec!["http://tracker.invalid/announce".to_string()];
+        let created = Metainfo::create(&root, 16 * 1024, trackers.clone())?;
+        assert!(created.multi_file);
+        assert_eq!(created.total_length, 50_000);
+        assert_eq!(created.piece_count(), 4);
+        assert_eq!(created.piece_size(3), 50_000 - 3 * 16 * 1024);
+        assert_eq!(created.files[1].path, PathBuf::from("cd2").join("b.bin"));
+        assert_eq!(created.files[1].offset, 40_000);
+        assert_eq!(created.file_pieces(&created.files[1]), 2..4);
+
+        let parsed = Metainfo::from_bytes(&created.to_bytes()?)?;
+        assert_eq!(parsed.info_hash, created.info_hash);
+        assert_eq!(parsed.files, created.files);
+        assert_eq!(parsed.trackers, trackers);
+        Ok(())
+    }
+
+    #[test]
+    fn paths_escaping_the_directory_are_rejected() {
+        let info = Value::dict([
+            ("name", Value::bytes("evil")),
+            ("piece length", Value::Int(16)),
+            ("pieces", Value::Bytes(vec![0; 20])),
+            (
+                "files",
+                Value::List(vec![Value::dict([
+                    ("length", Value::Int(10)),
+                    (
+                        "path",
+                        Value::List(vec![Value::bytes(".."), Value::bytes("passwd")]),
+                    ),
+                ])]),
+            ),
+        ]);
+        let result = Metainfo::from_info(bencode::encode(&info), Vec::new());
+        assert!(matches!(result, Err(Error::InvalidTorrent(_))));
+    }
+}
@@ -0,0 +1,595 @@
+pub mod bencode;
+mod connection;
+pub mod magnet;
+pub mod metainfo;
+pub mod peer;
+pub mod storage;
+mod swarm;
+pub mod tracker;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
+
+use crate::httpdownload::download::progress::ProgressTracker;
+use crate::httpdownload::download::{DownloadUpdate, State};
+use crate::httpdownload::{Download, DownloadMetadata};
+use crate::util::{mb, HALF_SECOND};
+
+use self::magnet::MagnetLink;
+use self::metainfo::Metainfo;
+use self::swarm::Swarm;
+use self::tracker::{Announce, Event};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("File IO operation failed, error: '{0}'")]
+    Io(#[from] std::io::Error),
+    #[error("Request error: '{0}'")]
+    Request(#[from] reqwest::Error),
+    #[error("Invalid bencode: {0}")]
+    Bencode(String),
+    #[error("Invalid torrent: {0}")]
+    InvalidTorrent(String),
+    #[error("Invalid magnet link: {0}")]
+    InvalidMagnet(String),
+    #[error("Tracker error: {0}")]
+    Tracker(String),
+    #[error("Peer error: {0}")]
+    Peer(String),
+    #[error("The torrent has no file with index {0}")]
+    InvalidFileSelection(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Waiting time between announces is capped, so new peers are found in a reasonable time
+const MAX_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TorrentConfig {
+    /// Port peers connect to, 0 picks a free port on every run
+    pub listen_port: u16,
+    /// Seeding stops once the uploaded bytes reach `seed_ratio` times the size of the download,
+    /// 0 completes the download right after the last piece
+    pub seed_ratio: f64,
+    /// Indices of the files of a multi-file torrent to download, all files if None.
+    /// Pieces shared with neighbouring files are written completely.
+    pub files: Option<Vec<usize>>,
+    pub max_peers: usize,
+}
+
+impl Default for TorrentConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: 0,
+            seed_ratio: 0.0,
+            files: None,
+            max_peers: 50,
+        }
+    }
+}
+
+/// BitTorrent download of a .torrent file or a magnet link.
+/// Pieces are verified against their hash before they are written, data already on disk is
+/// verified at the start of every run and only missing pieces are downloaded.
+/// Clones share the metadata and the transfer counters.
+#[derive(Debug, Clone)]
+pub struct TorrentDownload {
+    pub id: Uuid,
+    pub directory: PathBuf,
+    pub magnet: MagnetLink,
+    pub config: TorrentConfig,
+    /// Used to announce to trackers
+    pub client: Client,
+    /// Missing until fetched from peers for magnet links
+    metainfo: Arc<RwLock<Option<Arc<Metainfo>>>>,
+    verified: Arc<AtomicU64>,
+    uploaded: Arc<AtomicU64>,
+}
+
+impl TorrentDownload {
+    pub const KIND: &'static str = "torrent";
+
+    /// Creates the download from the content of a .torrent file
+    pub fn from_torrent(data: &[u8], directory: PathBuf, config: TorrentConfig) -> Result<Self> {
+        let metainfo = Metainfo::from_bytes(data)?;
+        if let Some(index) = config
+            .files
+            .iter()
+            .flatten()
+            .find(|index| **index >= metainfo.files.len())
+        {
+            return Err(Error::InvalidFileSelection(*index));
+        }
+        let magnet = MagnetLink {
+            info_hash: metainfo.info_hash,
+            name: Some(metainfo.name.clone()),
+            trackers: metainfo.trackers.clone(),
+        };
+        let download = Self::new(magnet, directory, config);
+        *download.metainfo.write().unwrap() = Some(Arc::new(metainfo));
+        Ok(download)
+    }
+
+    /// Creates the download from a magnet link, the metadata is fetched from the first peer
+    /// that has it once the download runs
+    pub fn from_magnet(uri: &str, directory: PathBuf, config: TorrentConfig) -> Result<Self> {
+        Ok(Self::new(MagnetLink::parse(uri)?, directory, config))
+    }
+
+    fn new(magnet: MagnetLink, directory: PathBuf, config: TorrentConfig) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            directory,
+            magnet,
+            config,
+            client: Client::new(),
+            metainfo: Arc::new(RwLock::new(None)),
+            verified: Arc::new(AtomicU64::new(0)),
+            uploaded: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn metainfo(&self) -> Option<Arc<Metainfo>> {
+        self.metainfo.read().unwrap().clone()
+    }
+
+    /// Name of the torrent, the info hash until the metadata of a magnet link without a display
+    /// name is known
+    pub fn name(&self) -> String {
+        match (self.metainfo(), self.magnet.name.as_ref()) {
+            (Some(metainfo), _) => metainfo.name.clone(),
+            (None, Some(name)) => name.clone(),
+            (None, None) => hex::encode(self.magnet.info_hash),
+        }
+    }
+
+    /// The file of a single file torrent, the directory of a multi-file torrent
+    pub fn file_path(&self) -> PathBuf {
+        self.directory.join(self.name())
+    }
+
+    /// Bytes sent to peers since the download was created
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::SeqCst)
+    }
+
+    fn swarm(&self) -> Arc<Swarm> {
+        Swarm::new(
+            self.magnet.info_hash,
+            self.directory.clone(),
+            self.config.files.clone(),
+            self.metainfo.clone(),
+            self.verified.clone(),
+            self.uploaded.clone(),
+        )
+    }
+
+    /// Verifies the data on disk and returns the bytes of the selected files that are complete
+    pub async fn check(&self) -> u64 {
+        self.swarm().check_existing().await;
+        self.verified.load(Ordering::SeqCst)
+    }
+
+    /// RestoreFn of TorrentDownloads for the SqliteStore
+    pub fn restore(record: serde_json::Value) -> anyhow::Result<Box<dyn Download>> {
+        Ok(Box::new(Self::from_record(record)?))
+    }
+
+    /// Restores a download persisted with `Download::to_record`, transfer counters start at 0
+    pub fn from_record(record: serde_json::Value) -> Result<Self> {
+        let record: TorrentRecord = serde_json::from_value(record)
+            .map_err(|e| Error::InvalidTorrent(format!("invalid record: {}", e)))?;
+        let mut download = Self::new(
+            MagnetLink::parse(&record.magnet)?,
+            record.directory,
+            record.config,
+        );
+        download.id = record.id;
+        if let Some(info) = record.info {
+            let info = hex::decode(info)
+                .map_err(|e| Error::InvalidTorrent(format!("invalid info dictionary: {}", e)))?;
+            let metainfo = Metainfo::from_info(info, download.magnet.trackers.clone())?;
+            *download.metainfo.write().unwrap() = Some(Arc::new(metainfo));
+        }
+        Ok(download)
+    }
+
+    fn trackers(&self) -> Vec<String> {
+        let mut trackers = self.magnet.trackers.clone();
+        for tracker in self.metainfo().iter().flat_map(|m| m.trackers.iter()) {
+            if !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+        trackers
+    }
+
+    /// Downloads the missing pieces and seeds until the seed ratio is reached, returns the size
+    /// of the download
+    pub async fn run(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+        let swarm = self.swarm();
+        // Every connection and announce task ends with the run
+        let _shutdown = ShutdownGuard(swarm.clone());
+        swarm.check_existing().await;
+        let listener = TcpListener::bind(("0.0.0.0", self.config.listen_port)).await?;
+        let port = listener.local_addr()?.port();
+        log::info!(
+            "Running torrent {} ({}), listening on port {}",
+            self.name(),
+            self.id,
+            port
+        );
+        let max_peers = self.config.max_peers;
+        let accepting = swarm.clone();
+        swarm.spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                if accepting.peer_count() >= max_peers || !accepting.add_peer(addr) {
+                    continue;
+                }
+                accepting.spawn(connection::accept(accepting.clone(), stream, addr));
+            }
+        });
+        let trackers = self.trackers();
+        if trackers.is_empty() {
+            log::warn!("Torrent {} has no trackers, waiting for peers", self.id);
+        }
+        for tracker in trackers {
+            swarm.spawn(announce_loop(
+                swarm.clone(),
+                self.client.clone(),
+                tracker,
+                port,
+                max_peers,
+            ));
+        }
+
+        let mut ticker = tokio::time::interval(HALF_SECOND);
+        let mut tracker =
+            ProgressTracker::new(swarm.wanted_size(), self.verified.load(Ordering::SeqCst));
+        while !swarm.is_complete() {
+            ticker.tick().await;
+            // The size of a magnet link is known once its metadata was fetched
+            tracker.set_total(swarm.wanted_size());
+            let _ = update_ch.try_send(DownloadUpdate {
+                id: self.id,
+                state: tracker.update(self.verified.load(Ordering::SeqCst)),
+            });
+        }
+        let size = swarm.wanted_size().unwrap_or_default();
+        log::info!(
+            "Torrent {} downloaded successfully: {}MB",
+            self.name(),
+            mb(size)
+        );
+
+        let seed_target = (size as f64 * self.config.seed_ratio.max(0.0)) as u64;
+        while self.uploaded() < seed_target {
+            let _ = update_ch.try_send(DownloadUpdate {
+                id: self.id,
+                state: State::Seeding {
+                    bytes_uploaded: self.uploaded(),
+                },
+            });
+            ticker.tick().await;
+        }
+        if seed_target > 0 {
+            log::info!("Torrent {} reached its seed ratio", self.name());
+        }
+        Ok(size)
+    }
+}
+
+/// Persisted form of a TorrentDownload
+#[derive(Debug, Serialize, Deserialize)]
+struct TorrentRecord {
+    id: Uuid,
+    directory: PathBuf,
+    magnet: String,
+    config: TorrentConfig,
+    /// Hex encoded info dictionary, None until the metadata of a magnet link is known
+    info: Option<String>,
+}
+
+/// Aborts all tasks of the swarm when the run ends or is cancelled
+struct ShutdownGuard(Arc<Swarm>);
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
+
+/// Announces to the tracker at the interval it asks for and connects to the returned peers
+async fn announce_loop(
+    swarm: Arc<Swarm>,
+    client: Client,
+    tracker: String,
+    port: u16,
+    max_peers: usize,
+) {
+    let mut event = Some(Event::Started);
+    let mut complete = swarm.is_complete();
+    loop {
+        let announce = Announce {
+            info_hash: swarm.info_hash,
+            peer_id: swarm.peer_id,
+            port,
+            uploaded: swarm.uploaded.load(Ordering::SeqCst),
+            downloaded: swarm.downloaded.load(Ordering::SeqCst),
+            left: swarm
+                .wanted_size()
+                .map(|size| size.saturating_sub(swarm.verified.load(Ordering::SeqCst)))
+                .unwrap_or(1),
+            event,
+        };
+        let interval = match tracker::announce(&client, &tracker, &announce).await {
+            Ok(response) => {
+                event = None;
+                log::debug!(
+                    "Tracker {} returned {} peers",
+                    tracker,
+                    response.peers.len()
+                );
+                for addr in response.peers {
+                    if swarm.peer_count() < max_peers && swarm.add_peer(addr) {
+                        swarm.spawn(connection::connect(swarm.clone(), addr));
+                    }
+                }
+                response.interval.min(MAX_ANNOUNCE_INTERVAL)
+            }
+            Err(e) => {
+                log::warn!("Announce to {} failed: {}", tracker, e);
+                MAX_ANNOUNCE_INTERVAL
+            }
+        };
+        // Wakes up early to report the completion
+        let deadline = Instant::now() + interval;
+        while Instant::now() < deadline {
+            tokio::time::sleep(HALF_SECOND.min(deadline - Instant::now())).await;
+            if !complete && swarm.is_complete() {
+                complete = true;
+                event = Some(Event::Completed);
+                break;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Download for TorrentDownload {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn get_metadata(&self) -> DownloadMetadata {
+        let download_size = self.metainfo().map(|metainfo| {
+            metainfo.pieces_size(&metainfo.wanted_pieces(self.config.files.as_deref()))
+        });
+        DownloadMetadata {
+            id: self.id,
+            url: self.magnet.to_uri(),
+            file_path: self.file_path(),
+            download_size,
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    /// Verified bytes as of the last run
+    async fn get_bytes_on_disk(&self) -> u64 {
+        self.verified.load(Ordering::SeqCst)
+    }
+
+    /// Data on disk is verified and kept, starting is the same as resuming
+    async fn start(&self, update_ch: Sender<DownloadUpdate>) -> anyhow::Result<u64> {
+        Ok(self.run(update_ch).await?)
+    }
+
+    async fn resume(&self, update_ch: Sender<DownloadUpdate>) -> anyhow::Result<u64> {
+        Ok(self.run(update_ch).await?)
+    }
+
+    fn kind(&self) -> &'static str {
+        Self::KIND
+    }
+
+    fn to_record(&self) -> Option<serde_json::Value> {
+        let record = TorrentRecord {
+            id: self.id,
+            directory: self.directory.clone(),
+            magnet: self.magnet.to_uri(),
+            config: self.config.clone(),
+            info: self
+                .metainfo()
+                .map(|metainfo| hex::encode(&metainfo.info_bytes)),
+        };
+        serde_json::to_value(record).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test_tracker::TestTracker;
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+    use tempfile::TempDir;
+    use test_log::test;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    const PIECE_LENGTH: u64 = 16 * 1024;
+
+    fn payload(len: usize, seed: u32) -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| (i.wrapping_mul(seed) % 251) as u8)
+            .collect()
+    }
+
+    /// Writes a multi-file torrent `album` with three files into a new directory
+    fn create_album(tracker: &TestTracker) -> TestResult<(TempDir, Metainfo)> {
+        let dir = TempDir::new()?;
+        let album = dir.path().join("album");
+        std::fs::create_dir_all(album.join("cd2"))?;
+        std::fs::write(album.join("a.bin"), payload(40_000, 7))?;
+        std::fs::write(album.join("b.bin"), payload(50_000, 13))?;
+        std::fs::write(album.join("cd2").join("c.bin"), payload(30_000, 31))?;
+        let metainfo = Metainfo::create(&album, PIECE_LENGTH, vec![tracker.announce_url()])?;
+        Ok((dir, metainfo))
+    }
+
+    /// Runs a seeder for the torrent in `directory` until the tracker knows it
+    async fn spawn_seeder(
+        tracker: &TestTracker,
+        metainfo: &Metainfo,
+        directory: &Path,
+        seed_ratio: f64,
+    ) -> TestResult<(
+        TorrentDownload,
+        tokio::task::JoinHandle<Result<u64>>,
+        mpsc::Receiver<DownloadUpdate>,
+    )> {
+        let config = TorrentConfig {
+            seed_ratio,
+            ..Default::default()
+        };
+        let seeder =
+            TorrentDownload::from_torrent(&metainfo.to_bytes()?, directory.to_owned(), config)?;
+        let (tx, rx) = mpsc::channel(1000);
+        let running = seeder.clone();
+        let task = tokio::spawn(async move { running.run(tx).await });
+        time::timeout(time::Duration::from_secs(10), async {
+            while tracker.peer_count(&metainfo.info_hash) == 0 {
+                time::sleep(time::Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+        Ok((seeder, task, rx))
+    }
+
+    fn assert_same_file(a: &Path, b: &Path) {
+        assert!(
+            std::fs::read(a).unwrap() == std::fs::read(b).unwrap(),
+            "{:?} differs from {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn torrent_is_downloaded_from_a_seeder_until_its_ratio() -> TestResult<()> {
+        let tracker = TestTracker::spawn();
+        let (seed_dir, metainfo) = create_album(&tracker)?;
+        let (seeder, seeder_task, mut seeder_updates) =
+            spawn_seeder(&tracker, &metainfo, seed_dir.path(), 1.0).await?;
+
+        let leech_dir = TempDir::new()?;
+        let leecher = TorrentDownload::from_torrent(
+            &metainfo.to_bytes()?,
+            leech_dir.path().to_owned(),
+            TorrentConfig::default(),
+        )?;
+        let (tx, _rx) = mpsc::channel(1000);
+        let size = time::timeout(time::Duration::from_secs(30), leecher.run(tx)).await??;
+        assert_eq!(size, 120_000);
+        assert_eq!(leecher.get_bytes_on_disk().await, 120_000);
+        for file in ["a.bin", "b.bin", "cd2/c.bin"] {
+            assert_same_file(
+                &seed_dir.path().join("album").join(file),
+                &leech_dir.path().join("album").join(file),
+            );
+        }
+
+        // The seeder uploaded the whole torrent once, which is its seed ratio
+        let seeded = time::timeout(time::Duration::from_secs(30), seeder_task).await???;
+        assert_eq!(seeded, 120_000);
+        assert!(seeder.uploaded() >= 120_000);
+        let mut states = Vec::new();
+        while let Ok(update) = seeder_updates.try_recv() {
+            states.push(update.state);
+        }
+        assert!(states
+            .iter()
+            .any(|state| matches!(state, State::Seeding { .. })));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn magnet_link_fetches_metadata_and_selected_files() -> TestResult<()> {
+        let tracker = TestTracker::spawn();
+        let (seed_dir, metainfo) = create_album(&tracker)?;
+        let (_seeder, seeder_task, _updates) =
+            spawn_seeder(&tracker, &metainfo, seed_dir.path(), 100.0).await?;
+
+        let magnet = MagnetLink {
+            info_hash: metainfo.info_hash,
+            name: None,
+            trackers: vec![tracker.announce_url()],
+        };
+        let leech_dir = TempDir::new()?;
+        let config = TorrentConfig {
+            files: Some(vec![1]),
+            ..Default::default()
+        };
+        let leecher =
+            TorrentDownload::from_magnet(&magnet.to_uri(), leech_dir.path().to_owned(), config)?;
+        assert_eq!(leecher.get_metadata().download_size, None);
+        let (tx, _rx) = mpsc::channel(1000);
+        time::timeout(time::Duration::from_secs(30), leecher.run(tx)).await??;
+        seeder_task.abort();
+
+        assert_eq!(leecher.name(), "album");
+        // b.bin covers bytes 40_000..90_000, which are held by pieces 2 to 5
+        let expected_size = 4 * PIECE_LENGTH;
+        assert_eq!(leecher.get_metadata().download_size, Some(expected_size));
+        let album = leech_dir.path().join("album");
+        assert_same_file(&seed_dir.path().join("album/b.bin"), &album.join("b.bin"));
+        // Only the start of c.bin shares a piece with b.bin
+        let c_len = std::fs::metadata(album.join("cd2/c.bin"))
+            .map(|m| m.len())
+            .unwrap_or_default();
+        assert!(c_len < 30_000);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn corrupted_data_on_disk_is_downloaded_again() -> TestResult<()> {
+        let tracker = TestTracker::spawn();
+        let (seed_dir, metainfo) = create_album(&tracker)?;
+        let (_seeder, seeder_task, _updates) =
+            spawn_seeder(&tracker, &metainfo, seed_dir.path(), 100.0).await?;
+
+        let leech_dir = TempDir::new()?;
+        let album = leech_dir.path().join("album");
+        std::fs::create_dir_all(album.join("cd2"))?;
+        for file in ["a.bin", "b.bin", "cd2/c.bin"] {
+            std::fs::copy(seed_dir.path().join("album").join(file), album.join(file))?;
+        }
+        let mut corrupted = std::fs::read(album.join("a.bin"))?;
+        corrupted[20_000] ^= 0xff;
+        std::fs::write(album.join("a.bin"), corrupted)?;
+
+        let leecher = TorrentDownload::from_torrent(
+            &metainfo.to_bytes()?,
+            leech_dir.path().to_owned(),
+            TorrentConfig::default(),
+        )?;
+        assert_eq!(leecher.check().await, 120_000 - PIECE_LENGTH);
+        let (tx, _rx) = mpsc::channel(1000);
+        time::timeout(time::Duration::from_secs(30), leecher.run(tx)).await??;
+        seeder_task.abort();
+        assert_same_file(&seed_dir.path().join("album/a.bin"), &album.join("a.bin"));
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::bencode::{self, Value};
+use super::metainfo::InfoHash;
+use super::{Error, Result};
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+/// Pieces are requested in blocks of this size
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+/// Metadata is exchanged in pieces of this size (BEP 9)
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Id under which we accept ut_metadata messages
+pub const UT_METADATA_ID: u8 = 1;
+/// Larger messages are treated as a protocol violation
+const MAX_MESSAGE_LEN: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    /// The peer supports the extension protocol (BEP 10)
+    pub extensions: bool,
+}
+
+impl Handshake {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(68);
+        bytes.push(PROTOCOL.len() as u8);
+        bytes.extend_from_slice(PROTOCOL);
+        let mut reserved = [0u8; 8];
+        if self.extensions {
+            reserved[5] |= 0x10;
+        }
+        bytes.extend_from_slice(&reserved);
+        bytes.extend_from_slice(&self.info_hash);
+        bytes.extend_from_slice(&self.peer_id);
+        bytes
+    }
+}
+
+/// Sends our handshake and reads the one of the peer, which has to be for the same torrent
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    ours: &Handshake,
+) -> Result<Handshake> {
+    stream.write_all(&ours.to_bytes()).await?;
+    let mut buf = [0u8; 68];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+        return Err(Error::Peer("unknown protocol".to_string()));
+    }
+    let theirs = Handshake {
+        extensions: buf[25] & 0x10 != 0,
+        info_hash: buf[28..48].try_into().expect("slice has 20 bytes"),
+        peer_id: buf[48..68].try_into().expect("slice has 20 bytes"),
+    };
+    if theirs.info_hash != ours.info_hash {
+        return Err(Error::Peer("handshake for another torrent".to_string()));
+    }
+    Ok(theirs)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// Extension protocol message (BEP 10), id 0 is the extended handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// Messages we don't support are ignored
+    Unknown(u8),
+}
+
+fn u32_at(payload: &[u8], at: usize) -> Result<u32> {
+    payload
+        .get(at..at + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().expect("slice has 4 bytes")))
+        .ok_or_else(|| Error::Peer("message too short".to_string()))
+}
+
+impl Message {
+    fn decode(id: u8, payload: Vec<u8>) -> Result<Self> {
+        Ok(match id {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(u32_at(&payload, 0)?),
+            5 => Message::Bitfield(payload),
+            6 | 8 => {
+                let (index, begin, length) = (
+                    u32_at(&payload, 0)?,
+                    u32_at(&payload, 4)?,
+                    u32_at(&payload, 8)?,
+                );
+                match id {
+                    6 => Message::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            7 => Message::Piece {
+                index: u32_at(&payload, 0)?,
+                begin: u32_at(&payload, 4)?,
+                block: payload[8..].to_vec(),
+            },
+            20 => match payload.split_first() {
+                Some((id, payload)) => Message::Extended {
+                    id: *id,
+                    payload: payload.to_vec(),
+                },
+                None => return Err(Error::Peer("empty extended message".to_string())),
+            },
+            id => Message::Unknown(id),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let mut push_u32s = |id: u8, values: &[u32]| {
+            body.push(id);
+            for value in values {
+                body.extend_from_slice(&value.to_be_bytes());
+            }
+        };
+        match self {
+            Message::KeepAlive => {}
+            Message::Choke => push_u32s(0, &[]),
+            Message::Unchoke => push_u32s(1, &[]),
+            Message::Interested => push_u32s(2, &[]),
+            Message::NotInterested => push_u32s(3, &[]),
+            Message::Have(index) => push_u32s(4, &[*index]),
+            Message::Bitfield(bitfield) => {
+                push_u32s(5, &[]);
+                body.extend_from_slice(bitfield);
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => push_u32s(6, &[*index, *begin, *length]),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                push_u32s(7, &[*index, *begin]);
+                body.extend_from_slice(block);
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => push_u32s(8, &[*index, *begin, *length]),
+            Message::Extended { id, payload } => {
+                body.push(20);
+                body.push(*id);
+                body.extend_from_slice(payload);
+            }
+            Message::Unknown(id) => push_u32s(*id, &[]),
+        }
+        let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Message> {
+    let len = reader.read_u32().await? as usize;
+    if len == 0 {
+        return Ok(Message::KeepAlive);
+    }
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::Peer(format!("message of {} bytes", len)));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    let payload = body.split_off(1);
+    Message::decode(body[0], payload)
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> Result<()> {
+    writer.write_all(&message.encode()).await?;
+    Ok(())
+}
+
+/// Extended handshake announcing ut_metadata support and the size of the metadata if known
+pub fn extended_handshake(metadata_size: Option<usize>) -> Message {
+    let mut handshake = vec![(
+        "m",
+        Value::dict([("ut_metadata", Value::Int(UT_METADATA_ID as i64))]),
+    )];
+    if let Some(size) = metadata_size {
+        handshake.push(("metadata_size", Value::Int(size as i64)));
+    }
+    Message::Extended {
+        id: 0,
+        payload: bencode::encode(&Value::dict(handshake)),
+    }
+}
+
+/// The id the peer wants ut_metadata messages sent with and the size of its metadata
+pub fn parse_extended_handshake(payload: &[u8]) -> Result<(Option<u8>, Option<usize>)> {
+    let handshake = bencode::decode(payload)?;
+    let id = handshake
+        .get("m")
+        .and_then(|m| m.get("ut_metadata"))
+        .and_then(Value::as_int)
+        .and_then(|id| u8::try_from(id).ok())
+        .filter(|id| *id != 0);
+    let size = handshake
+        .get("metadata_size")
+        .and_then(Value::as_int)
+        .and_then(|size| usize::try_from(size).ok());
+    Ok((id, size))
+}
+
+/// Messages of the ut_metadata extension (BEP 9)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(usize),
+    Data { piece: usize, data: Vec<u8> },
+    Reject(usize),
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request(piece) => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject(piece) => (2, piece),
+        };
+        let mut dict = vec![
+            ("msg_type", Value::Int(msg_type)),
+            ("piece", Value::Int(*piece as i64)),
+        ];
+        if let MetadataMessage::Data { data, .. } = self {
+            dict.push(("total_size", Value::Int(data.len() as i64)));
+        }
+        let mut payload = bencode::encode(&Value::dict(dict));
+        if let MetadataMessage::Data { data, .. } = self {
+            payload.extend_from_slice(data);
+        }
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let (dict, len) = bencode::decode_prefix(payload)?;
+        let piece = dict
+            .get("piece")
+            .and_then(Value::as_int)
+            .and_then(|piece| usize::try_from(piece).ok())
+            .ok_or_else(|| Error::Peer("ut_metadata message without piece".to_string()))?;
+        match dict.get("msg_type").and_then(Value::as_int) {
+            Some(0) => Ok(MetadataMessage::Request(piece)),
+            Some(1) => Ok(MetadataMessage::Data {
+                piece,
+                data: payload[len..].to_vec(),
+            }),
+            Some(2) => Ok(MetadataMessage::Reject(piece)),
+            _ => Err(Error::Peer("unknown ut_metadata message".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn messages_round_trip() {
+        let messages = [
+            Message::KeepAlive,
+            Message::Unchoke,
+            Message::Have(7),
+            Message::Bitfield(vec![0b1010_0000]),
+            Message::Request {
+                index: 1,
+                begin: BLOCK_SIZE,
+                length: BLOCK_SIZE,
+            },
+            Message::Piece {
+                index: 1,
+                begin: 0,
+                block: vec![1, 2, 3],
+            },
+            Message::Extended {
+                id: UT_METADATA_ID,
+                payload: MetadataMessage::Data {
+                    piece: 0,
+                    data: b"d4:name4:spame".to_vec(),
+                }
+                .encode(),
+            },
+        ];
+        let mut wire = Vec::new();
+        for message in messages.iter() {
+            write_message(&mut wire, message).await.unwrap();
+        }
+        let mut reader = wire.as_slice();
+        for message in messages.iter() {
+            assert_eq!(&read_message(&mut reader).await.unwrap(), message);
+        }
+        let Message::Extended { payload, .. } = &messages[6] else {
+            unreachable!()
+        };
+        assert_eq!(
+            MetadataMessage::decode(payload).unwrap(),
+            MetadataMessage::Data {
+                piece: 0,
+                data: b"d4:name4:spame".to_vec()
+            }
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use sha1::{Digest, Sha1};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::metainfo::Metainfo;
+
+#[derive(Debug, Clone)]
+struct StorageFile {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// Maps the concatenated data of a torrent onto its files.
+/// All IO is blocking, it's meant to run in `spawn_blocking`.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    files: Vec<StorageFile>,
+}
+
+impl Storage {
+    /// Files of single file torrents are placed in `directory`, multi-file torrents get their
+    /// own directory inside of it
+    pub fn new(directory: &Path, metainfo: &Metainfo) -> Self {
+        let root = if metainfo.multi_file {
+            directory.join(&metainfo.name)
+        } else {
+            directory.to_owned()
+        };
+        let files = metainfo
+            .files
+            .iter()
+            .map(|file| StorageFile {
+                path: root.join(&file.path),
+                offset: file.offset,
+                length: file.length,
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// Parts of the range `offset..offset + len` as (file, offset in the file, length)
+    fn spans(&self, offset: u64, len: u64) -> impl Iterator<Item = (&StorageFile, u64, u64)> {
+        let end = offset + len;
+        self.files
+            .iter()
+            .filter(move |file| file.offset < end && offset < file.offset + file.length)
+            .map(move |file| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                (file, start - file.offset, stop - start)
+            })
+    }
+
+    /// Fails if a file is missing or too short
+    pub fn read(&self, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len as usize);
+        for (file, file_offset, span) in self.spans(offset, len) {
+            let mut handle = std::fs::File::open(&file.path)?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            let start = data.len();
+            data.resize(start + span as usize, 0);
+            handle.read_exact(&mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Creates missing files and directories on the way
+    pub fn write(&self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let mut written = 0;
+        for (file, file_offset, span) in self.spans(offset, data.len() as u64) {
+            if let Some(parent) = file.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&file.path)?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.write_all(&data[written..written + span as usize])?;
+            written += span as usize;
+        }
+        Ok(())
+    }
+
+    /// Checks the data of the piece on disk against its hash
+    pub fn verify_piece(&self, metainfo: &Metainfo, index: u32) -> bool {
+        let (offset, len) = metainfo.piece_range(index);
+        match self.read(offset, len) {
+            Ok(data) => Sha1::digest(&data).as_slice() == metainfo.pieces[index as usize],
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn pieces_spanning_files_are_verified() -> TestResult<()> {
+        let source = TempDir::new()?;
+        let root = source.path().join("torrent");
+        std::fs::create_dir(&root)?;
+        let a: Vec<u8> = (0..25_000u32).map(|i| (i % 251) as u8).collect();
+        let b: Vec<u8> = (0..20_000u32).map(|i| (i % 13) as u8).collect();
+        std::fs::write(root.join("a.bin"), &a)?;
+        std::fs::write(root.join("b.bin"), &b)?;
+        let metainfo = Metainfo::create(&root, 16 * 1024, Vec::new())?;
+
+        let target = TempDir::new()?;
+        let storage = Storage::new(target.path(), &metainfo);
+        assert!(!storage.verify_piece(&metainfo, 1));
+        // Piece 1 spans the end of a.bin and the start of b.bin
+        let data = [&a[..], &b[..]].concat();
+        let (offset, len) = metainfo.piece_range(1);
+        storage.write(offset, &data[offset as usize..(offset + len) as usize])?;
+        assert!(storage.verify_piece(&metainfo, 1));
+        assert!(!storage.verify_piece(&metainfo, 0));
+        assert_eq!(
+            storage.read(offset, 100)?,
+            data[offset as usize..offset as usize + 100]
+        );
+
+        storage.write(offset + 10, &[0xff])?;
+        assert!(!storage.verify_piece(&metainfo, 1));
+        Ok(())
+    }
+}
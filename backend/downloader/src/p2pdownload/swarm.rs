@@ -0,0 +1,265 @@
+use rand::Rng;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+
+use super::metainfo::{InfoHash, Metainfo};
+use super::storage::Storage;
+
+/// Broadcast to every peer connection of a swarm
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    /// The piece was downloaded and verified
+    Have(u32),
+    /// The metadata of a magnet link was received
+    Metadata,
+}
+
+#[derive(Debug, Default)]
+struct Pieces {
+    have: Vec<bool>,
+    wanted: Vec<bool>,
+    in_progress: HashSet<u32>,
+}
+
+/// State of a running torrent shared by all of its peer connections
+#[derive(Debug)]
+pub struct Swarm {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    pub directory: PathBuf,
+    /// Indices of the files to download, all files if None
+    selection: Option<Vec<usize>>,
+    /// Shared with the TorrentDownload, so metadata fetched for a magnet link outlives the run
+    metainfo: Arc<RwLock<Option<Arc<Metainfo>>>>,
+    storage: OnceLock<Arc<Storage>>,
+    pieces: Mutex<Pieces>,
+    /// Verified bytes of the selected files
+    pub verified: Arc<AtomicU64>,
+    /// Payload sent to peers over all runs
+    pub uploaded: Arc<AtomicU64>,
+    /// Payload received in this run
+    pub downloaded: AtomicU64,
+    events: broadcast::Sender<Event>,
+    /// Addresses we are connected to or connecting to
+    peers: Mutex<HashSet<SocketAddr>>,
+    tasks: Mutex<Vec<AbortHandle>>,
+}
+
+impl Swarm {
+    pub fn new(
+        info_hash: InfoHash,
+        directory: PathBuf,
+        selection: Option<Vec<usize>>,
+        metainfo: Arc<RwLock<Option<Arc<Metainfo>>>>,
+        verified: Arc<AtomicU64>,
+        uploaded: Arc<AtomicU64>,
+    ) -> Arc<Self> {
+        let mut peer_id = *b"-LU0001-000000000000";
+        rand::thread_rng().fill(&mut peer_id[8..]);
+        let (events, _) = broadcast::channel(256);
+        let swarm = Arc::new(Self {
+            info_hash,
+            peer_id,
+            directory,
+            selection,
+            metainfo,
+            storage: OnceLock::new(),
+            pieces: Mutex::new(Pieces::default()),
+            verified,
+            uploaded,
+            downloaded: AtomicU64::new(0),
+            events,
+            peers: Mutex::new(HashSet::new()),
+            tasks: Mutex::new(Vec::new()),
+        });
+        if let Some(metainfo) = swarm.metainfo() {
+            swarm.init_pieces(&metainfo);
+        }
+        swarm
+    }
+
+    pub fn metainfo(&self) -> Option<Arc<Metainfo>> {
+        self.metainfo.read().unwrap().clone()
+    }
+
+    pub fn storage(&self) -> Option<Arc<Storage>> {
+        self.storage.get().cloned()
+    }
+
+    fn init_pieces(&self, metainfo: &Metainfo) {
+        let wanted = metainfo.wanted_pieces(self.selection.as_deref());
+        *self.pieces.lock().unwrap() = Pieces {
+            have: vec![false; metainfo.pieces.len()],
+            wanted,
+            in_progress: HashSet::new(),
+        };
+        let _ = self
+            .storage
+            .set(Arc::new(Storage::new(&self.directory, metainfo)));
+    }
+
+    /// Stores the metadata fetched for a magnet link, the first one wins
+    pub fn set_metainfo(&self, metainfo: Metainfo) {
+        let metainfo = {
+            let mut current = self.metainfo.write().unwrap();
+            if current.is_some() {
+                return;
+            }
+            let metainfo = Arc::new(metainfo);
+            *current = Some(metainfo.clone());
+            metainfo
+        };
+        log::info!("Received the metadata of torrent {}", metainfo.name);
+        self.init_pieces(&metainfo);
+        let _ = self.events.send(Event::Metadata);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Hashes the data on disk and marks the pieces that are already complete
+    pub async fn check_existing(&self) {
+        let (Some(metainfo), Some(storage)) = (self.metainfo(), self.storage()) else {
+            return;
+        };
+        let check = metainfo.clone();
+        let have = tokio::task::spawn_blocking(move || {
+            (0..check.piece_count())
+                .map(|index| storage.verify_piece(&check, index))
+                .collect::<Vec<bool>>()
+        })
+        .await
+        .unwrap_or_default();
+        let mut pieces = self.pieces.lock().unwrap();
+        if have.len() == pieces.have.len() {
+            pieces.have = have;
+        }
+        let have: Vec<bool> = pieces
+            .wanted
+            .iter()
+            .zip(pieces.have.iter())
+            .map(|(wanted, have)| *wanted && *have)
+            .collect();
+        self.verified
+            .store(metainfo.pieces_size(&have), Ordering::SeqCst);
+    }
+
+    /// Size of all wanted pieces, None while the metadata is missing
+    pub fn wanted_size(&self) -> Option<u64> {
+        let metainfo = self.metainfo()?;
+        Some(metainfo.pieces_size(&self.pieces.lock().unwrap().wanted))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        if self.metainfo().is_none() {
+            return false;
+        }
+        let pieces = self.pieces.lock().unwrap();
+        pieces
+            .wanted
+            .iter()
+            .zip(pieces.have.iter())
+            .all(|(wanted, have)| !wanted || *have)
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        let pieces = self.pieces.lock().unwrap();
+        pieces.have.get(index as usize).copied().unwrap_or(false)
+    }
+
+    /// Bitfield message payload of the pieces we have, None if we have none
+    pub fn bitfield(&self) -> Option<Vec<u8>> {
+        let pieces = self.pieces.lock().unwrap();
+        if !pieces.have.contains(&true) {
+            return None;
+        }
+        let mut bitfield = vec![0u8; pieces.have.len().div_ceil(8)];
+        for (index, have) in pieces.have.iter().enumerate() {
+            if *have {
+                bitfield[index / 8] |= 0x80 >> (index % 8);
+            }
+        }
+        Some(bitfield)
+    }
+
+    /// True if the peer has a wanted piece we are missing
+    pub fn wants_from(&self, peer_has: impl Fn(u32) -> bool) -> bool {
+        let pieces = self.pieces.lock().unwrap();
+        (0..pieces.have.len() as u32).any(|index| {
+            pieces.wanted[index as usize] && !pieces.have[index as usize] && peer_has(index)
+        })
+    }
+
+    /// Reserves a wanted piece the peer has and nobody is downloading, starting at a random
+    /// piece so peers spread over the torrent
+    pub fn pick_piece(&self, peer_has: impl Fn(u32) -> bool) -> Option<u32> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let count = pieces.have.len() as u32;
+        if count == 0 {
+            return None;
+        }
+        let start = rand::thread_rng().gen_range(0..count);
+        let index = (0..count).map(|i| (start + i) % count).find(|index| {
+            pieces.wanted[*index as usize]
+                && !pieces.have[*index as usize]
+                && !pieces.in_progress.contains(index)
+                && peer_has(*index)
+        })?;
+        pieces.in_progress.insert(index);
+        Some(index)
+    }
+
+    /// Gives up the reservation of a piece, e.g. when the peer disconnects
+    pub fn release_piece(&self, index: u32) {
+        self.pieces.lock().unwrap().in_progress.remove(&index);
+    }
+
+    /// Marks a verified and written piece as complete and tells all peers
+    pub fn complete_piece(&self, index: u32, size: u64) {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.in_progress.remove(&index);
+        if pieces.have[index as usize] {
+            return;
+        }
+        pieces.have[index as usize] = true;
+        if pieces.wanted[index as usize] {
+            self.verified.fetch_add(size, Ordering::SeqCst);
+        }
+        drop(pieces);
+        let _ = self.events.send(Event::Have(index));
+    }
+
+    /// Reserves the address for a connection, false if we are already connected to it
+    pub fn add_peer(&self, addr: SocketAddr) -> bool {
+        self.peers.lock().unwrap().insert(addr)
+    }
+
+    pub fn remove_peer(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    /// Spawns a task that is aborted together with the swarm
+    pub fn spawn(&self, task: impl std::future::Future<Output = ()> + Send + 'static) {
+        let handle = tokio::spawn(task);
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(handle.abort_handle());
+    }
+
+    /// Stops all tasks of the swarm, called when the run of the download ends
+    pub fn shutdown(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
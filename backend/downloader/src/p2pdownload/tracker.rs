@@ -0,0 +1,136 @@
+use reqwest::Client;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use super::bencode::{self, Value};
+use super::metainfo::InfoHash;
+use super::{Error, Result};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Announce {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<Event>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceResponse {
+    /// How long to wait before the next announce
+    pub interval: Duration,
+    pub peers: Vec<SocketAddr>,
+}
+
+/// Percent-encodes raw bytes for a query string, only unreserved characters are kept
+pub fn url_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+    for byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(*byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Announces to an HTTP tracker (BEP 3, compact peer lists of BEP 23)
+pub async fn announce(
+    client: &Client,
+    tracker: &str,
+    announce: &Announce,
+) -> Result<AnnounceResponse> {
+    if !tracker.starts_with("http://") && !tracker.starts_with("https://") {
+        return Err(Error::Tracker(format!("unsupported tracker {}", tracker)));
+    }
+    let separator = if tracker.contains('?') { '&' } else { '?' };
+    let mut url = format!(
+        "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+        tracker,
+        separator,
+        url_encode(&announce.info_hash),
+        url_encode(&announce.peer_id),
+        announce.port,
+        announce.uploaded,
+        announce.downloaded,
+        announce.left
+    );
+    if let Some(event) = announce.event {
+        url.push_str("&event=");
+        url.push_str(event.as_str());
+    }
+    let resp = client.get(url).send().await?;
+    if !resp.status().is_success() {
+        return Err(Error::Tracker(format!(
+            "{} answered with {}",
+            tracker,
+            resp.status()
+        )));
+    }
+    let body = bencode::decode(&resp.bytes().await?)?;
+    if let Some(reason) = body.get("failure reason") {
+        return Err(Error::Tracker(format!(
+            "{} failed: {}",
+            tracker,
+            reason.as_str().unwrap_or_default()
+        )));
+    }
+    let interval = body
+        .get("interval")
+        .and_then(Value::as_int)
+        .filter(|interval| *interval > 0)
+        .map(|interval| Duration::from_secs(interval as u64))
+        .unwrap_or(DEFAULT_INTERVAL);
+    let peers = match body.get("peers") {
+        Some(Value::Bytes(compact)) => compact
+            .chunks_exact(6)
+            .map(|peer| {
+                let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+                SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([peer[4], peer[5]]))
+            })
+            .collect(),
+        Some(Value::List(peers)) => peers
+            .iter()
+            .filter_map(|peer| {
+                let ip: IpAddr = peer.get("ip")?.as_str()?.parse().ok()?;
+                let port = u16::try_from(peer.get("port")?.as_int()?).ok()?;
+                Some(SocketAddr::new(ip, port))
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(AnnounceResponse { interval, peers })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn raw_bytes_are_url_encoded() {
+        assert_eq!(url_encode(&[0x12, b'a', 0xff, b'~', b' ']), "%12a%FF~%20");
+    }
+}
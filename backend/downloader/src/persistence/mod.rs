@@ -0,0 +1,695 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::httpdownload::download;
+use crate::httpdownload::package::Package;
+use crate::httpdownload::{Download, DownloadUpdateSubscriber};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("SQLite error: '{0}'")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Serialization error: '{0}'")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Stored value is invalid: '{0}'")]
+    InvalidData(String),
+    #[error("Database task failed: '{0}'")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Schema migrations, applied in order. `PRAGMA user_version` stores how many have already been
+/// applied to a database, so new migrations must only ever be appended to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE httpdownload (
+        id TEXT PRIMARY KEY NOT NULL,
+        url TEXT NOT NULL,
+        directory TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        content_length INTEGER NOT NULL,
+        supports_byte_ranges INTEGER NOT NULL,
+        config TEXT NOT NULL,
+        state TEXT NOT NULL
+    );",
+    // content_length becomes nullable for downloads of unknown size
+    "CREATE TABLE httpdownload_new (
+        id TEXT PRIMARY KEY NOT NULL,
+        url TEXT NOT NULL,
+        directory TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        content_length INTEGER,
+        supports_byte_ranges INTEGER NOT NULL,
+        config TEXT NOT NULL,
+        state TEXT NOT NULL
+    );
+    INSERT INTO httpdownload_new SELECT * FROM httpdownload;
+    DROP TABLE httpdownload;
+    ALTER TABLE httpdownload_new RENAME TO httpdownload;",
+    "ALTER TABLE httpdownload ADD COLUMN etag TEXT;
+    ALTER TABLE httpdownload ADD COLUMN last_modified TEXT;",
+    "CREATE TABLE package (
+        id TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        directory TEXT NOT NULL,
+        download_ids TEXT NOT NULL
+    );",
+    // Proxies downloads are pinned to in the ClientPool of the DownloadManager
+    "CREATE TABLE proxy_pin (
+        id TEXT PRIMARY KEY NOT NULL,
+        proxy TEXT NOT NULL
+    );",
+    // Every kind of download other than HttpDownload, data is the record of the download
+    "CREATE TABLE download (
+        id TEXT PRIMARY KEY NOT NULL,
+        kind TEXT NOT NULL,
+        data TEXT NOT NULL,
+        state TEXT NOT NULL
+    );",
+    // Priorities in the queue of the DownloadManager, downloads without a row have priority 0
+    "CREATE TABLE priority (
+        id TEXT PRIMARY KEY NOT NULL,
+        priority INTEGER NOT NULL
+    );",
+    // Downloads stored before part files were introduced keep their data at the target path
+    "ALTER TABLE httpdownload ADD COLUMN in_place INTEGER NOT NULL DEFAULT 0;
+    UPDATE httpdownload SET in_place = 1;",
+    // HttpDownloads are stored as records like every other kind, the data of complete downloads
+    // is where it belongs so only unfinished ones keep the in_place flag
+    "INSERT INTO download (id, kind, data, state)
+        SELECT id, 'http', json_object(
+            'id', id,
+            'url', url,
+            'directory', directory,
+            'filename', filename,
+            'content_length', content_length,
+            'supports_byte_ranges', json(CASE WHEN supports_byte_ranges THEN 'true' ELSE 'false' END),
+            'etag', etag,
+            'last_modified', last_modified,
+            'config', json(config),
+            'in_place', json(CASE WHEN in_place AND state != json_quote('Complete') THEN 'true' ELSE 'false' END)
+        ), state FROM httpdownload;
+    DROP TABLE httpdownload;",
+];
+
+/// Restores a download of one kind from the record returned by `Download::to_record`
+pub type RestoreFn = fn(serde_json::Value) -> anyhow::Result<Box<dyn Download>>;
+
+/// Persists downloads together with their configuration and last known state.
+/// Every download is stored as its record and restored by the RestoreFn registered for its kind.
+/// All queries run on tokio's blocking thread pool, the struct is cheap to clone and safe to
+/// share between threads.
+/// Subscribing it to the DownloadUpdateBuffer keeps the stored state of every download up to date.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+    restorers: Arc<RwLock<HashMap<&'static str, RestoreFn>>>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `path` and applies all pending migrations
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        log::info!("Opening SQLite database at {:?}", path);
+        let conn = tokio::task::spawn_blocking(move || Connection::open(path)).await??;
+        Self::from_connection(conn).await
+    }
+
+    /// Creates a database that only lives as long as the returned store
+    pub async fn in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?).await
+    }
+
+    async fn from_connection(conn: Connection) -> Result<Self> {
+        let store = Self {
+            conn: Arc::new(Mutex::new(conn)),
+            restorers: Default::default(),
+        };
+        store.with_conn(migrate).await?;
+        Ok(store)
+    }
+
+    /// Registers how downloads of `kind` are restored, replacing the previous function
+    pub fn register_kind(&self, kind: &'static str, restore: RestoreFn) {
+        self.restorers.write().unwrap().insert(kind, restore);
+    }
+
+    /// Runs `f` with exclusive access to the connection on the blocking thread pool
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut guard = conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            f(&mut guard)
+        })
+        .await?
+    }
+
+    /// Inserts the download or overwrites it if it was already stored.
+    /// Downloads without a record are not stored.
+    pub async fn save_download(
+        &self,
+        download: &dyn Download,
+        state: &download::State,
+    ) -> Result<()> {
+        let Some(record) = download.to_record() else {
+            log::warn!(
+                "Download {} of kind {} can't be persisted",
+                download.id(),
+                download.kind()
+            );
+            return Ok(());
+        };
+        let id = download.id().to_string();
+        let kind = download.kind();
+        let data = serde_json::to_string(&record)?;
+        let state = serde_json::to_string(state)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO download (id, kind, data, state) VALUES (?1, ?2, ?3, ?4)",
+                params![id, kind, data, state],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Overwrites the stored state of every download in `updates`, unknown ids are ignored
+    pub async fn update_states(&self, updates: &[(Uuid, download::State)]) -> Result<()> {
+        let updates = updates
+            .iter()
+            .map(|(id, state)| Ok((id.to_string(), serde_json::to_string(state)?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached("UPDATE download SET state = ?1 WHERE id = ?2")?;
+                for (id, state) in updates.iter() {
+                    stmt.execute(params![state, id])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Overwrites the stored settings of the download (e.g. after its bandwidth limit changed),
+    /// its state is kept
+    pub async fn update_download(&self, download: &dyn Download) -> Result<()> {
+        let Some(record) = download.to_record() else {
+            return Ok(());
+        };
+        let id = download.id().to_string();
+        let data = serde_json::to_string(&record)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE download SET data = ?1 WHERE id = ?2",
+                params![data, id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn get_state(&self, id: &Uuid) -> Result<Option<download::State>> {
+        let id = id.to_string();
+        let state: Option<String> = self
+            .with_conn(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT state FROM download WHERE id = ?1",
+                        params![id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        Ok(state.map(|s| serde_json::from_str(&s)).transpose()?)
+    }
+
+    pub async fn delete_download(&self, id: &Uuid) -> Result<()> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM download WHERE id = ?1", params![id])?;
+            conn.execute("DELETE FROM priority WHERE id = ?1", params![id])?;
+            conn.execute("DELETE FROM proxy_pin WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Loads all stored downloads with their last known state.
+    /// Downloads of a kind without a registered RestoreFn are skipped.
+    pub async fn load_downloads(&self) -> Result<Vec<(Box<dyn Download>, download::State)>> {
+        let restorers = self.restorers.read().unwrap().clone();
+        self.with_conn(move |conn| {
+            let mut downloads = Vec::new();
+            let mut stmt = conn.prepare("SELECT id, kind, data, state FROM download")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })?;
+            for row in rows {
+                let (id, kind, data, state) = row?;
+                let Some(restore) = restorers.get(kind.as_str()) else {
+                    log::warn!("Skipping download {} of unknown kind {}", id, kind);
+                    continue;
+                };
+                let download = restore(serde_json::from_str(&data)?)
+                    .map_err(|e| Error::InvalidData(format!("download {}: {}", id, e)))?;
+                downloads.push((download, serde_json::from_str(&state)?));
+            }
+            Ok(downloads)
+        })
+        .await
+    }
+
+    pub async fn update_priority(&self, id: &Uuid, priority: i32) -> Result<()> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO priority (id, priority) VALUES (?1, ?2)",
+                params![id, priority],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn load_priorities(&self) -> Result<HashMap<Uuid, i32>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT id, priority FROM priority")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)?))
+            })?;
+            let mut priorities = HashMap::new();
+            for row in rows {
+                let (id, priority) = row?;
+                let id = Uuid::parse_str(&id).map_err(|e| Error::InvalidData(e.to_string()))?;
+                priorities.insert(id, priority);
+            }
+            Ok(priorities)
+        })
+        .await
+    }
+
+    /// Stores the proxy the download is pinned to, None removes the pin
+    pub async fn update_pin(&self, id: &Uuid, proxy: Option<String>) -> Result<()> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            match proxy {
+                Some(proxy) => conn.execute(
+                    "INSERT OR REPLACE INTO proxy_pin (id, proxy) VALUES (?1, ?2)",
+                    params![id, proxy],
+                )?,
+                None => conn.execute("DELETE FROM proxy_pin WHERE id = ?1", params![id])?,
+            };
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn load_pins(&self) -> Result<HashMap<Uuid, String>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT id, proxy FROM proxy_pin")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?;
+            let mut pins = HashMap::new();
+            for row in rows {
+                let (id, proxy) = row?;
+                let id = Uuid::parse_str(&id).map_err(|e| Error::InvalidData(e.to_string()))?;
+                pins.insert(id, proxy);
+            }
+            Ok(pins)
+        })
+        .await
+    }
+
+    /// Inserts the package or overwrites it if it was already stored
+    pub async fn save_package(&self, package: &Package) -> Result<()> {
+        let id = package.id.to_string();
+        let name = package.name.clone();
+        let directory = package.directory.to_string_lossy().to_string();
+        let download_ids = serde_json::to_string(&package.download_ids)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO package (id, name, directory, download_ids)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![id, name, directory, download_ids],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn delete_package(&self, id: &Uuid) -> Result<()> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM package WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn load_packages(&self) -> Result<Vec<Package>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT id, name, directory, download_ids FROM package")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })?;
+            let mut packages = Vec::new();
+            for row in rows {
+                let (id, name, directory, download_ids) = row?;
+                packages.push(Package {
+                    id: Uuid::parse_str(&id).map_err(|e| Error::InvalidData(e.to_string()))?,
+                    name,
+                    directory: PathBuf::from(directory),
+                    download_ids: serde_json::from_str(&download_ids)?,
+                });
+            }
+            Ok(packages)
+        })
+        .await
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= MIGRATIONS.len() {
+        return Ok(());
+    }
+    log::info!(
+        "Migrating database from version {} to {}",
+        version,
+        MIGRATIONS.len()
+    );
+    let tx = conn.transaction()?;
+    for migration in MIGRATIONS[version..].iter() {
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()?;
+    Ok(())
+}
+
+#[async_trait]
+impl DownloadUpdateSubscriber for SqliteStore {
+    async fn update(&self, updates: &[(Uuid, download::State)]) {
+        if let Err(e) = self.update_states(updates).await {
+            log::error!("Couldn't persist {} download updates: {}", updates.len(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bandwidth::RateLimiter;
+    use crate::httpdownload::download::{config::HttpDownloadConfig, HttpDownload};
+    use crate::p2pdownload::metainfo::Metainfo;
+    use crate::p2pdownload::{TorrentConfig, TorrentDownload};
+    use crate::util::TestResult;
+    use pretty_assertions::assert_eq;
+    use reqwest::header::{HeaderValue, AUTHORIZATION};
+    use reqwest::{Client, Url};
+    use serde_json::json;
+    use tempfile::TempDir;
+    use test_log::test;
+
+    async fn open(path: Option<&Path>) -> Result<SqliteStore> {
+        let store = match path {
+            Some(path) => SqliteStore::open(path).await?,
+            None => SqliteStore::in_memory().await?,
+        };
+        store.register_kind(HttpDownload::KIND, HttpDownload::restore);
+        store.register_kind(TorrentDownload::KIND, TorrentDownload::restore);
+        Ok(store)
+    }
+
+    fn test_download() -> HttpDownload {
+        let mut config = HttpDownloadConfig::default();
+        config
+            .headers
+            .insert(AUTHORIZATION, HeaderValue::from_static("Bearer token"));
+        HttpDownload {
+            url: Url::parse("https://somewebsite.biz/api/v1/big-ass-file.fantasy").unwrap(),
+            id: Uuid::new_v4(),
+            directory: PathBuf::from("/tmp/downloads"),
+            filename: "big-ass-file.fantasy".to_string(),
+            config,
+            content_length: Some(1024),
+            supports_byte_ranges: true,
+            etag: Some("\"33a64df551425fcc55e4d42a148795d9f25f89d4\"".to_string()),
+            last_modified: None,
+            client: Client::new(),
+            limiter: RateLimiter::default(),
+            global_limiter: RateLimiter::default(),
+            received: Default::default(),
+            in_place: Default::default(),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn save_and_load_download() -> TestResult<()> {
+        let store = open(None).await?;
+        let download = test_download();
+        store
+            .save_download(&download, &download::State::Paused(0))
+            .await?;
+        let loaded = store.load_downloads().await?;
+        assert_eq!(loaded.len(), 1);
+        let (loaded_download, state) = &loaded[0];
+        assert_eq!(loaded_download.kind(), HttpDownload::KIND);
+        assert_eq!(loaded_download.get_metadata().id, download.id);
+        assert_eq!(
+            loaded_download.get_metadata().file_path,
+            download.file_path()
+        );
+        // The record covers the headers, validators and the rest of the config
+        assert_eq!(loaded_download.to_record(), download.to_record());
+        assert!(matches!(state, download::State::Paused(0)));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn downloads_stored_before_part_files_are_flagged() -> TestResult<()> {
+        let tmp_dir = TempDir::new()?;
+        let db_path = tmp_dir.path().join("ludownloader.db");
+        let paused = test_download();
+        let complete = test_download();
+        {
+            // Database of the version before the flag was added, HttpDownloads were stored in a
+            // table of their own until the migration after it
+            let conn = Connection::open(&db_path)?;
+            let version = MIGRATIONS.len() - 2;
+            conn.execute_batch(&MIGRATIONS[..version].join("\n"))?;
+            conn.pragma_update(None, "user_version", version)?;
+            for (download, state) in [(&paused, "{\"Paused\":10}"), (&complete, "\"Complete\"")] {
+                conn.execute(
+                    "INSERT INTO httpdownload (id, url, directory, filename, content_length,
+                        supports_byte_ranges, config, state)
+                     VALUES (?1, ?2, ?3, ?4, 1024, 1, ?5, ?6)",
+                    params![
+                        download.id.to_string(),
+                        download.url.to_string(),
+                        download.directory.to_string_lossy(),
+                        download.filename,
+                        serde_json::to_string(&download.config)?,
+                        state
+                    ],
+                )?;
+            }
+        }
+        let store = open(Some(&db_path)).await?;
+        let in_place = |id: Uuid, loaded: &[(Box<dyn Download>, download::State)]| {
+            let (download, _) = loaded.iter().find(|(d, _)| d.id() == id).unwrap();
+            let record = download.to_record().unwrap();
+            record["in_place"] == json!(true)
+        };
+        let loaded = store.load_downloads().await?;
+        assert!(in_place(paused.id, &loaded));
+        assert!(!in_place(complete.id, &loaded));
+        // Downloads stored since keep their data in part files
+        let fresh = test_download();
+        store
+            .save_download(&fresh, &download::State::Paused(0))
+            .await?;
+        assert!(!in_place(fresh.id, &store.load_downloads().await?));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn unknown_content_length_is_stored_as_null() -> TestResult<()> {
+        let store = open(None).await?;
+        let mut download = test_download();
+        download.content_length = None;
+        store
+            .save_download(&download, &download::State::Paused(0))
+            .await?;
+        let loaded = store.load_downloads().await?;
+        assert_eq!(loaded[0].0.get_metadata().download_size, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn torrents_are_stored_as_records() -> TestResult<()> {
+        let store = open(None).await?;
+        let tmp_dir = TempDir::new()?;
+        let source = tmp_dir.path().join("file.bin");
+        std::fs::write(&source, vec![7u8; 40_000])?;
+        let torrent = Metainfo::create(&source, 16 * 1024, vec!["http://tracker".to_string()])?;
+        let config = TorrentConfig {
+            seed_ratio: 2.0,
+            ..Default::default()
+        };
+        let download =
+            TorrentDownload::from_torrent(&torrent.to_bytes()?, tmp_dir.path().to_owned(), config)?;
+        let magnet = TorrentDownload::from_magnet(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=debian.iso",
+            tmp_dir.path().to_owned(),
+            TorrentConfig::default(),
+        )?;
+        store
+            .save_download(&download, &download::State::Paused(0))
+            .await?;
+        store
+            .save_download(&magnet, &download::State::Complete)
+            .await?;
+        store
+            .update(&[(download.id, download::State::Seeding { bytes_uploaded: 10 })])
+            .await;
+
+        let mut loaded = store.load_downloads().await?;
+        loaded.sort_by_key(|(download, _)| download.get_metadata().download_size);
+        assert_eq!(loaded.len(), 2);
+        let (restored_magnet, state) = &loaded[0];
+        assert_eq!(restored_magnet.kind(), "torrent");
+        assert_eq!(restored_magnet.id(), magnet.id);
+        assert_eq!(
+            restored_magnet.get_metadata().url,
+            magnet.get_metadata().url
+        );
+        assert!(matches!(state, download::State::Complete));
+        let (restored, state) = &loaded[1];
+        assert_eq!(restored.kind(), TorrentDownload::KIND);
+        assert_eq!(restored.id(), download.id);
+        assert_eq!(restored.get_metadata().download_size, Some(40_000));
+        assert_eq!(restored.get_metadata().file_path, download.file_path());
+        assert!(matches!(
+            state,
+            download::State::Seeding { bytes_uploaded: 10 }
+        ));
+
+        store.delete_download(&download.id).await?;
+        assert!(store.get_state(&download.id).await?.is_none());
+        assert_eq!(store.load_downloads().await?.len(), 1);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn unknown_kinds_are_skipped() -> TestResult<()> {
+        fn fail(_: serde_json::Value) -> anyhow::Result<Box<dyn Download>> {
+            Err(anyhow::anyhow!("can't restore"))
+        }
+        let store = SqliteStore::in_memory().await?;
+        let magnet = TorrentDownload::from_magnet(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+            PathBuf::from("/tmp"),
+            TorrentConfig::default(),
+        )?;
+        store
+            .save_download(&magnet, &download::State::Paused(0))
+            .await?;
+        store.restorers.write().unwrap().clear();
+        assert!(store.load_downloads().await?.is_empty());
+        store.register_kind("torrent", fail);
+        assert!(store.load_downloads().await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn states_stored_by_older_versions_are_loaded() -> TestResult<()> {
+        let state: download::State =
+            serde_json::from_str(r#"{"Running":{"bytes_downloaded":1,"bytes_per_second":2}}"#)?;
+        assert!(matches!(
+            state,
+            download::State::Running {
+                bytes_downloaded: 1,
+                bytes_per_second: 2,
+                average_bytes_per_second: 0,
+                percentage: None,
+                eta_seconds: None,
+            }
+        ));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn save_load_and_delete_package() -> TestResult<()> {
+        let store = SqliteStore::in_memory().await?;
+        let mut package = Package::new("season-1".to_string(), PathBuf::from("/tmp/season-1"));
+        package.download_ids = vec![Uuid::new_v4(), Uuid::new_v4()];
+        store.save_package(&package).await?;
+        assert_eq!(store.load_packages().await?, vec![package.clone()]);
+        store.delete_package(&package.id).await?;
+        assert!(store.load_packages().await?.is_empty());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn updates_are_persisted_and_survive_reopening() -> TestResult<()> {
+        let tmp_dir = TempDir::new()?;
+        let db_path = tmp_dir.path().join("ludownloader.db");
+        let download = test_download();
+        {
+            let store = open(Some(&db_path)).await?;
+            store
+                .save_download(&download, &download::State::Paused(0))
+                .await?;
+            store
+                .update(&[(
+                    download.id,
+                    download::State::Running {
+                        bytes_downloaded: 512,
+                        bytes_per_second: 100,
+                        average_bytes_per_second: 100,
+                        percentage: Some(50.0),
+                        eta_seconds: Some(6),
+                    },
+                )])
+                .await;
+        }
+        let store = SqliteStore::open(&db_path).await?;
+        let state = store.get_state(&download.id).await?;
+        assert!(matches!(
+            state,
+            Some(download::State::Running {
+                bytes_downloaded: 512,
+                ..
+            })
+        ));
+        store.delete_download(&download.id).await?;
+        assert!(store.get_state(&download.id).await?.is_none());
+        assert!(store.load_downloads().await?.is_empty());
+        Ok(())
+    }
+}
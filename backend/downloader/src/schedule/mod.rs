@@ -0,0 +1,147 @@
+use chrono::{DateTime, Datelike, Days, NaiveDateTime, NaiveTime, TimeZone, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// Recurring weekly time span during which the queue of a DownloadManager runs, in local time.
+/// A window ending at or before its start ends on the following day, e.g. `22:00` to `06:00`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    /// Days the window starts on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Replaces the global bandwidth limit while the window is open, in bytes per second
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+}
+
+impl TimeWindow {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn ends_next_day(&self) -> bool {
+        self.end <= self.start
+    }
+
+    pub fn contains(&self, time: &NaiveDateTime) -> bool {
+        let (day, time) = (time.weekday(), time.time());
+        if self.ends_next_day() {
+            (self.starts_on(day) && time >= self.start)
+                || (self.starts_on(day.pred()) && time < self.end)
+        } else {
+            self.starts_on(day) && time >= self.start && time < self.end
+        }
+    }
+}
+
+/// The open window at `now`, the first one if several overlap
+pub fn active_window<'a, Tz: TimeZone>(
+    windows: &'a [TimeWindow],
+    now: &DateTime<Tz>,
+) -> Option<&'a TimeWindow> {
+    let now = now.naive_local();
+    windows.iter().find(|window| window.contains(&now))
+}
+
+/// When one of the windows opens next after `now`, None without windows
+pub fn next_opening<Tz: TimeZone>(
+    windows: &[TimeWindow],
+    now: &DateTime<Tz>,
+) -> Option<DateTime<Tz>> {
+    let local = now.naive_local();
+    // A week and a day covers windows that opened just before `now` on the same weekday
+    (0..=7)
+        .filter_map(|days| local.date().checked_add_days(Days::new(days)))
+        .flat_map(|date| {
+            windows
+                .iter()
+                .filter(move |window| window.starts_on(date.weekday()))
+                .map(move |window| date.and_time(window.start))
+        })
+        .filter(|opening| *opening > local)
+        // Openings that fall into a gap of a daylight saving transition are skipped
+        .filter_map(|opening| now.timezone().from_local_datetime(&opening).earliest())
+        .min()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{NaiveDate, Utc};
+    use pretty_assertions::assert_eq;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    /// 2024-01-01 was a Monday
+    fn monday_at(hour: u32, minute: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_time(time(hour, minute))
+            .and_utc()
+    }
+
+    fn night(days: Vec<Weekday>) -> TimeWindow {
+        TimeWindow {
+            days,
+            start: time(22, 0),
+            end: time(6, 0),
+            bandwidth_limit: Some(1024),
+        }
+    }
+
+    #[test]
+    fn windows_can_span_midnight_test() {
+        let windows = [night(vec![Weekday::Sun])];
+        // Sunday 22:00 until Monday 06:00
+        assert_eq!(
+            active_window(&windows, &monday_at(5, 59)),
+            Some(&windows[0])
+        );
+        assert_eq!(active_window(&windows, &monday_at(6, 0)), None);
+        assert_eq!(active_window(&windows, &monday_at(22, 30)), None);
+        let every_day = [night(vec![])];
+        assert!(active_window(&every_day, &monday_at(23, 0)).is_some());
+        assert!(active_window(&every_day, &monday_at(12, 0)).is_none());
+    }
+
+    #[test]
+    fn next_opening_test() {
+        let office_hours = TimeWindow {
+            days: vec![Weekday::Mon, Weekday::Wed],
+            start: time(9, 0),
+            end: time(17, 0),
+            bandwidth_limit: None,
+        };
+        let windows = [office_hours, night(vec![Weekday::Tue])];
+        assert_eq!(
+            next_opening(&windows, &monday_at(8, 0)),
+            Some(monday_at(9, 0))
+        );
+        let tuesday_night = monday_at(22, 0) + Days::new(1);
+        assert_eq!(
+            next_opening(&windows, &monday_at(9, 0)),
+            Some(tuesday_night)
+        );
+        // The same weekday a week later
+        let windows = [night(vec![Weekday::Mon])];
+        assert_eq!(
+            next_opening(&windows, &monday_at(23, 0)),
+            Some(monday_at(22, 0) + Days::new(7))
+        );
+        assert_eq!(next_opening(&[], &monday_at(23, 0)), None);
+    }
+
+    #[test]
+    fn windows_are_deserialized_from_settings_test() {
+        let window: TimeWindow = serde_json::from_str(
+            r#"{"days": ["sat", "Sunday"], "start": "01:30:00", "end": "07:00:00"}"#,
+        )
+        .unwrap();
+        assert_eq!(window.days, vec![Weekday::Sat, Weekday::Sun]);
+        assert_eq!(window.start, time(1, 30));
+        assert_eq!(window.bandwidth_limit, None);
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::test_server::test_payload;
+
+const SESSION_COOKIE: &str = "session=s3ss10n";
+
+/// Local file hoster for resolver tests, speaks the API of `hoster::token::TokenHoster`.
+/// Logs in `user` with password `secret`, every page `/file/{id}` has the two parts
+/// `/dl/{id}/{id}.part{n}.bin` that are only served with the session cookie.
+/// Pages themselves require a login like on real hosters, only the API gets to see them.
+#[derive(Clone)]
+pub struct MockHoster {
+    pub url: Url,
+    pub payload: Arc<Vec<u8>>,
+    logins: Arc<AtomicUsize>,
+    /// Tokens of older generations are rejected
+    generation: Arc<AtomicUsize>,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct LinksQuery {
+    url: String,
+}
+
+impl MockHoster {
+    pub fn spawn() -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let hoster = MockHoster {
+            url,
+            payload: Arc::new(test_payload(64 * 1024)),
+            logins: Arc::new(AtomicUsize::new(0)),
+            generation: Arc::new(AtomicUsize::new(0)),
+        };
+        let app = Router::new()
+            .route("/file/:id", get(page))
+            .route("/api/login", post(login))
+            .route("/api/links", get(links))
+            .route("/dl/:id/:name", get(file))
+            .with_state(hoster.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        hoster
+    }
+
+    pub fn host(&self) -> String {
+        self.url.host_str().unwrap().to_string()
+    }
+
+    pub fn api_url(&self) -> Url {
+        self.url.join("api").unwrap()
+    }
+
+    pub fn page_url(&self, id: &str) -> Url {
+        self.url.join(&format!("file/{}", id)).unwrap()
+    }
+
+    pub fn logins(&self) -> usize {
+        self.logins.load(Ordering::SeqCst)
+    }
+
+    /// Invalidates all tokens handed out so far
+    pub fn expire_sessions(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn token(&self) -> String {
+        format!("t0k3n-{}", self.generation.load(Ordering::SeqCst))
+    }
+}
+
+async fn page(Path(id): Path<String>) -> (StatusCode, String) {
+    (
+        StatusCode::FORBIDDEN,
+        format!("<html><body>Log in to download {}</body></html>", id),
+    )
+}
+
+async fn login(State(hoster): State<MockHoster>, Json(login): Json<Login>) -> Response {
+    if login.username != "user" || login.password != "secret" {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    hoster.logins.fetch_add(1, Ordering::SeqCst);
+    (
+        [(
+            header::SET_COOKIE,
+            format!("{}; Path=/; HttpOnly", SESSION_COOKIE),
+        )],
+        Json(json!({ "token": hoster.token() })),
+    )
+        .into_response()
+}
+
+async fn links(
+    State(hoster): State<MockHoster>,
+    Query(query): Query<LinksQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let expected = format!("Bearer {}", hoster.token());
+    if headers.get(header::AUTHORIZATION).map(|v| v.as_bytes()) != Some(expected.as_bytes()) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let Some(id) = query.url.rsplit('/').next().filter(|id| !id.is_empty()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let links: Vec<_> = (1..=2)
+        .map(|part| {
+            let filename = format!("{}.part{}.bin", id, part);
+            json!({ "url": format!("/dl/{}/{}", id, filename), "filename": filename })
+        })
+        .collect();
+    Json(json!({ "links": links })).into_response()
+}
+
+async fn file(State(hoster): State<MockHoster>, headers: HeaderMap) -> Response {
+    let has_session = headers
+        .get(header::COOKIE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|cookies| cookies.split("; ").any(|c| c == SESSION_COOKIE));
+    if !has_session {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    hoster.payload.to_vec().into_response()
+}
@@ -1,10 +1,21 @@
 use reqwest::header::HeaderMap;
-use reqwest::{header, Client, Url};
+use reqwest::{header, Url};
 use std::error::Error;
 use std::path::Path;
-use tempfile::TempDir;
 
+#[cfg(test)]
+pub mod mock_hoster;
+#[cfg(test)]
+pub mod test_server;
+#[cfg(test)]
+pub mod test_tracker;
+
+#[cfg(test)]
 use crate::httpdownload::download::HttpDownload;
+#[cfg(test)]
+use reqwest::Client;
+#[cfg(test)]
+use tempfile::TempDir;
 
 /// Extracts filesize from path, if file does not exist or read fails the function returns 0
 pub async fn file_size(fpath: &Path) -> u64 {
@@ -20,8 +31,8 @@ pub type TestResult<T> = std::result::Result<T, Box<dyn Error>>;
  * Returns None if there is no filename or if url.path_segments() fails
  */
 pub fn parse_filename(url: &Url) -> Option<&str> {
-    let segments = url.path_segments()?;
-    let filename = segments.last()?;
+    let mut segments = url.path_segments()?;
+    let filename = segments.next_back()?;
     if filename.is_empty() {
         None
     } else {
@@ -56,9 +67,8 @@ pub async fn setup_test_download(url_str: &str) -> anyhow::Result<(HttpDownload,
     let tmp_dir = TempDir::new()?;
     let tmp_path = tmp_dir.path().to_owned();
     let url = Url::parse(url_str)?;
-    let filename = parse_filename(&url).unwrap().to_string();
     let client = Client::new();
-    let download = HttpDownload::create(url, tmp_path, filename, client, None).await?;
+    let download = HttpDownload::create(url, tmp_path, None, client, None).await?;
     Ok((download, tmp_dir))
 }
 
@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use reqwest::Url;
+
+/// Local HTTP server for tests, serves `payload` at `/file.bin` and answers byte range requests
+/// (`bytes=a-b` and `bytes=a-`) with 206.
+/// Every received Range header is recorded in `ranges`.
+/// Responses carry `etag`, range requests with a different If-Range are answered with the whole
+/// payload like a server whose file changed.
+#[derive(Clone)]
+pub struct TestServer {
+    pub url: Url,
+    pub payload: Arc<Vec<u8>>,
+    pub ranges: Arc<Mutex<Vec<String>>>,
+    pub etag: Arc<Mutex<String>>,
+    /// Number of upcoming requests answered with 503
+    pub failures: Arc<AtomicUsize>,
+    /// Number of upcoming range requests answered with a range one byte off
+    pub shifted: Arc<AtomicUsize>,
+    /// Content-Disposition sent with every response
+    pub disposition: Arc<Mutex<Option<String>>>,
+}
+
+/// Deterministic payload of `len` bytes that doesn't repeat with a power of two period
+pub fn test_payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+impl TestServer {
+    pub fn spawn(payload: Vec<u8>) -> Self {
+        Self::spawn_with(payload, Router::new().route("/file.bin", get(serve_file)))
+    }
+
+    /// Serves `payload` as a chunked response without Content-Length and without byte range
+    /// support, like dynamically generated content.
+    pub fn spawn_chunked(payload: Vec<u8>) -> Self {
+        Self::spawn_with(
+            payload,
+            Router::new().route("/file.bin", get(serve_chunked)),
+        )
+    }
+
+    fn spawn_with(payload: Vec<u8>, router: Router<TestServer>) -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/file.bin",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = TestServer {
+            url,
+            payload: Arc::new(payload),
+            ranges: Arc::new(Mutex::new(Vec::new())),
+            etag: Arc::new(Mutex::new("\"v1\"".to_string())),
+            failures: Arc::new(AtomicUsize::new(0)),
+            shifted: Arc::new(AtomicUsize::new(0)),
+            disposition: Default::default(),
+        };
+        let app = router.with_state(server.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        server
+    }
+
+    pub fn ranges(&self) -> Vec<String> {
+        self.ranges.lock().unwrap().clone()
+    }
+
+    /// Simulates a flaky server, the next `count` requests fail with 503
+    pub fn fail_next_requests(&self, count: usize) {
+        self.failures.store(count, Ordering::SeqCst);
+    }
+
+    /// Simulates a server that misreads range requests, the next `count` are answered with the
+    /// range starting one byte after the requested one, as stated by their Content-Range
+    pub fn shift_next_ranges(&self, count: usize) {
+        self.shifted.store(count, Ordering::SeqCst);
+    }
+
+    pub fn set_content_disposition(&self, disposition: &str) {
+        *self.disposition.lock().unwrap() = Some(disposition.to_string());
+    }
+
+    /// Simulates a change of the remote file
+    pub fn set_etag(&self, etag: &str) {
+        *self.etag.lock().unwrap() = etag.to_string();
+    }
+}
+
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse::<usize>().ok()?;
+    let end = match end {
+        "" => len,
+        end => end.parse::<usize>().ok()? + 1,
+    };
+    (start < end && end <= len).then_some((start, end))
+}
+
+async fn serve_file(State(server): State<TestServer>, headers: HeaderMap) -> Response {
+    let failing = server
+        .failures
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok();
+    if failing {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let payload = &server.payload;
+    let etag = server.etag.lock().unwrap().clone();
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let if_range = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok());
+    let range = range.filter(|_| if_range.is_none_or(|if_range| if_range == etag));
+    let mut response = match range {
+        Some(range) => {
+            server.ranges.lock().unwrap().push(range.to_string());
+            let shifted = server
+                .shifted
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            let range = parse_range(range, payload.len())
+                .map(|(start, end)| (start + usize::from(shifted), end))
+                .filter(|(start, end)| start < end);
+            match range {
+                Some((start, end)) => (
+                    StatusCode::PARTIAL_CONTENT,
+                    [
+                        (header::ACCEPT_RANGES, "bytes".to_string()),
+                        (
+                            header::CONTENT_RANGE,
+                            format!("bytes {}-{}/{}", start, end - 1, payload.len()),
+                        ),
+                    ],
+                    payload[start..end].to_vec(),
+                )
+                    .into_response(),
+                None => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+            }
+        }
+        None => (
+            StatusCode::OK,
+            [(header::ACCEPT_RANGES, "bytes")],
+            payload.to_vec(),
+        )
+            .into_response(),
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, etag.parse().unwrap());
+    if let Some(disposition) = server.disposition.lock().unwrap().as_ref() {
+        headers.insert(header::CONTENT_DISPOSITION, disposition.parse().unwrap());
+    }
+    response
+}
+
+async fn serve_chunked(State(server): State<TestServer>) -> Response {
+    let chunks: Vec<Result<Bytes, std::io::Error>> = server
+        .payload
+        .chunks(64 * 1024)
+        .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+        .collect();
+    StreamBody::new(futures::stream::iter(chunks)).into_response()
+}
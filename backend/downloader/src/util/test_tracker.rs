@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{ConnectInfo, RawQuery, State},
+    routing::get,
+    Router,
+};
+
+use crate::p2pdownload::bencode::{self, Value};
+
+/// Peers by info hash, each peer by its peer id
+type Swarms = Arc<Mutex<HashMap<Vec<u8>, HashMap<Vec<u8>, SocketAddr>>>>;
+
+/// Local HTTP tracker for torrent tests, announces are answered with a compact list of the other
+/// peers of the torrent and an interval of one second
+#[derive(Clone)]
+pub struct TestTracker {
+    pub addr: SocketAddr,
+    swarms: Swarms,
+}
+
+impl TestTracker {
+    pub fn spawn() -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker = TestTracker {
+            addr: listener.local_addr().unwrap(),
+            swarms: Default::default(),
+        };
+        let app = Router::new()
+            .route("/announce", get(announce))
+            .with_state(tracker.swarms.clone());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>()),
+        );
+        tracker
+    }
+
+    pub fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    pub fn peer_count(&self, info_hash: &[u8]) -> usize {
+        self.swarms
+            .lock()
+            .unwrap()
+            .get(info_hash)
+            .map_or(0, HashMap::len)
+    }
+}
+
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(byte) = u8::from_str_radix(&value[i + 1..i + 3], 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    decoded
+}
+
+async fn announce(
+    State(swarms): State<Swarms>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    RawQuery(query): RawQuery,
+) -> Vec<u8> {
+    let params: HashMap<&str, Vec<u8>> = query
+        .as_deref()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key, percent_decode(value)))
+        .collect();
+    let (Some(info_hash), Some(peer_id), Some(port)) = (
+        params.get("info_hash"),
+        params.get("peer_id"),
+        params
+            .get("port")
+            .and_then(|port| String::from_utf8_lossy(port).parse::<u16>().ok()),
+    ) else {
+        return bencode::encode(&Value::dict([(
+            "failure reason",
+            Value::bytes("missing parameters"),
+        )]));
+    };
+    let mut swarms = swarms.lock().unwrap();
+    let swarm = swarms.entry(info_hash.clone()).or_default();
+    if params.get("event").map(Vec::as_slice) == Some(b"stopped") {
+        swarm.remove(peer_id);
+    } else {
+        swarm.insert(peer_id.clone(), SocketAddr::new(remote.ip(), port));
+    }
+    let mut peers = Vec::new();
+    for (id, addr) in swarm.iter().filter(|(id, _)| *id != peer_id) {
+        let SocketAddr::V4(addr) = addr else {
+            log::warn!("Skipping IPv6 peer {:?}", id);
+            continue;
+        };
+        peers.extend_from_slice(&addr.ip().octets());
+        peers.extend_from_slice(&addr.port().to_be_bytes());
+    }
+    bencode::encode(&Value::dict([
+        ("interval", Value::Int(1)),
+        ("peers", Value::Bytes(peers)),
+    ]))
+}
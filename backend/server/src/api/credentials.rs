@@ -0,0 +1,74 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use downloader::credentials::{self, Credential, CredentialInfo};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{ApiError, ApiResult, AppState};
+
+/// Body of `POST /unlock`
+#[derive(Deserialize)]
+pub struct Unlock {
+    pub password: String,
+}
+
+/// Body of `POST /`, the secrets are never returned by any endpoint
+#[derive(Deserialize)]
+pub struct CreateCredential {
+    pub host: String,
+    pub credential: Credential,
+}
+
+impl From<credentials::Error> for ApiError {
+    fn from(e: credentials::Error) -> Self {
+        let status = match e {
+            credentials::Error::Locked => StatusCode::LOCKED,
+            credentials::Error::WrongPassword => StatusCode::UNAUTHORIZED,
+            credentials::Error::NotFound(_) => StatusCode::NOT_FOUND,
+            credentials::Error::Invalid(_) => StatusCode::BAD_REQUEST,
+            credentials::Error::Io(_) | credentials::Error::InvalidFile(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        ApiError::new(status, e)
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_credentials).post(create))
+        .route("/unlock", post(unlock))
+        .route("/lock", post(lock))
+        .route("/:id", delete(remove))
+}
+
+async fn unlock(State(state): State<AppState>, Json(body): Json<Unlock>) -> ApiResult<StatusCode> {
+    state.credentials.unlock(&body.password).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn lock(State(state): State<AppState>) -> StatusCode {
+    state.credentials.lock();
+    StatusCode::NO_CONTENT
+}
+
+async fn get_credentials(State(state): State<AppState>) -> ApiResult<Json<Vec<CredentialInfo>>> {
+    Ok(Json(state.credentials.list()?))
+}
+
+async fn create(
+    State(state): State<AppState>,
+    Json(body): Json<CreateCredential>,
+) -> ApiResult<(StatusCode, Json<CredentialInfo>)> {
+    let info = state.credentials.add(&body.host, body.credential).await?;
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+async fn remove(State(state): State<AppState>, Path(id): Path<Uuid>) -> ApiResult<StatusCode> {
+    state.credentials.remove(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
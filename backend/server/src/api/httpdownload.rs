@@ -0,0 +1,383 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::{get, post, put},
+    Json, Router,
+};
+use downloader::httpdownload::{
+    download::{self, checksum::Checksum, filename::CollisionPolicy},
+    manager::QueuePosition,
+    DownloadMetadata,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::Stream;
+use uuid::Uuid;
+
+use super::{ApiError, ApiResult, AppState};
+
+/// Metadata and current state of a single download, returned by `GET /{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadData {
+    pub metadata: DownloadMetadata,
+    pub state: download::State,
+}
+
+/// JSON body of `POST /`, a plain URL body is accepted as well
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateDownload {
+    pub url: String,
+    #[serde(default)]
+    pub file_path: Option<PathBuf>,
+    #[serde(default)]
+    pub checksum: Option<Checksum>,
+    /// What happens if the file already exists, renaming by default
+    #[serde(default)]
+    pub on_collision: Option<CollisionPolicy>,
+    /// Adds the download to this package
+    #[serde(default)]
+    pub package_id: Option<Uuid>,
+}
+
+/// Body of `PUT /{id}/bandwidth-limit`, null removes the limit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BandwidthLimit {
+    pub bytes_per_second: Option<u64>,
+}
+
+/// Body of `PUT /{id}/schedule`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleAt {
+    /// Unix timestamp in milliseconds
+    pub at: u64,
+}
+
+/// Body of `PUT /{id}/priority` and response of `GET /{id}/priority`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Priority {
+    pub priority: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteParams {
+    #[serde(default)]
+    pub delete_file: bool,
+}
+
+/// Query of `GET /events`, without any filter updates of every download are sent
+#[derive(Debug, Default, Deserialize)]
+pub struct EventFilter {
+    /// Comma separated ids of downloads
+    #[serde(default)]
+    pub ids: Option<String>,
+    /// Downloads that are part of this package, checked again for every batch
+    #[serde(default)]
+    pub package_id: Option<Uuid>,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", post(create))
+        .route("/events", get(events))
+        .route("/metadata", get(get_metadata_all))
+        .route("/state", get(get_state_all))
+        .route("/queue", get(get_queue))
+        .route("/:id", get(get_download).delete(delete))
+        .route("/:id/start", get(start))
+        .route("/:id/stop", get(stop))
+        .route("/:id/resume", get(resume))
+        .route("/:id/bandwidth-limit", put(set_bandwidth_limit))
+        .route("/:id/schedule", put(schedule))
+        .route("/:id/priority", get(get_priority).put(set_priority))
+        .route("/:id/queue-position", put(move_in_queue))
+}
+
+async fn create(
+    State(state): State<AppState>,
+    body: String,
+) -> ApiResult<(StatusCode, Json<DownloadMetadata>)> {
+    let request = if body.trim_start().starts_with('{') {
+        serde_json::from_str(&body)
+            .map_err(|e| ApiError::bad_request(format!("Invalid request body: {}", e)))?
+    } else {
+        CreateDownload {
+            url: body.trim().to_string(),
+            file_path: None,
+            checksum: None,
+            on_collision: None,
+            package_id: None,
+        }
+    };
+    let metadata = create_from_request(&state, request).await?;
+    Ok((StatusCode::CREATED, Json(metadata)))
+}
+
+/// Validates the request and creates the download, shared with the WebSocket commands
+pub(crate) async fn create_from_request(
+    state: &AppState,
+    request: CreateDownload,
+) -> ApiResult<DownloadMetadata> {
+    let url = Url::parse(&request.url)
+        .map_err(|e| ApiError::bad_request(format!("Invalid URL '{}': {}", request.url, e)))?;
+    let checksum = request
+        .checksum
+        .map(|checksum| Checksum::new(checksum.algorithm, &checksum.digest))
+        .transpose()
+        .map_err(ApiError::bad_request)?;
+    let package = match request.package_id {
+        Some(package_id) => Some(
+            state
+                .manager
+                .get_package(&package_id)
+                .await
+                .map_err(ApiError::not_found)?,
+        ),
+        None => None,
+    };
+    state
+        .create_download(
+            url,
+            request.file_path,
+            checksum,
+            request.on_collision,
+            package.as_ref(),
+        )
+        .await
+        .map_err(|e| match e.downcast_ref::<download::Error>() {
+            Some(download::Error::FileExists(_)) => ApiError::conflict(e),
+            _ => ApiError::internal(format!("Error creating download: {}", e)),
+        })
+}
+
+async fn get_download(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<DownloadData>> {
+    let metadata = state
+        .manager
+        .get_metadata(&id)
+        .await
+        .map_err(ApiError::not_found)?;
+    let download_state = state
+        .manager
+        .observer
+        .get_state(&id)
+        .await
+        .ok_or_else(|| ApiError::not_found(format!("No state tracked for download {}", id)))?;
+    Ok(Json(DownloadData {
+        metadata,
+        state: download_state,
+    }))
+}
+
+async fn get_metadata_all(State(state): State<AppState>) -> Json<Vec<DownloadMetadata>> {
+    Json(state.manager.get_metadata_all().await)
+}
+
+async fn get_state_all(State(state): State<AppState>) -> Json<Vec<(Uuid, download::State)>> {
+    Json(state.manager.observer.get_state_all().await)
+}
+
+/// Streams the batches flushed by the DownloadUpdateBuffer as Server-Sent Events.
+/// The first event is a `snapshot` of the current state of every matching download, followed by
+/// an `updates` event for every batch. A client that falls behind gets a new snapshot.
+async fn events(
+    State(state): State<AppState>,
+    Query(filter): Query<EventFilter>,
+) -> ApiResult<Sse<impl Stream<Item = Result<Event, serde_json::Error>>>> {
+    let ids = filter
+        .ids
+        .map(|ids| {
+            ids.split(',')
+                .map(|id| Uuid::parse_str(id.trim()))
+                .collect::<Result<HashSet<_>, _>>()
+        })
+        .transpose()
+        .map_err(|e| ApiError::bad_request(format!("Invalid download id: {}", e)))?
+        .unwrap_or_default();
+    let package_id = filter.package_id;
+    if let Some(package_id) = package_id {
+        state
+            .manager
+            .get_package(&package_id)
+            .await
+            .map_err(ApiError::not_found)?;
+    }
+    // Subscribe before taking the snapshot so no update falls in between
+    let mut receiver = state.updates.subscribe();
+    let index = state.manager.package_index();
+    let snapshot = state.manager.observer.get_state_all().await;
+    let stream = async_stream::stream! {
+        let packages = index.packages_of(snapshot.iter().map(|(id, _)| id)).await;
+        let selected = select(&ids, package_id, &snapshot, &packages);
+        yield Event::default().event("snapshot").json_data(selected);
+        loop {
+            match receiver.recv().await {
+                Ok(batch) => {
+                    let selected = select(&ids, package_id, &batch.updates, &batch.packages);
+                    if !selected.is_empty() {
+                        yield Event::default().event("updates").json_data(selected);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Event stream lagged behind, skipped {} batches", skipped);
+                    let snapshot = state.manager.observer.get_state_all().await;
+                    let packages = index.packages_of(snapshot.iter().map(|(id, _)| id)).await;
+                    let selected = select(&ids, package_id, &snapshot, &packages);
+                    yield Event::default().event("snapshot").json_data(selected);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Updates of the downloads in `ids` or in the package, all updates if there is no filter.
+/// `packages` maps the updated downloads to their package.
+fn select(
+    ids: &HashSet<Uuid>,
+    package_id: Option<Uuid>,
+    updates: &[(Uuid, download::State)],
+    packages: &HashMap<Uuid, Uuid>,
+) -> Vec<(Uuid, download::State)> {
+    updates
+        .iter()
+        .filter(|(id, _)| {
+            (ids.is_empty() && package_id.is_none())
+                || ids.contains(id)
+                || package_id.is_some_and(|package_id| packages.get(id) == Some(&package_id))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Ids of the downloads waiting for a free slot, next in line first
+async fn get_queue(State(state): State<AppState>) -> Json<Vec<Uuid>> {
+    Json(state.manager.queue().await)
+}
+
+async fn start(State(state): State<AppState>, Path(id): Path<Uuid>) -> ApiResult<StatusCode> {
+    ensure_exists(&state, &id).await?;
+    state
+        .manager
+        .start(&id)
+        .await
+        .map_err(ApiError::bad_request)?;
+    Ok(StatusCode::OK)
+}
+
+async fn stop(State(state): State<AppState>, Path(id): Path<Uuid>) -> ApiResult<StatusCode> {
+    ensure_exists(&state, &id).await?;
+    state
+        .manager
+        .stop(&id)
+        .await
+        .map_err(ApiError::bad_request)?;
+    Ok(StatusCode::OK)
+}
+
+async fn resume(State(state): State<AppState>, Path(id): Path<Uuid>) -> ApiResult<StatusCode> {
+    ensure_exists(&state, &id).await?;
+    state
+        .manager
+        .resume(&id)
+        .await
+        .map_err(ApiError::bad_request)?;
+    Ok(StatusCode::OK)
+}
+
+async fn set_bandwidth_limit(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(limit): Json<BandwidthLimit>,
+) -> ApiResult<StatusCode> {
+    ensure_exists(&state, &id).await?;
+    state
+        .manager
+        .set_download_bandwidth_limit(&id, limit.bytes_per_second)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(StatusCode::OK)
+}
+
+async fn schedule(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<ScheduleAt>,
+) -> ApiResult<StatusCode> {
+    ensure_exists(&state, &id).await?;
+    state
+        .manager
+        .schedule_download(&id, request.at)
+        .await
+        .map_err(ApiError::bad_request)?;
+    Ok(StatusCode::OK)
+}
+
+async fn get_priority(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Priority>> {
+    ensure_exists(&state, &id).await?;
+    Ok(Json(Priority {
+        priority: state.manager.priority(&id).await,
+    }))
+}
+
+async fn set_priority(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<Priority>,
+) -> ApiResult<StatusCode> {
+    ensure_exists(&state, &id).await?;
+    state
+        .manager
+        .set_priority(&id, request.priority)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(StatusCode::OK)
+}
+
+async fn move_in_queue(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(position): Json<QueuePosition>,
+) -> ApiResult<StatusCode> {
+    ensure_exists(&state, &id).await?;
+    state
+        .manager
+        .move_in_queue(&id, position)
+        .await
+        .map_err(ApiError::bad_request)?;
+    Ok(StatusCode::OK)
+}
+
+async fn delete(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<DeleteParams>,
+) -> ApiResult<StatusCode> {
+    ensure_exists(&state, &id).await?;
+    state
+        .manager
+        .delete(&id, params.delete_file)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn ensure_exists(state: &AppState, id: &Uuid) -> ApiResult<()> {
+    state
+        .manager
+        .get_metadata(id)
+        .await
+        .map(|_| ())
+        .map_err(ApiError::not_found)
+}
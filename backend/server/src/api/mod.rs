@@ -0,0 +1,141 @@
+pub mod credentials;
+pub mod httpdownload;
+pub mod p2pdownload;
+pub mod package;
+pub mod proxy;
+pub mod settings;
+pub mod ws;
+
+use std::path::PathBuf;
+
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use downloader::{
+    credentials::CredentialStore,
+    httpdownload::{
+        download::{checksum::Checksum, config::HttpDownloadConfig, filename::CollisionPolicy},
+        manager::{DownloadManager, NewDownload},
+        package::Package,
+        DownloadMetadata,
+    },
+};
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{settings::SettingManager, updates::UpdateBroadcast};
+
+/// Shared state handed to every route and gRPC service, all members are cheap to clone.
+#[derive(Clone)]
+pub struct AppState {
+    pub manager: DownloadManager,
+    pub settings: SettingManager,
+    pub updates: UpdateBroadcast,
+    pub credentials: CredentialStore,
+}
+
+impl AppState {
+    /// Creates a new HttpDownload and adds it to the manager.
+    /// Without a `file_path` the file is placed in the default download directory and its name is
+    /// detected from the response.
+    /// With a `checksum` the file is verified once it's downloaded.
+    /// `on_collision` decides what happens if the file already exists, renaming by default.
+    /// Downloads created for a `package` are added to it and default to its directory.
+    /// Stored credentials for the host are sent along.
+    /// A hoster page can resolve into several downloads, the metadata of the first one is
+    /// returned.
+    pub async fn create_download(
+        &self,
+        url: Url,
+        file_path: Option<PathBuf>,
+        checksum: Option<Checksum>,
+        on_collision: Option<CollisionPolicy>,
+        package: Option<&Package>,
+    ) -> anyhow::Result<DownloadMetadata> {
+        let (directory, filename) = match file_path {
+            Some(path) => (
+                path.parent().map(PathBuf::from).unwrap_or_default(),
+                path.file_name()
+                    .map(|name| name.to_string_lossy().to_string()),
+            ),
+            None => (
+                match package {
+                    Some(package) => package.directory.clone(),
+                    None => self.settings.read().await.default_download_dir.clone(),
+                },
+                None,
+            ),
+        };
+        let mut config = HttpDownloadConfig {
+            checksum,
+            on_collision: on_collision.unwrap_or_default(),
+            ..Default::default()
+        };
+        self.credentials.apply(&url, &mut config.headers);
+        let ids = self
+            .manager
+            .add(NewDownload::Url {
+                url,
+                directory,
+                filename,
+                config,
+            })
+            .await?;
+        let Some(first) = ids.first() else {
+            anyhow::bail!("No files found on the hoster page");
+        };
+        if let Some(package) = package {
+            for id in ids.iter() {
+                if let Err(e) = self.manager.add_to_package(&package.id, id).await {
+                    log::error!(
+                        "Couldn't add download {} to package {}: {}",
+                        id,
+                        package.id,
+                        e
+                    );
+                }
+            }
+        }
+        self.manager.get_metadata(first).await
+    }
+}
+
+/// Error body returned by every endpoint of the API: `{"error": "..."}`
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    pub error: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl ToString) -> Self {
+        Self {
+            status,
+            error: error.to_string(),
+        }
+    }
+
+    pub fn bad_request(error: impl ToString) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, error)
+    }
+
+    pub fn not_found(error: impl ToString) -> Self {
+        Self::new(StatusCode::NOT_FOUND, error)
+    }
+
+    pub fn conflict(error: impl ToString) -> Self {
+        Self::new(StatusCode::CONFLICT, error)
+    }
+
+    pub fn internal(error: impl ToString) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        log::error!("Request failed with {}: {}", self.status, self.error);
+        (self.status, Json(self)).into_response()
+    }
+}
+
+pub type ApiResult<T> = std::result::Result<T, ApiError>;
@@ -0,0 +1,93 @@
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    routing::post,
+    Json, Router,
+};
+use downloader::httpdownload::{Download, DownloadMetadata};
+use downloader::p2pdownload::{TorrentConfig, TorrentDownload};
+use serde::Deserialize;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use super::{ApiError, ApiResult, AppState};
+
+/// Query of `POST /`, the body is either a magnet link or the content of a .torrent file
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateTorrent {
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    #[serde(default)]
+    pub seed_ratio: Option<f64>,
+    /// Comma separated indices of the files to download, all files if missing
+    #[serde(default)]
+    pub files: Option<String>,
+    /// Adds the torrent to this package, it's placed in the package directory
+    #[serde(default)]
+    pub package_id: Option<Uuid>,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/", post(create))
+}
+
+async fn create(
+    State(state): State<AppState>,
+    Query(request): Query<CreateTorrent>,
+    body: Bytes,
+) -> ApiResult<(StatusCode, Json<DownloadMetadata>)> {
+    let files = request
+        .files
+        .map(|files| {
+            files
+                .split(',')
+                .map(|index| index.trim().parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .map_err(|e| ApiError::bad_request(format!("Invalid file selection: {}", e)))?;
+    let package = match request.package_id {
+        Some(package_id) => Some(
+            state
+                .manager
+                .get_package(&package_id)
+                .await
+                .map_err(ApiError::not_found)?,
+        ),
+        None => None,
+    };
+    let directory = match (request.directory, package.as_ref()) {
+        (Some(directory), _) => directory,
+        (None, Some(package)) => package.directory.clone(),
+        (None, None) => state.settings.read().await.default_download_dir.clone(),
+    };
+    let mut config = TorrentConfig {
+        files,
+        ..Default::default()
+    };
+    if let Some(seed_ratio) = request.seed_ratio {
+        config.seed_ratio = seed_ratio;
+    }
+    let torrent = match std::str::from_utf8(&body).map(str::trim) {
+        Ok(uri) if uri.starts_with("magnet:") => {
+            TorrentDownload::from_magnet(uri, directory, config)
+        }
+        _ => TorrentDownload::from_torrent(&body, directory, config),
+    }
+    .map_err(ApiError::bad_request)?;
+    let metadata = torrent.get_metadata();
+    let ids = state
+        .manager
+        .add(torrent)
+        .await
+        .map_err(ApiError::internal)?;
+    if let Some(package) = package {
+        state
+            .manager
+            .add_to_package(&package.id, &ids[0])
+            .await
+            .map_err(ApiError::internal)?;
+    }
+    Ok((StatusCode::CREATED, Json(metadata)))
+}
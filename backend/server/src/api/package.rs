@@ -0,0 +1,149 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post, put},
+    Json, Router,
+};
+use downloader::httpdownload::package::{Package, PackageProgress};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use super::{
+    httpdownload::{DeleteParams, ScheduleAt},
+    ApiError, ApiResult, AppState,
+};
+
+/// Body of `POST /`, without a directory the package gets its own directory named after it in
+/// the default download directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatePackage {
+    pub name: String,
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+}
+
+/// A package with the combined progress of its downloads, returned by `GET /{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageData {
+    pub package: Package,
+    pub progress: PackageProgress,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", post(create).get(get_packages))
+        .route("/:id", get(get_package).delete(delete))
+        .route("/:id/start", get(start))
+        .route("/:id/stop", get(stop))
+        .route("/:id/schedule", put(schedule))
+        .route("/:id/downloads/:download_id", put(add_download))
+}
+
+async fn create(
+    State(state): State<AppState>,
+    Json(request): Json<CreatePackage>,
+) -> ApiResult<(StatusCode, Json<Package>)> {
+    if request.name.trim().is_empty() {
+        return Err(ApiError::bad_request("Package name must not be empty"));
+    }
+    let directory = match request.directory {
+        Some(directory) => directory,
+        None => state
+            .settings
+            .read()
+            .await
+            .default_download_dir
+            .join(&request.name),
+    };
+    let package = state
+        .manager
+        .create_package(request.name, directory)
+        .await
+        .map_err(|e| ApiError::internal(format!("Error creating package: {}", e)))?;
+    Ok((StatusCode::CREATED, Json(package)))
+}
+
+async fn get_packages(State(state): State<AppState>) -> Json<Vec<Package>> {
+    Json(state.manager.get_packages().await)
+}
+
+async fn get_package(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<PackageData>> {
+    let package = state
+        .manager
+        .get_package(&id)
+        .await
+        .map_err(ApiError::not_found)?;
+    let progress = state
+        .manager
+        .package_progress(&id)
+        .await
+        .map_err(ApiError::not_found)?;
+    Ok(Json(PackageData { package, progress }))
+}
+
+async fn start(State(state): State<AppState>, Path(id): Path<Uuid>) -> ApiResult<StatusCode> {
+    state
+        .manager
+        .start_package(&id)
+        .await
+        .map_err(ApiError::not_found)?;
+    Ok(StatusCode::OK)
+}
+
+async fn stop(State(state): State<AppState>, Path(id): Path<Uuid>) -> ApiResult<StatusCode> {
+    state
+        .manager
+        .stop_package(&id)
+        .await
+        .map_err(ApiError::not_found)?;
+    Ok(StatusCode::OK)
+}
+
+/// Schedules every download of the package that isn't running
+async fn schedule(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(request): Json<ScheduleAt>,
+) -> ApiResult<StatusCode> {
+    state
+        .manager
+        .schedule_package(&id, request.at)
+        .await
+        .map_err(ApiError::not_found)?;
+    Ok(StatusCode::OK)
+}
+
+/// Moves an existing download into the package
+async fn add_download(
+    State(state): State<AppState>,
+    Path((id, download_id)): Path<(Uuid, Uuid)>,
+) -> ApiResult<StatusCode> {
+    state
+        .manager
+        .add_to_package(&id, &download_id)
+        .await
+        .map_err(ApiError::not_found)?;
+    Ok(StatusCode::OK)
+}
+
+async fn delete(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<DeleteParams>,
+) -> ApiResult<StatusCode> {
+    state
+        .manager
+        .get_package(&id)
+        .await
+        .map_err(ApiError::not_found)?;
+    state
+        .manager
+        .delete_package(&id, params.delete_file)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(StatusCode::NO_CONTENT)
+}
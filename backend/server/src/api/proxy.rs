@@ -0,0 +1,67 @@
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Json, Router,
+};
+use downloader::proxy::ProxyStatus;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{ApiError, ApiResult, AppState};
+
+/// Proxy of a download, None stands for a direct connection or no pin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyAssignment {
+    pub proxy: Option<String>,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_status))
+        .route("/health-check", post(check_health))
+        .route("/download/:id", get(get_download_proxy).put(pin_download))
+}
+
+async fn get_status(State(state): State<AppState>) -> Json<Vec<ProxyStatus>> {
+    Json(state.manager.clients.status())
+}
+
+/// Checks every proxy right away instead of waiting for the next scheduled check
+async fn check_health(State(state): State<AppState>) -> Json<Vec<ProxyStatus>> {
+    state.manager.clients.check_health().await;
+    Json(state.manager.clients.status())
+}
+
+/// Proxy the download was last started with
+async fn get_download_proxy(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<ProxyAssignment>> {
+    state
+        .manager
+        .get_metadata(&id)
+        .await
+        .map_err(ApiError::not_found)?;
+    Ok(Json(ProxyAssignment {
+        proxy: state.manager.clients.proxy_of(&id),
+    }))
+}
+
+/// Pins the download to a proxy, applies from the next start or resume on and survives restarts
+async fn pin_download(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(assignment): Json<ProxyAssignment>,
+) -> ApiResult<Json<ProxyAssignment>> {
+    state
+        .manager
+        .get_metadata(&id)
+        .await
+        .map_err(ApiError::not_found)?;
+    state
+        .manager
+        .pin_download(&id, assignment.proxy.clone())
+        .await
+        .map_err(ApiError::bad_request)?;
+    Ok(Json(assignment))
+}
@@ -0,0 +1,45 @@
+use axum::{extract::State, routing::get, Json, Router};
+
+use super::{ApiError, ApiResult, AppState};
+use crate::settings::Settings;
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/", get(get_settings).put(update_settings))
+}
+
+async fn get_settings(State(state): State<AppState>) -> Json<Settings> {
+    Json(state.settings.read().await.clone())
+}
+
+/// Overwrites the settings and applies them right away, invalid proxy or hoster settings are
+/// rejected before anything is changed
+async fn update_settings(
+    State(state): State<AppState>,
+    Json(settings): Json<Settings>,
+) -> ApiResult<Json<Settings>> {
+    state
+        .manager
+        .clients
+        .configure(settings.proxy.clone())
+        .map_err(ApiError::bad_request)?;
+    state
+        .manager
+        .hosters
+        .configure(&settings.hosters, &state.credentials)
+        .map_err(ApiError::bad_request)?;
+    state
+        .manager
+        .set_max_concurrent_downloads(settings.max_concurrent_downloads)
+        .await;
+    state
+        .manager
+        .set_bandwidth_limit(settings.bandwidth_limit)
+        .await;
+    state.manager.set_schedule(settings.schedule.clone()).await;
+    state
+        .manager
+        .set_preemption(settings.preempt_lower_priority)
+        .await;
+    state.settings.write(settings.clone()).await;
+    Ok(Json(settings))
+}
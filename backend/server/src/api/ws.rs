@@ -0,0 +1,323 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use downloader::httpdownload::{download, DownloadUpdateSubscriber, Subscription};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+use super::httpdownload::{create_from_request, ensure_exists, CreateDownload};
+use super::{ApiError, ApiResult, AppState};
+
+/// Batches of updates waiting to be written to a socket, further batches are dropped and the
+/// client gets a fresh snapshot instead
+const UPDATE_CAPACITY: usize = 64;
+
+type Updates = Vec<(Uuid, download::State)>;
+
+/// Command sent by a client, `id` is a correlation id echoed in the response to it
+#[derive(Debug, Deserialize)]
+pub struct ClientMessage {
+    #[serde(default)]
+    pub id: Value,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Sends updates of the given downloads from now on, of all downloads if empty.
+    /// Answered with their current state, replaces the previous subscription.
+    Subscribe {
+        #[serde(default)]
+        ids: Vec<Uuid>,
+    },
+    Unsubscribe,
+    Add(CreateDownload),
+    Start {
+        download_id: Uuid,
+    },
+    Stop {
+        download_id: Uuid,
+    },
+    Resume {
+        download_id: Uuid,
+    },
+    Delete {
+        download_id: Uuid,
+        #[serde(default)]
+        delete_file: bool,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Answer to the command with the same id, either `result` or `error` is set
+    Response {
+        id: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Updates {
+        updates: Updates,
+    },
+}
+
+/// Forwards the updates of the subscribed downloads to the task serving the socket
+struct SocketSubscriber {
+    ids: HashSet<Uuid>,
+    /// Generation of the subscription the batches are tagged with
+    generation: u64,
+    sender: mpsc::Sender<(u64, Updates)>,
+    lagged: Arc<AtomicBool>,
+}
+
+/// State of a connection shared by the commands of a client
+struct Session {
+    sender: mpsc::Sender<(u64, Updates)>,
+    /// Responses to the commands running in the background
+    responses: mpsc::UnboundedSender<ServerMessage>,
+    subscription: Option<Subscription>,
+    /// Downloads of the current subscription, all downloads if empty
+    ids: HashSet<Uuid>,
+    /// Bumped whenever the subscription ends, queued batches of older generations are stale
+    generation: u64,
+    /// Set once a batch of updates of the current subscription was dropped
+    lagged: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl DownloadUpdateSubscriber for SocketSubscriber {
+    async fn update(&self, updates: &[(Uuid, download::State)]) {
+        let updates: Updates = updates
+            .iter()
+            .filter(|(id, _)| self.ids.is_empty() || self.ids.contains(id))
+            .cloned()
+            .collect();
+        if updates.is_empty() {
+            return;
+        }
+        if let Err(TrySendError::Full(_)) = self.sender.try_send((self.generation, updates)) {
+            log::warn!("WebSocket client fell behind, dropping a batch of updates");
+            self.lagged.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/", get(upgrade))
+}
+
+async fn upgrade(State(state): State<AppState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| serve(state, socket))
+}
+
+/// Answers commands and writes subscribed updates until the client disconnects
+async fn serve(state: AppState, mut socket: WebSocket) {
+    log::info!("WebSocket client connected");
+    let (sender, mut updates) = mpsc::channel(UPDATE_CAPACITY);
+    let (responses, mut finished) = mpsc::unbounded_channel();
+    let mut session = Session {
+        sender,
+        responses,
+        subscription: None,
+        ids: HashSet::new(),
+        generation: 0,
+        lagged: Arc::new(AtomicBool::new(false)),
+    };
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => match handle(&state, &text, &mut session).await {
+                    Some(response) => response,
+                    // Answered once the command is done
+                    None => continue,
+                },
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    log::warn!("WebSocket connection failed: {}", e);
+                    break;
+                }
+            },
+            Some(response) = finished.recv() => response,
+            Some((generation, batch)) = updates.recv() => {
+                if generation != session.generation {
+                    // Queued before the subscription changed
+                    continue;
+                }
+                if session.lagged.swap(false, Ordering::Relaxed) {
+                    // The queued batches are older than the snapshot
+                    while updates.try_recv().is_ok() {}
+                    ServerMessage::Updates {
+                        updates: snapshot(&state, &session.ids).await,
+                    }
+                } else {
+                    ServerMessage::Updates { updates: batch }
+                }
+            }
+        };
+        let text = serde_json::to_string(&message).expect("Messages are always serializable");
+        if let Err(e) = socket.send(Message::Text(text)).await {
+            log::warn!("Couldn't write to WebSocket: {}", e);
+            break;
+        }
+    }
+    // Dropping the subscription unsubscribes
+    drop(session);
+    log::info!("WebSocket client disconnected");
+}
+
+/// Commands changing the subscription are answered right away, the others run in the
+/// background and send their response to `session.responses` so updates keep flowing
+async fn handle(state: &AppState, text: &str, session: &mut Session) -> Option<ServerMessage> {
+    let message: ClientMessage = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(e) => {
+            // The correlation id is still answered if the rest of the message is invalid
+            let id = serde_json::from_str::<Value>(text)
+                .ok()
+                .and_then(|message| message.get("id").cloned())
+                .unwrap_or_default();
+            return Some(ServerMessage::Response {
+                id,
+                result: None,
+                error: Some(format!("Invalid message: {}", e)),
+            });
+        }
+    };
+    let id = message.id;
+    match message.command {
+        Command::Subscribe { ids } => {
+            session.unsubscribe();
+            session.ids = ids.into_iter().collect();
+            // Subscribe before taking the snapshot so no update falls in between
+            let subscriber = SocketSubscriber {
+                ids: session.ids.clone(),
+                generation: session.generation,
+                sender: session.sender.clone(),
+                lagged: session.lagged.clone(),
+            };
+            session.subscription = Some(state.manager.add_subscriber(subscriber));
+            Some(respond(id, to_value(snapshot(state, &session.ids).await)))
+        }
+        Command::Unsubscribe => {
+            session.unsubscribe();
+            Some(respond(id, Ok(Value::Null)))
+        }
+        command => {
+            let state = state.clone();
+            let responses = session.responses.clone();
+            tokio::spawn(async move {
+                // The client may be gone by now
+                let _ = responses.send(respond(id, execute(&state, command).await));
+            });
+            None
+        }
+    }
+}
+
+impl Session {
+    /// Ends the current subscription, its queued batches are skipped from now on
+    fn unsubscribe(&mut self) {
+        self.subscription = None;
+        self.generation += 1;
+        self.lagged = Arc::new(AtomicBool::new(false));
+    }
+}
+
+fn respond(id: Value, result: ApiResult<Value>) -> ServerMessage {
+    match result {
+        Ok(result) => ServerMessage::Response {
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => ServerMessage::Response {
+            id,
+            result: None,
+            error: Some(e.error),
+        },
+    }
+}
+
+/// Runs the commands that don't touch the subscription
+async fn execute(state: &AppState, command: Command) -> ApiResult<Value> {
+    match command {
+        Command::Subscribe { .. } | Command::Unsubscribe => {
+            unreachable!("Subscriptions are handled by the session")
+        }
+        Command::Add(request) => to_value(create_from_request(state, request).await?),
+        Command::Start { download_id } => {
+            ensure_exists(state, &download_id).await?;
+            state
+                .manager
+                .start(&download_id)
+                .await
+                .map_err(ApiError::bad_request)?;
+            Ok(Value::Null)
+        }
+        Command::Stop { download_id } => {
+            ensure_exists(state, &download_id).await?;
+            state
+                .manager
+                .stop(&download_id)
+                .await
+                .map_err(ApiError::bad_request)?;
+            Ok(Value::Null)
+        }
+        Command::Resume { download_id } => {
+            ensure_exists(state, &download_id).await?;
+            state
+                .manager
+                .resume(&download_id)
+                .await
+                .map_err(ApiError::bad_request)?;
+            Ok(Value::Null)
+        }
+        Command::Delete {
+            download_id,
+            delete_file,
+        } => {
+            ensure_exists(state, &download_id).await?;
+            state
+                .manager
+                .delete(&download_id, delete_file)
+                .await
+                .map_err(ApiError::internal)?;
+            Ok(Value::Null)
+        }
+    }
+}
+
+/// Current state of the downloads in `ids`, of all downloads if empty
+async fn snapshot(state: &AppState, ids: &HashSet<Uuid>) -> Updates {
+    state
+        .manager
+        .observer
+        .get_state_all()
+        .await
+        .into_iter()
+        .filter(|(id, _)| ids.is_empty() || ids.contains(id))
+        .collect()
+}
+
+fn to_value(value: impl Serialize) -> ApiResult<Value> {
+    serde_json::to_value(value).map_err(ApiError::internal)
+}
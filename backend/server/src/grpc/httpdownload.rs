@@ -0,0 +1,202 @@
+use std::{collections::HashSet, path::PathBuf, pin::Pin};
+
+use downloader::httpdownload::download;
+use reqwest::Url;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use super::{
+    parse_checksum, parse_id,
+    proto::{
+        http_download_manager_server::HttpDownloadManager, CreateDownloadRequest,
+        DeleteDownloadRequest, Download, DownloadId, DownloadMetadata, DownloadUpdate,
+        DownloadUpdateBatch, Empty, ListDownloadsRequest, ListDownloadsResponse,
+        WatchUpdatesRequest,
+    },
+};
+use crate::api::AppState;
+
+pub use super::proto::http_download_manager_server::HttpDownloadManagerServer;
+
+type GrpcResult<T> = Result<Response<T>, Status>;
+
+/// gRPC counterpart of the REST routes in `api::httpdownload`, backed by the same AppState.
+pub struct HttpDownloadService {
+    state: AppState,
+}
+
+impl HttpDownloadService {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    pub fn into_server(self) -> HttpDownloadManagerServer<Self> {
+        HttpDownloadManagerServer::new(self)
+    }
+
+    async fn get_download(&self, id: &Uuid) -> Result<Download, Status> {
+        let metadata = self
+            .state
+            .manager
+            .get_metadata(id)
+            .await
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        let state = self.state.manager.observer.get_state(id).await;
+        Ok(Download {
+            metadata: Some(metadata.into()),
+            state: state.map(Into::into),
+        })
+    }
+}
+
+fn to_batch(updates: &[(Uuid, download::State)], ids: &HashSet<Uuid>) -> DownloadUpdateBatch {
+    let updates = updates
+        .iter()
+        .filter(|(id, _)| ids.is_empty() || ids.contains(id))
+        .map(|(id, state)| DownloadUpdate {
+            id: id.as_bytes().to_vec(),
+            state: Some(state.clone().into()),
+        })
+        .collect();
+    DownloadUpdateBatch { updates }
+}
+
+#[tonic::async_trait]
+impl HttpDownloadManager for HttpDownloadService {
+    type WatchUpdatesStream =
+        Pin<Box<dyn Stream<Item = Result<DownloadUpdateBatch, Status>> + Send + 'static>>;
+
+    async fn create(
+        &self,
+        request: Request<CreateDownloadRequest>,
+    ) -> GrpcResult<DownloadMetadata> {
+        let request = request.into_inner();
+        let url = Url::parse(&request.url).map_err(|e| {
+            Status::invalid_argument(format!("Invalid URL '{}': {}", request.url, e))
+        })?;
+        let checksum = request.checksum.map(parse_checksum).transpose()?;
+        let package = match request.package_id {
+            Some(package_id) => Some(
+                self.state
+                    .manager
+                    .get_package(&parse_id(&package_id)?)
+                    .await
+                    .map_err(|e| Status::not_found(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let metadata = self
+            .state
+            .create_download(
+                url,
+                request.file_path.map(PathBuf::from),
+                checksum,
+                None,
+                package.as_ref(),
+            )
+            .await
+            .map_err(|e| match e.downcast_ref::<download::Error>() {
+                Some(download::Error::FileExists(_)) => Status::already_exists(e.to_string()),
+                _ => Status::internal(format!("Error creating download: {}", e)),
+            })?;
+        Ok(Response::new(metadata.into()))
+    }
+
+    async fn get(&self, request: Request<DownloadId>) -> GrpcResult<Download> {
+        let id = parse_id(&request.into_inner().id)?;
+        Ok(Response::new(self.get_download(&id).await?))
+    }
+
+    async fn list(&self, _: Request<ListDownloadsRequest>) -> GrpcResult<ListDownloadsResponse> {
+        let observer = &self.state.manager.observer;
+        let mut downloads = Vec::new();
+        for metadata in self.state.manager.get_metadata_all().await {
+            let state = observer.get_state(&metadata.id).await;
+            downloads.push(Download {
+                metadata: Some(metadata.into()),
+                state: state.map(Into::into),
+            });
+        }
+        Ok(Response::new(ListDownloadsResponse { downloads }))
+    }
+
+    async fn start(&self, request: Request<DownloadId>) -> GrpcResult<Empty> {
+        let id = parse_id(&request.into_inner().id)?;
+        self.get_download(&id).await?;
+        self.state
+            .manager
+            .start(&id)
+            .await
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn stop(&self, request: Request<DownloadId>) -> GrpcResult<Empty> {
+        let id = parse_id(&request.into_inner().id)?;
+        self.get_download(&id).await?;
+        self.state
+            .manager
+            .stop(&id)
+            .await
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn resume(&self, request: Request<DownloadId>) -> GrpcResult<Empty> {
+        let id = parse_id(&request.into_inner().id)?;
+        self.get_download(&id).await?;
+        self.state
+            .manager
+            .resume(&id)
+            .await
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn delete(&self, request: Request<DeleteDownloadRequest>) -> GrpcResult<Empty> {
+        let request = request.into_inner();
+        let id = parse_id(&request.id)?;
+        self.get_download(&id).await?;
+        self.state
+            .manager
+            .delete(&id, request.delete_file)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn watch_updates(
+        &self,
+        request: Request<WatchUpdatesRequest>,
+    ) -> GrpcResult<Self::WatchUpdatesStream> {
+        let ids = request
+            .into_inner()
+            .ids
+            .iter()
+            .map(|id| parse_id(id))
+            .collect::<Result<HashSet<Uuid>, Status>>()?;
+        // Subscribe before taking the snapshot so no update falls in between
+        let mut receiver = self.state.updates.subscribe();
+        let snapshot = self.state.manager.observer.get_state_all().await;
+        let stream = async_stream::stream! {
+            yield Ok(to_batch(&snapshot, &ids));
+            loop {
+                match receiver.recv().await {
+                    Ok(updates) => {
+                        let batch = to_batch(&updates.updates, &ids);
+                        if !batch.updates.is_empty() {
+                            yield Ok(batch);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("gRPC update stream lagged behind, skipped {} batches", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
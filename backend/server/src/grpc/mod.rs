@@ -0,0 +1,89 @@
+// tonic::Status is the error type of every generated gRPC method, boxing it buys nothing
+#![allow(clippy::result_large_err)]
+
+pub mod httpdownload;
+
+pub mod proto {
+    tonic::include_proto!("ludownloader");
+}
+
+use downloader::httpdownload::{
+    download::{
+        self,
+        checksum::{Checksum, ChecksumAlgorithm},
+    },
+    DownloadMetadata,
+};
+use tonic::Status;
+use uuid::Uuid;
+
+use self::proto::download_state;
+
+pub fn parse_id(bytes: &[u8]) -> Result<Uuid, Status> {
+    Uuid::from_slice(bytes).map_err(|e| Status::invalid_argument(format!("Invalid id: {}", e)))
+}
+
+pub fn parse_checksum(checksum: proto::Checksum) -> Result<Checksum, Status> {
+    let algorithm: ChecksumAlgorithm = checksum
+        .algorithm
+        .parse()
+        .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+    Checksum::new(algorithm, &checksum.digest)
+        .map_err(|e| Status::invalid_argument(format!("{}", e)))
+}
+
+impl From<DownloadMetadata> for proto::DownloadMetadata {
+    fn from(metadata: DownloadMetadata) -> Self {
+        Self {
+            id: metadata.id.as_bytes().to_vec(),
+            url: metadata.url,
+            file_path: metadata.file_path.to_string_lossy().to_string(),
+            download_size: metadata.download_size,
+            etag: metadata.etag,
+            last_modified: metadata.last_modified,
+        }
+    }
+}
+
+impl From<download::State> for proto::DownloadState {
+    fn from(state: download::State) -> Self {
+        let state = match state {
+            download::State::Complete => {
+                download_state::State::Complete(download_state::Complete {})
+            }
+            download::State::Paused(bytes_downloaded) => {
+                download_state::State::Paused(download_state::Paused { bytes_downloaded })
+            }
+            download::State::Running {
+                bytes_downloaded,
+                bytes_per_second,
+                average_bytes_per_second,
+                percentage,
+                eta_seconds,
+            } => download_state::State::Running(download_state::Running {
+                bytes_downloaded,
+                bytes_per_second,
+                average_bytes_per_second,
+                percentage,
+                eta_seconds,
+            }),
+            download::State::Error(error) => {
+                download_state::State::Error(download_state::Error { error })
+            }
+            download::State::Queued => download_state::State::Queued(download_state::Queued {}),
+            download::State::Scheduled { at } => {
+                download_state::State::Scheduled(download_state::Scheduled { at })
+            }
+            download::State::Verifying => {
+                download_state::State::Verifying(download_state::Verifying {})
+            }
+            download::State::Retrying { attempt, next_at } => {
+                download_state::State::Retrying(download_state::Retrying { attempt, next_at })
+            }
+            download::State::Seeding { bytes_uploaded } => {
+                download_state::State::Seeding(download_state::Seeding { bytes_uploaded })
+            }
+        };
+        Self { state: Some(state) }
+    }
+}
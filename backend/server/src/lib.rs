@@ -1,8 +1,130 @@
+pub mod api;
+pub mod grpc;
 mod settings;
-use std::net::TcpListener;
+mod updates;
 
-pub async fn launch_app(listener: TcpListener) {
-    // let httpdownload_routes = routes().with_state(state);
-    // let app = Router::new().nest("/api/v1/httpdownload", httpdownload_routes);
-    todo!()
+use std::{net::TcpListener, path::PathBuf};
+
+use api::AppState;
+use axum::Router;
+use downloader::{
+    credentials::CredentialStore, httpdownload::manager::DownloadManager,
+    p2pdownload::TorrentDownload, persistence::SqliteStore,
+};
+use grpc::httpdownload::HttpDownloadService;
+use settings::SettingManager;
+use tokio_stream::wrappers::TcpListenerStream;
+use updates::UpdateBroadcast;
+
+/// Unlocks the credentials on startup, otherwise they stay locked until unlocked over the API
+const CREDENTIALS_PASSWORD_VAR: &str = "LUDOWNLOADER_CREDENTIALS_PASSWORD";
+
+async fn build_state(settings_path: Option<PathBuf>) -> AppState {
+    let settings = SettingManager::load(settings_path).await;
+    let store = SqliteStore::open(settings.database_path())
+        .await
+        .expect("Couldn't open database");
+    store.register_kind(TorrentDownload::KIND, TorrentDownload::restore);
+    let manager = DownloadManager::with_persistence(store)
+        .await
+        .expect("Couldn't restore downloads from database");
+    let (max_concurrent_downloads, bandwidth_limit, proxy, hosters, schedule, preempt) = {
+        let settings = settings.read().await;
+        (
+            settings.max_concurrent_downloads,
+            settings.bandwidth_limit,
+            settings.proxy.clone(),
+            settings.hosters.clone(),
+            settings.schedule.clone(),
+            settings.preempt_lower_priority,
+        )
+    };
+    if let Err(e) = manager.clients.configure(proxy) {
+        log::error!("Invalid proxy settings, using direct connections: {}", e);
+    }
+    manager
+        .set_max_concurrent_downloads(max_concurrent_downloads)
+        .await;
+    manager.set_bandwidth_limit(bandwidth_limit).await;
+    manager.set_schedule(schedule).await;
+    manager.set_preemption(preempt).await;
+    let credentials = CredentialStore::new(settings.credentials_path());
+    if let Ok(password) = std::env::var(CREDENTIALS_PASSWORD_VAR) {
+        if let Err(e) = credentials.unlock(&password).await {
+            log::error!("Couldn't unlock credentials: {}", e);
+        }
+    }
+    if let Err(e) = manager.hosters.configure(&hosters, &credentials) {
+        log::error!(
+            "Invalid hoster settings, hoster pages aren't resolved: {}",
+            e
+        );
+    }
+    manager.set_credentials(credentials.clone()).await;
+    let updates = UpdateBroadcast::new(manager.package_index());
+    manager.add_subscriber(updates.clone()).detach();
+    AppState {
+        manager,
+        settings,
+        updates,
+        credentials,
+    }
+}
+
+async fn serve_rest(listener: TcpListener, state: AppState) {
+    let httpdownload_routes = api::httpdownload::routes().with_state(state.clone());
+    let package_routes = api::package::routes().with_state(state.clone());
+    let credentials_routes = api::credentials::routes().with_state(state.clone());
+    let p2pdownload_routes = api::p2pdownload::routes().with_state(state.clone());
+    let proxy_routes = api::proxy::routes().with_state(state.clone());
+    let ws_routes = api::ws::routes().with_state(state.clone());
+    let settings_routes = api::settings::routes().with_state(state);
+    let app = Router::new()
+        .nest("/api/v1/httpdownload", httpdownload_routes)
+        .nest("/api/v1/p2pdownload", p2pdownload_routes)
+        .nest("/api/v1/credentials", credentials_routes)
+        .nest("/api/v1/package", package_routes)
+        .nest("/api/v1/proxy", proxy_routes)
+        .nest("/api/v1/settings", settings_routes)
+        .nest("/api/v1/ws", ws_routes);
+    log::info!("Starting REST server on {:?}", listener.local_addr());
+    axum::Server::from_tcp(listener)
+        .expect("Couldn't create server from TcpListener")
+        .serve(app.into_make_service())
+        .await
+        .expect("Server crashed");
+}
+
+async fn serve_grpc(listener: TcpListener, state: AppState) {
+    log::info!("Starting gRPC server on {:?}", listener.local_addr());
+    listener
+        .set_nonblocking(true)
+        .expect("Couldn't set gRPC listener to non-blocking");
+    let listener =
+        tokio::net::TcpListener::from_std(listener).expect("Couldn't register gRPC listener");
+    tonic::transport::Server::builder()
+        .add_service(HttpDownloadService::new(state).into_server())
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+        .expect("gRPC server crashed");
+}
+
+/// Without a `settings_path` the settings and the database are kept in `~/.ludownloader`
+pub async fn launch_app(listener: TcpListener, settings_path: Option<PathBuf>) {
+    let state = build_state(settings_path).await;
+    serve_rest(listener, state).await
+}
+
+/// Serves the REST API and the gRPC service on separate listeners, both backed by the same
+/// DownloadManager.
+pub async fn launch_app_with_grpc(
+    listener: TcpListener,
+    grpc_listener: TcpListener,
+    settings_path: Option<PathBuf>,
+) {
+    let state = build_state(settings_path).await;
+    tokio::join!(
+        serve_rest(listener, state.clone()),
+        serve_grpc(grpc_listener, state)
+    );
 }
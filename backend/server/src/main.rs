@@ -1,8 +1,9 @@
-use server::launch_app;
+use server::launch_app_with_grpc;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
     let listener = std::net::TcpListener::bind("0.0.0.0:42069").unwrap();
-    launch_app(listener).await
+    let grpc_listener = std::net::TcpListener::bind("0.0.0.0:42070").unwrap();
+    launch_app_with_grpc(listener, grpc_listener, None).await
 }
@@ -1,5 +1,5 @@
 use dirs::{download_dir, home_dir};
-use downloader::httpdownload::DownloadMetadata;
+use downloader::{hoster::token::TokenHosterConfig, proxy::ClientPoolConfig, schedule::TimeWindow};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -18,8 +18,22 @@ pub struct Settings {
     pub default_download_dir: PathBuf,
     #[serde(default)]
     pub max_concurrent_downloads: usize,
-    #[serde(default = "Vec::new")]
-    pub downloads: Vec<DownloadMetadata>,
+    /// Combined bandwidth limit of all downloads in bytes per second
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+    /// Proxies used for downloads, no proxies means direct connections
+    #[serde(default)]
+    pub proxy: ClientPoolConfig,
+    /// File hosters whose pages are resolved into direct downloads, they log in with the
+    /// account stored in the credentials for their host
+    #[serde(default)]
+    pub hosters: Vec<TokenHosterConfig>,
+    /// Weekly windows during which downloads run, at any time if empty
+    #[serde(default)]
+    pub schedule: Vec<TimeWindow>,
+    /// Queued downloads stop running downloads of a lower priority to take their slot
+    #[serde(default)]
+    pub preempt_lower_priority: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -28,6 +42,9 @@ pub struct SettingManager {
     settings_path: PathBuf,
 }
 
+const DATABASE_FILENAME: &str = "ludownloader.db";
+const CREDENTIALS_FILENAME: &str = "credentials.enc";
+
 fn default_settings_path() -> PathBuf {
     let home_dir = home_dir().unwrap_or_default();
     home_dir.join(".ludownloader/settings.yaml")
@@ -46,7 +63,17 @@ impl SettingManager {
         }
     }
 
-    pub async fn read(&self) -> RwLockReadGuard<Settings> {
+    /// The SQLite database lives in the same directory as the settings file
+    pub fn database_path(&self) -> PathBuf {
+        self.settings_path.with_file_name(DATABASE_FILENAME)
+    }
+
+    /// The encrypted credentials file lives next to the settings file as well
+    pub fn credentials_path(&self) -> PathBuf {
+        self.settings_path.with_file_name(CREDENTIALS_FILENAME)
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, Settings> {
         self.inner.read().await
     }
 
@@ -75,7 +102,11 @@ impl Default for Settings {
                 .map(|p| p.join("ludownloader"))
                 .unwrap_or_default(),
             max_concurrent_downloads: 0,
-            downloads: Vec::new(),
+            bandwidth_limit: None,
+            proxy: ClientPoolConfig::default(),
+            hosters: Vec::new(),
+            schedule: Vec::new(),
+            preempt_lower_priority: false,
         }
     }
 }
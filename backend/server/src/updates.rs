@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use downloader::httpdownload::{download, manager::PackageIndex, DownloadUpdateSubscriber};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+pub type UpdateBatch = Arc<Batch>;
+
+/// Updates flushed at once, with the packages of the updated downloads resolved once for all
+/// clients
+#[derive(Debug)]
+pub struct Batch {
+    pub updates: Vec<(Uuid, download::State)>,
+    /// Package of every updated download that belongs to one
+    pub packages: HashMap<Uuid, Uuid>,
+}
+
+const BROADCAST_CAPACITY: usize = 256;
+
+/// Fans out every batch flushed by the DownloadUpdateBuffer to any number of streaming clients.
+/// Only one instance is subscribed to the DownloadManager, clients hold a broadcast::Receiver
+/// instead, so a client that disconnects doesn't leave a dangling subscriber behind.
+#[derive(Clone)]
+pub struct UpdateBroadcast {
+    sender: broadcast::Sender<UpdateBatch>,
+    packages: PackageIndex,
+}
+
+impl UpdateBroadcast {
+    pub fn new(packages: PackageIndex) -> Self {
+        let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+        Self { sender, packages }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UpdateBatch> {
+        self.sender.subscribe()
+    }
+}
+
+#[async_trait]
+impl DownloadUpdateSubscriber for UpdateBroadcast {
+    async fn update(&self, updates: &[(Uuid, download::State)]) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let batch = Batch {
+            updates: updates.to_vec(),
+            packages: self
+                .packages
+                .packages_of(updates.iter().map(|(id, _)| id))
+                .await,
+        };
+        // Sending only fails if no client is currently listening
+        let _ = self.sender.send(Arc::new(batch));
+    }
+}
@@ -0,0 +1,139 @@
+// Every test crate compiles its own copy and uses only some of the helpers
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use server::launch_app;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use test_context::AsyncTestContext;
+
+pub const LOCAL_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Writes a settings file into `data_dir` that downloads into `data_dir` as well, downloads
+/// created without a file path would end up in the working directory otherwise
+pub fn settings_in(data_dir: &Path) -> PathBuf {
+    write_settings(data_dir, json!({}))
+}
+
+/// Like settings_in, with the fields of `settings` on top
+fn write_settings(data_dir: &Path, mut settings: Value) -> PathBuf {
+    let settings_path = data_dir.join("settings.yaml");
+    settings["default_download_dir"] = json!(data_dir);
+    std::fs::write(&settings_path, settings.to_string()).unwrap();
+    settings_path
+}
+
+/// The app running in its own data directory, see settings_in
+pub struct Ctx {
+    pub client: Client,
+    pub server_url: Url,
+    pub data_dir: TempDir,
+    pub settings_path: PathBuf,
+}
+
+impl Ctx {
+    /// Launches the app with the fields of `settings` on top of the ones of settings_in
+    pub async fn with_settings(settings: Value) -> Self {
+        let listener = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let server_url = Url::parse(&format!("http://{}", local_addr)).unwrap();
+        log::info!("Local server running on {}", server_url);
+        let data_dir = TempDir::new().unwrap();
+        let settings_path = write_settings(data_dir.path(), settings);
+        tokio::spawn(launch_app(listener, Some(settings_path.clone())));
+        Ctx {
+            client: Client::new(),
+            server_url,
+            data_dir,
+            settings_path,
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncTestContext for Ctx {
+    async fn setup() -> Self {
+        Self::with_settings(json!({})).await
+    }
+}
+
+/// Serves a single file at `/file.bin` with byte range support, so tests don't depend on remote
+/// hosts being available.
+pub fn spawn_file_server() -> Url {
+    spawn_server(None)
+}
+
+/// Like spawn_file_server, but requests without the given Authorization header are answered with
+/// 401
+pub fn spawn_protected_file_server(authorization: &'static str) -> Url {
+    spawn_server(Some(authorization))
+}
+
+fn spawn_server(authorization: Option<&'static str>) -> Url {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!(
+        "http://{}/file.bin",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+    let app = Router::new()
+        .route("/file.bin", get(serve_file))
+        .with_state(authorization);
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+    url
+}
+
+async fn serve_file(
+    State(authorization): State<Option<&'static str>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(authorization) = authorization {
+        if headers.get(header::AUTHORIZATION).map(|v| v.as_bytes())
+            != Some(authorization.as_bytes())
+        {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    let payload: Vec<u8> = (0..LOCAL_FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes="))
+        .and_then(|v| v.split_once('-'))
+        .and_then(|(start, end)| {
+            let start = start.parse::<usize>().ok()?;
+            let end = match end {
+                "" => LOCAL_FILE_SIZE,
+                end => end.parse::<usize>().ok()? + 1,
+            };
+            (start < end && end <= LOCAL_FILE_SIZE).then_some((start, end))
+        });
+    match range {
+        Some((start, end)) => (
+            StatusCode::PARTIAL_CONTENT,
+            [
+                (header::ACCEPT_RANGES, "bytes".to_string()),
+                (
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end - 1, LOCAL_FILE_SIZE),
+                ),
+            ],
+            payload[start..end].to_vec(),
+        )
+            .into_response(),
+        None => (StatusCode::OK, [(header::ACCEPT_RANGES, "bytes")], payload).into_response(),
+    }
+}
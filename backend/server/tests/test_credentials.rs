@@ -0,0 +1,138 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{spawn_protected_file_server, Ctx};
+use downloader::httpdownload::{download::State as DownloadState, DownloadMetadata};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use server::api::httpdownload::DownloadData;
+use test_context::test_context;
+use test_log::test;
+
+/// Basic auth of `user:s3cr3t`
+const AUTHORIZATION: &str = "Basic dXNlcjpzM2NyM3Q=";
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_credentials_are_applied_and_never_echoed(
+    Ctx {
+        client,
+        server_url,
+        data_dir,
+        ..
+    }: &mut Ctx,
+) {
+    let credentials_endpoint = server_url.join("/api/v1/credentials").unwrap();
+
+    let resp = client
+        .get(credentials_endpoint.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::LOCKED);
+    let resp = client
+        .post(format!("{}/unlock", credentials_endpoint))
+        .json(&json!({ "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(data_dir.path().join("credentials.enc").exists());
+
+    let file_url = spawn_protected_file_server(AUTHORIZATION);
+    let resp = client
+        .post(credentials_endpoint.clone())
+        .json(&json!({
+            "host": file_url.host_str().unwrap(),
+            "credential": { "type": "basic", "username": "user", "password": "s3cr3t" }
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let created = resp.text().await.unwrap();
+    assert!(!created.contains("s3cr3t"));
+    let created: Value = serde_json::from_str(&created).unwrap();
+    let listed = client
+        .get(credentials_endpoint.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(!listed.contains("s3cr3t"));
+    assert_eq!(
+        serde_json::from_str::<Value>(&listed).unwrap(),
+        json!([created])
+    );
+
+    let file_path = data_dir.path().join("file.bin");
+    let resp = client
+        .post(server_url.join("/api/v1/httpdownload").unwrap())
+        .json(&json!({ "url": file_url.as_str(), "file_path": file_path }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let metadata: DownloadMetadata = resp.json().await.unwrap();
+    let download_url = server_url
+        .join(&format!("/api/v1/httpdownload/{}", metadata.id))
+        .unwrap();
+    client
+        .get(format!("{}/start", download_url))
+        .send()
+        .await
+        .unwrap();
+    let mut complete = false;
+    for _ in 0..100 {
+        let data: DownloadData = client
+            .get(download_url.clone())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if matches!(data.state, DownloadState::Complete) {
+            complete = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(complete, "Download with credentials should complete");
+    let database = tokio::fs::read(data_dir.path().join("ludownloader.db"))
+        .await
+        .unwrap();
+    assert!(!String::from_utf8_lossy(&database).contains("dXNlcjpzM2NyM3Q="));
+
+    client
+        .post(format!("{}/lock", credentials_endpoint))
+        .send()
+        .await
+        .unwrap();
+    let resp = client
+        .post(format!("{}/unlock", credentials_endpoint))
+        .json(&json!({ "password": "wrong" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    client
+        .post(format!("{}/unlock", credentials_endpoint))
+        .json(&json!({ "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    let resp = client
+        .delete(format!(
+            "{}/{}",
+            credentials_endpoint,
+            created["id"].as_str().unwrap()
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+}
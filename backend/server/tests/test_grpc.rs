@@ -0,0 +1,150 @@
+mod common;
+
+use async_trait::async_trait;
+use common::{settings_in, spawn_file_server, LOCAL_FILE_SIZE};
+use server::{
+    grpc::proto::{
+        download_state, http_download_manager_client::HttpDownloadManagerClient, Checksum,
+        CreateDownloadRequest, DeleteDownloadRequest, DownloadId, ListDownloadsRequest,
+        WatchUpdatesRequest,
+    },
+    launch_app_with_grpc,
+};
+use tempfile::TempDir;
+use test_context::{test_context, AsyncTestContext};
+use test_log::test;
+use tonic::{transport::Channel, Code};
+
+struct GrpcCtx {
+    pub client: HttpDownloadManagerClient<Channel>,
+    pub tmp_dir: TempDir,
+}
+
+#[async_trait]
+impl AsyncTestContext for GrpcCtx {
+    async fn setup() -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let grpc_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let grpc_url = format!("http://{}", grpc_listener.local_addr().unwrap());
+        log::info!("Local gRPC server running on {}", grpc_url);
+        let tmp_dir = TempDir::new().unwrap();
+        let settings_path = settings_in(tmp_dir.path());
+        tokio::spawn(launch_app_with_grpc(
+            listener,
+            grpc_listener,
+            Some(settings_path),
+        ));
+        let client = HttpDownloadManagerClient::connect(grpc_url).await.unwrap();
+        GrpcCtx { client, tmp_dir }
+    }
+}
+
+#[test_context(GrpcCtx)]
+#[test(tokio::test)]
+async fn test_grpc_invalid_requests(GrpcCtx { client, .. }: &mut GrpcCtx) {
+    let status = client
+        .create(CreateDownloadRequest {
+            url: "hgesdg98wq19".to_owned(),
+            file_path: None,
+            checksum: None,
+            package_id: None,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = client
+        .create(CreateDownloadRequest {
+            url: spawn_file_server().to_string(),
+            file_path: None,
+            checksum: Some(Checksum {
+                algorithm: "crc32".to_owned(),
+                digest: "cbf43926".to_owned(),
+            }),
+            package_id: None,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = client
+        .get(DownloadId { id: vec![1, 2, 3] })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = client
+        .start(DownloadId {
+            id: uuid::Uuid::new_v4().as_bytes().to_vec(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+}
+
+#[test_context(GrpcCtx)]
+#[test(tokio::test)]
+async fn test_grpc_download_lifecycle(GrpcCtx { client, tmp_dir }: &mut GrpcCtx) {
+    let file_path = tmp_dir.path().join("file.bin");
+    let metadata = client
+        .create(CreateDownloadRequest {
+            url: spawn_file_server().to_string(),
+            file_path: Some(file_path.to_string_lossy().to_string()),
+            checksum: None,
+            package_id: None,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(metadata.download_size, Some(LOCAL_FILE_SIZE as u64));
+    assert_eq!(metadata.file_path, file_path.to_string_lossy());
+    let id = DownloadId {
+        id: metadata.id.clone(),
+    };
+
+    let mut updates = client
+        .watch_updates(WatchUpdatesRequest {
+            ids: vec![metadata.id.clone()],
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let snapshot = updates.message().await.unwrap().unwrap();
+    assert_eq!(snapshot.updates.len(), 1);
+    assert!(matches!(
+        snapshot.updates[0].state.as_ref().unwrap().state,
+        Some(download_state::State::Paused(_))
+    ));
+
+    client.start(id.clone()).await.unwrap();
+    loop {
+        let batch = updates.message().await.unwrap().unwrap();
+        let state = batch.updates.last().unwrap().state.clone().unwrap().state;
+        assert!(!matches!(state, Some(download_state::State::Error(_))));
+        if matches!(state, Some(download_state::State::Complete(_))) {
+            break;
+        }
+    }
+    assert_eq!(
+        tokio::fs::metadata(&file_path).await.unwrap().len(),
+        LOCAL_FILE_SIZE as u64
+    );
+
+    let downloads = client
+        .list(ListDownloadsRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .downloads;
+    assert_eq!(downloads.len(), 1);
+    let download = client.get(id.clone()).await.unwrap().into_inner();
+    assert_eq!(download.metadata.unwrap().id, metadata.id);
+
+    client
+        .delete(DeleteDownloadRequest {
+            id: metadata.id.clone(),
+            delete_file: true,
+        })
+        .await
+        .unwrap();
+    assert!(!file_path.exists());
+    let status = client.get(id).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+}
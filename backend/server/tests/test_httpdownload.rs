@@ -1,32 +1,16 @@
+mod common;
+
 use std::time::Duration;
 
-use async_trait::async_trait;
-use downloader::httpdownload::{download, DownloadMetadata};
+use common::{spawn_file_server, Ctx, LOCAL_FILE_SIZE};
+use downloader::httpdownload::{download, download::State as DownloadState, DownloadMetadata};
 use reqwest::{Client, StatusCode, Url};
 use serde::{Deserialize, Serialize};
-use server::launch_app;
-use test_context::{test_context, AsyncTestContext};
+use server::api::httpdownload::DownloadData;
+use test_context::test_context;
 use test_log::test;
 use uuid::Uuid;
 
-struct Ctx {
-    pub client: reqwest::Client,
-    pub server_url: Url,
-}
-
-#[async_trait]
-impl AsyncTestContext for Ctx {
-    async fn setup() -> Self {
-        let listener = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
-        let local_addr = listener.local_addr().unwrap();
-        let server_url = Url::parse(&format!("http://{}", local_addr)).unwrap();
-        log::info!("Local server running on {}", server_url);
-        let client = reqwest::Client::builder().build().unwrap();
-        tokio::spawn(launch_app(listener));
-        Ctx { client, server_url }
-    }
-}
-
 #[derive(Deserialize, Serialize)]
 struct ApiError {
     error: String,
@@ -34,7 +18,11 @@ struct ApiError {
 
 #[test_context(Ctx)]
 #[test(tokio::test)]
-async fn test_download_crud(Ctx { client, server_url }: &mut Ctx) {
+async fn test_download_crud(
+    Ctx {
+        client, server_url, ..
+    }: &mut Ctx,
+) {
     let body = "https://speed.hetzner.de/1GB.bin".to_owned();
     let resp = client
         .post(server_url.join("/api/v1/httpdownload").unwrap())
@@ -44,7 +32,7 @@ async fn test_download_crud(Ctx { client, server_url }: &mut Ctx) {
         .unwrap();
     assert_eq!(resp.status(), StatusCode::CREATED);
     let metadata: DownloadMetadata = resp.json().await.unwrap();
-    assert_eq!(metadata.download_size, 1048576000);
+    assert_eq!(metadata.download_size, Some(1048576000));
     let incorrect_url = "hgesdg98wq19".to_owned();
     let resp = client
         .post(server_url.join("/api/v1/httpdownload").unwrap())
@@ -69,7 +57,11 @@ async fn test_download_crud(Ctx { client, server_url }: &mut Ctx) {
 
 #[test_context(Ctx)]
 #[test(tokio::test)]
-async fn test_multiple_download_crud(Ctx { client, server_url }: &mut Ctx) {
+async fn test_multiple_download_crud(
+    Ctx {
+        client, server_url, ..
+    }: &mut Ctx,
+) {
     let download_url = "https://speed.hetzner.de/1GB.bin";
     for _ in 0..20 {
         let body = download_url.to_owned();
@@ -104,7 +96,11 @@ async fn test_multiple_download_crud(Ctx { client, server_url }: &mut Ctx) {
 
 #[test_context(Ctx)]
 #[test(tokio::test)]
-async fn test_download_start_stop_resume(Ctx { client, server_url }: &mut Ctx) {
+async fn test_download_start_stop_resume(
+    Ctx {
+        client, server_url, ..
+    }: &mut Ctx,
+) {
     let body =
         "https://dl.google.com/linux/direct/google-chrome-stable_current_amd64.deb".to_owned();
     let resp = client
@@ -136,15 +132,406 @@ async fn test_download_start_stop_resume(Ctx { client, server_url }: &mut Ctx) {
         data.state
     }
 
-    let mut state = fetch_state(&client, &update_endpoint).await;
+    let mut state = fetch_state(client, &update_endpoint).await;
     while matches!(
         state,
         DownloadState::Running { .. } | DownloadState::Paused(_)
     ) {
         tokio::time::sleep(Duration::from_millis(500)).await;
-        state = fetch_state(&client, &update_endpoint).await;
+        state = fetch_state(client, &update_endpoint).await;
+    }
+
+    state = fetch_state(client, &update_endpoint).await;
+    assert!(matches!(state, DownloadState::Complete));
+}
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_local_download_lifecycle(
+    Ctx {
+        client, server_url, ..
+    }: &mut Ctx,
+) {
+    let file_url = spawn_file_server();
+    let resp = client
+        .post(server_url.join("/api/v1/httpdownload").unwrap())
+        .body(file_url.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let metadata: DownloadMetadata = resp.json().await.unwrap();
+    assert_eq!(metadata.download_size, Some(LOCAL_FILE_SIZE as u64));
+
+    let download_endpoint = server_url
+        .join(format!("/api/v1/httpdownload/{}", metadata.id).as_ref())
+        .unwrap();
+    let resp = client
+        .get(format!("{}/start", download_endpoint))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let mut data: DownloadData = client
+        .get(download_endpoint.clone())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    while !matches!(data.state, DownloadState::Complete) {
+        assert!(!matches!(data.state, DownloadState::Error(_)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        data = client
+            .get(download_endpoint.clone())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        tokio::fs::metadata(&metadata.file_path)
+            .await
+            .unwrap()
+            .len(),
+        LOCAL_FILE_SIZE as u64
+    );
+
+    let resp = client
+        .delete(format!("{}?delete_file=true", download_endpoint))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(!metadata.file_path.exists());
+    let resp = client.get(download_endpoint).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: ApiError = resp.json().await.unwrap();
+    assert!(body.error.contains("does not exist"));
+}
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_checksum_mismatch(
+    Ctx {
+        client,
+        server_url,
+        data_dir,
+        ..
+    }: &mut Ctx,
+) {
+    let file_url = spawn_file_server();
+    let create_endpoint = server_url.join("/api/v1/httpdownload").unwrap();
+    let file_path = data_dir.path().join("file.bin");
+    let resp = client
+        .post(create_endpoint.clone())
+        .json(&serde_json::json!({
+            "url": file_url.as_str(),
+            "file_path": file_path,
+            "checksum": { "algorithm": "sha256", "digest": "not-a-digest" },
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = client
+        .post(create_endpoint)
+        .json(&serde_json::json!({
+            "url": file_url.as_str(),
+            "file_path": file_path,
+            "checksum": { "algorithm": "sha256", "digest": "0".repeat(64) },
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let metadata: DownloadMetadata = resp.json().await.unwrap();
+    assert_eq!(metadata.file_path, file_path);
+    let download_endpoint = server_url
+        .join(format!("/api/v1/httpdownload/{}", metadata.id).as_ref())
+        .unwrap();
+    client
+        .get(format!("{}/start", download_endpoint))
+        .send()
+        .await
+        .unwrap();
+    let error = loop {
+        let data: DownloadData = client
+            .get(download_endpoint.clone())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        match data.state {
+            DownloadState::Error(error) => break error,
+            DownloadState::Complete => panic!("Download with a wrong checksum must not complete"),
+            _ => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    };
+    assert!(error.contains("Checksum mismatch"));
+}
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_file_collisions(
+    Ctx {
+        client,
+        server_url,
+        data_dir,
+        ..
+    }: &mut Ctx,
+) {
+    let file_url = spawn_file_server();
+    let create_endpoint = server_url.join("/api/v1/httpdownload").unwrap();
+    let file_path = data_dir.path().join("file.bin");
+    std::fs::write(&file_path, b"existing").unwrap();
+    let resp = client
+        .post(create_endpoint.clone())
+        .json(&serde_json::json!({ "url": file_url.as_str(), "file_path": file_path }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let metadata: DownloadMetadata = resp.json().await.unwrap();
+    assert_eq!(metadata.file_path, data_dir.path().join("file (1).bin"));
+
+    let resp = client
+        .post(create_endpoint)
+        .json(&serde_json::json!({
+            "url": file_url.as_str(),
+            "file_path": file_path,
+            "on_collision": "skip",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+}
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_queue_priorities(
+    Ctx {
+        client,
+        server_url,
+        data_dir,
+        ..
+    }: &mut Ctx,
+) {
+    let settings_endpoint = server_url.join("/api/v1/settings").unwrap();
+    let mut settings: serde_json::Value = client
+        .get(settings_endpoint.clone())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    settings["max_concurrent_downloads"] = serde_json::json!(1);
+    // Keeps the first download running for the whole test
+    settings["bandwidth_limit"] = serde_json::json!(256 * 1024);
+    let resp = client
+        .put(settings_endpoint)
+        .json(&settings)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let file_url = spawn_file_server();
+    let mut ids = Vec::new();
+    for i in 0..3 {
+        let metadata: DownloadMetadata = client
+            .post(server_url.join("/api/v1/httpdownload").unwrap())
+            .json(&serde_json::json!({
+                "url": file_url.as_str(),
+                "file_path": data_dir.path().join(format!("file-{}.bin", i)),
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let resp = client
+            .get(
+                server_url
+                    .join(&format!("/api/v1/httpdownload/{}/start", metadata.id))
+                    .unwrap(),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        ids.push(metadata.id);
     }
+    let download_endpoint =
+        |id: &Uuid, path: &str| server_url.join(&format!("/api/v1/httpdownload/{}/{}", id, path));
+    let resp = client
+        .put(download_endpoint(&ids[2], "priority").unwrap())
+        .json(&serde_json::json!({ "priority": 1 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(get_queue(client, server_url).await, vec![ids[2], ids[1]]);
 
-    state = fetch_state(&client, &update_endpoint).await;
-    assert!(matches!(state, DownloadState::Complete(_)));
+    let resp = client
+        .put(download_endpoint(&ids[1], "queue-position").unwrap())
+        .json(&serde_json::json!({ "position": "before", "id": ids[2] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(get_queue(client, server_url).await, vec![ids[1], ids[2]]);
+    let priority: serde_json::Value = client
+        .get(download_endpoint(&ids[1], "priority").unwrap())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(priority["priority"], 1);
+
+    let resp = client
+        .put(download_endpoint(&ids[0], "queue-position").unwrap())
+        .json(&serde_json::json!({ "position": "top" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+async fn get_queue(client: &Client, server_url: &Url) -> Vec<Uuid> {
+    client
+        .get(server_url.join("/api/v1/httpdownload/queue").unwrap())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+/// Reads Server-Sent Events from a response, comments like keep-alives are skipped
+struct EventReader {
+    resp: reqwest::Response,
+    buffer: String,
+}
+
+impl EventReader {
+    async fn next(&mut self) -> (String, Vec<(Uuid, DownloadState)>) {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let block: String = self.buffer.drain(..end + 2).collect();
+                let mut event = String::new();
+                let mut data = String::new();
+                for line in block.lines() {
+                    if let Some(name) = line.strip_prefix("event:") {
+                        event = name.trim().to_string();
+                    } else if let Some(line) = line.strip_prefix("data:") {
+                        data.push_str(line.trim());
+                    }
+                }
+                if !data.is_empty() {
+                    return (event, serde_json::from_str(&data).unwrap());
+                }
+                continue;
+            }
+            let chunk = self
+                .resp
+                .chunk()
+                .await
+                .unwrap()
+                .expect("event stream ended");
+            self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+}
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_event_stream(
+    Ctx {
+        client, server_url, ..
+    }: &mut Ctx,
+) {
+    let file_url = spawn_file_server();
+    let create_endpoint = server_url.join("/api/v1/httpdownload").unwrap();
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let metadata: DownloadMetadata = client
+            .post(create_endpoint.clone())
+            .body(file_url.to_string())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        ids.push(metadata.id);
+    }
+    let events_endpoint = server_url.join("/api/v1/httpdownload/events").unwrap();
+    let resp = client
+        .get(events_endpoint.clone())
+        .query(&[("ids", ids[0].to_string())])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let mut events = EventReader {
+        resp,
+        buffer: String::new(),
+    };
+    let (event, snapshot) = events.next().await;
+    assert_eq!(event, "snapshot");
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].0, ids[0]);
+    assert!(matches!(snapshot[0].1, DownloadState::Paused(0)));
+
+    for id in ids.iter() {
+        let resp = client
+            .get(format!("{}/{}/start", create_endpoint, id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    tokio::time::timeout(Duration::from_secs(30), async {
+        loop {
+            let (event, updates) = events.next().await;
+            assert_eq!(event, "updates");
+            assert!(updates.iter().all(|(id, _)| *id == ids[0]));
+            if updates
+                .iter()
+                .any(|(_, state)| matches!(state, DownloadState::Complete))
+            {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    let resp = client
+        .get(events_endpoint.clone())
+        .query(&[("ids", "not-an-id")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = client
+        .get(events_endpoint)
+        .query(&[("package_id", Uuid::new_v4().to_string())])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
 }
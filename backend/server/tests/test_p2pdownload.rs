@@ -0,0 +1,84 @@
+mod common;
+
+use common::Ctx;
+use downloader::httpdownload::DownloadMetadata;
+use downloader::p2pdownload::metainfo::Metainfo;
+use reqwest::StatusCode;
+use server::api::httpdownload::DownloadData;
+use test_context::test_context;
+use test_log::test;
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_torrent_creation(
+    Ctx {
+        client,
+        server_url,
+        data_dir,
+        ..
+    }: &mut Ctx,
+) {
+    let endpoint = server_url.join("/api/v1/p2pdownload").unwrap();
+
+    let magnet = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=debian.iso";
+    let resp = client
+        .post(endpoint.clone())
+        .query(&[("directory", data_dir.path().to_str().unwrap())])
+        .body(magnet)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let metadata: DownloadMetadata = resp.json().await.unwrap();
+    assert_eq!(metadata.file_path, data_dir.path().join("debian.iso"));
+    assert_eq!(metadata.download_size, None);
+    assert!(metadata.url.starts_with("magnet:?xt=urn:btih:c12fe1c0"));
+
+    // Torrents are tracked like every other download
+    let resp = client
+        .get(
+            server_url
+                .join(&format!("/api/v1/httpdownload/{}", metadata.id))
+                .unwrap(),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let data: DownloadData = resp.json().await.unwrap();
+    assert_eq!(data.metadata.id, metadata.id);
+
+    let source = data_dir.path().join("file.bin");
+    std::fs::write(&source, vec![7u8; 40_000]).unwrap();
+    let torrent = Metainfo::create(&source, 16 * 1024, Vec::new())
+        .unwrap()
+        .to_bytes()
+        .unwrap();
+    let resp = client
+        .post(endpoint.clone())
+        .query(&[("seed_ratio", "1.5")])
+        .body(torrent.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let metadata: DownloadMetadata = resp.json().await.unwrap();
+    assert_eq!(metadata.download_size, Some(40_000));
+
+    // A single file torrent has no file 1
+    let resp = client
+        .post(endpoint.clone())
+        .query(&[("files", "1")])
+        .body(torrent)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = client
+        .post(endpoint)
+        .body("not a torrent")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
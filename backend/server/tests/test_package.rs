@@ -0,0 +1,127 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{spawn_file_server, Ctx, LOCAL_FILE_SIZE};
+use downloader::httpdownload::{package::Package, DownloadMetadata};
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+use server::api::package::PackageData;
+use test_context::test_context;
+use test_log::test;
+use uuid::Uuid;
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_package_lifecycle(
+    Ctx {
+        client,
+        server_url,
+        data_dir,
+        ..
+    }: &mut Ctx,
+) {
+    let package_endpoint = server_url.join("/api/v1/package").unwrap();
+    let download_endpoint = server_url.join("/api/v1/httpdownload").unwrap();
+
+    let package_dir = data_dir.path().join("archive");
+    let resp = client
+        .post(package_endpoint.clone())
+        .json(&json!({ "name": "archive", "directory": package_dir }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let package: Package = resp.json().await.unwrap();
+    assert_eq!(package.directory, package_dir);
+
+    let file_url = spawn_file_server();
+    let mut download_ids = Vec::new();
+    // The first download defaults to the package directory
+    let requests = [
+        json!({ "url": file_url.as_str(), "package_id": package.id }),
+        json!({
+            "url": file_url.as_str(),
+            "file_path": package_dir.join("other.bin"),
+            "package_id": package.id
+        }),
+    ];
+    for request in requests {
+        let resp = client
+            .post(download_endpoint.clone())
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let metadata: DownloadMetadata = resp.json().await.unwrap();
+        assert_eq!(metadata.file_path.parent().unwrap(), package_dir);
+        download_ids.push(metadata.id);
+    }
+    let resp = client
+        .post(download_endpoint.clone())
+        .json(&json!({ "url": file_url.as_str(), "package_id": Uuid::new_v4() }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let packages: Vec<Package> = client
+        .get(package_endpoint.clone())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].download_ids.len(), 2);
+
+    let package_url = Url::parse(&format!("{}/{}", package_endpoint, package.id)).unwrap();
+    let resp = client
+        .get(format!("{}/start", package_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let mut progress = None;
+    for _ in 0..100 {
+        let data: PackageData = client
+            .get(package_url.clone())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if data.progress.complete == 2 {
+            progress = Some(data.progress);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let progress = progress.expect("package should complete");
+    assert_eq!(progress.total_bytes, Some(2 * LOCAL_FILE_SIZE as u64));
+    assert_eq!(progress.bytes_downloaded, 2 * LOCAL_FILE_SIZE as u64);
+
+    let resp = client
+        .delete(format!("{}?delete_file=true", package_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    for id in download_ids {
+        let resp = client
+            .get(
+                download_endpoint
+                    .join(&format!("httpdownload/{}", id))
+                    .unwrap(),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    let resp = client.get(package_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
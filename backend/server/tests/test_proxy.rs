@@ -0,0 +1,134 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{spawn_file_server, Ctx};
+use downloader::{
+    httpdownload::{download::State as DownloadState, DownloadMetadata},
+    proxy::ProxyStatus,
+};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use server::api::{httpdownload::DownloadData, proxy::ProxyAssignment};
+use test_context::test_context;
+use test_log::test;
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_downloads_use_configured_proxy(
+    Ctx {
+        client,
+        server_url,
+        data_dir,
+        ..
+    }: &mut Ctx,
+) {
+    let settings_endpoint = server_url.join("/api/v1/settings").unwrap();
+    let proxy_endpoint = server_url.join("/api/v1/proxy").unwrap();
+
+    // The file server answers the absolute-form requests a plain HTTP proxy receives
+    let proxy_url = spawn_file_server();
+    let mut settings: Value = client
+        .get(settings_endpoint.clone())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    settings["proxy"]["proxies"] = json!([{ "name": "invalid", "url": "ftp://127.0.0.1:21" }]);
+    let resp = client
+        .put(settings_endpoint.clone())
+        .json(&settings)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    settings["proxy"]["proxies"] = json!([{
+        "name": "local",
+        "url": format!("http://{}", proxy_url.authority())
+    }]);
+    settings["proxy"]["health_check_url"] = json!("http://ludownloader.invalid/file.bin");
+    let resp = client
+        .put(settings_endpoint)
+        .json(&settings)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let status: Vec<ProxyStatus> = client
+        .post(format!("{}/health-check", proxy_endpoint))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(status.len(), 1);
+    assert!(status[0].healthy);
+
+    let file_path = data_dir.path().join("file.bin");
+    let metadata: DownloadMetadata = client
+        .post(server_url.join("/api/v1/httpdownload").unwrap())
+        .json(&json!({
+            "url": "http://ludownloader.invalid/file.bin",
+            "file_path": file_path
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let download_proxy_url = format!("{}/download/{}", proxy_endpoint, metadata.id);
+    let resp = client
+        .put(&download_proxy_url)
+        .json(&json!({ "proxy": "unknown" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = client
+        .put(&download_proxy_url)
+        .json(&json!({ "proxy": "local" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let download_url = server_url
+        .join(&format!("/api/v1/httpdownload/{}", metadata.id))
+        .unwrap();
+    client
+        .get(format!("{}/start", download_url))
+        .send()
+        .await
+        .unwrap();
+    let mut complete = false;
+    for _ in 0..100 {
+        let data: DownloadData = client
+            .get(download_url.clone())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if matches!(data.state, DownloadState::Complete) {
+            complete = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(complete, "Download through the proxy should complete");
+    let assignment: ProxyAssignment = client
+        .get(&download_proxy_url)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(assignment.proxy.as_deref(), Some("local"));
+}
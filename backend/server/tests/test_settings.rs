@@ -0,0 +1,85 @@
+mod common;
+
+use common::Ctx;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use test_log::test;
+use uuid::Uuid;
+
+#[test(tokio::test)]
+async fn test_update_settings() {
+    let ctx = Ctx::with_settings(json!({ "max_concurrent_downloads": 1 })).await;
+    let Ctx {
+        client,
+        server_url,
+        settings_path,
+        ..
+    } = &ctx;
+    let settings_endpoint = server_url.join("/api/v1/settings").unwrap();
+
+    let mut settings: Value = client
+        .get(settings_endpoint.clone())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(settings["max_concurrent_downloads"], json!(1));
+
+    settings["max_concurrent_downloads"] = json!(2);
+    settings["bandwidth_limit"] = json!(1024 * 1024);
+    let resp = client
+        .put(settings_endpoint.clone())
+        .json(&settings)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let updated: Value = client
+        .get(settings_endpoint.clone())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(updated, settings);
+    let settings_file = tokio::fs::read_to_string(settings_path).await.unwrap();
+    assert!(settings_file.contains("max_concurrent_downloads: 2"));
+
+    let mut invalid = settings.clone();
+    invalid["hosters"] = json!([{ "hosts": ["hoster.example"], "api": "not a url" }]);
+    let resp = client
+        .put(settings_endpoint.clone())
+        .json(&invalid)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let queue: Vec<Uuid> = client
+        .get(server_url.join("/api/v1/httpdownload/queue").unwrap())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(queue.is_empty());
+
+    let resp = client
+        .put(
+            server_url
+                .join(&format!(
+                    "/api/v1/httpdownload/{}/bandwidth-limit",
+                    Uuid::new_v4()
+                ))
+                .unwrap(),
+        )
+        .json(&json!({ "bytes_per_second": 1000 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
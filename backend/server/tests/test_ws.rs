@@ -0,0 +1,145 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{spawn_file_server, Ctx, LOCAL_FILE_SIZE};
+use downloader::httpdownload::download::State as DownloadState;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use test_context::test_context;
+use test_log::test;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn send(socket: &mut Socket, message: Value) {
+    socket
+        .send(Message::Text(message.to_string()))
+        .await
+        .unwrap();
+}
+
+async fn receive(socket: &mut Socket) -> Value {
+    loop {
+        let message = tokio::time::timeout(Duration::from_secs(30), socket.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+/// Skips update messages until the response to the command with `id` arrives
+async fn response(socket: &mut Socket, id: Value) -> Value {
+    loop {
+        let message = receive(socket).await;
+        if message["type"] == "response" && message["id"] == id {
+            return message;
+        }
+    }
+}
+
+#[test_context(Ctx)]
+#[test(tokio::test)]
+async fn test_websocket_commands_and_updates(
+    Ctx {
+        server_url,
+        data_dir,
+        ..
+    }: &mut Ctx,
+) {
+    let file_url = spawn_file_server();
+    let (mut socket, _) = connect_async(format!("ws://{}/api/v1/ws", server_url.authority()))
+        .await
+        .unwrap();
+
+    send(&mut socket, json!({"id": "bad", "command": "fly"})).await;
+    let message = response(&mut socket, json!("bad")).await;
+    assert!(message["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Invalid message"));
+
+    send(
+        &mut socket,
+        json!({
+            "id": 1,
+            "command": "add",
+            "url": file_url.to_string(),
+            "file_path": data_dir.path().join("file.bin"),
+        }),
+    )
+    .await;
+    let message = response(&mut socket, json!(1)).await;
+    let download_id: Uuid = serde_json::from_value(message["result"]["id"].clone()).unwrap();
+    assert_eq!(message["result"]["download_size"], json!(LOCAL_FILE_SIZE));
+
+    send(
+        &mut socket,
+        json!({"id": 2, "command": "subscribe", "ids": [download_id]}),
+    )
+    .await;
+    let message = response(&mut socket, json!(2)).await;
+    let snapshot: Vec<(Uuid, DownloadState)> =
+        serde_json::from_value(message["result"].clone()).unwrap();
+    assert_eq!(snapshot.len(), 1);
+    assert!(matches!(snapshot[0].1, DownloadState::Paused(0)));
+
+    send(
+        &mut socket,
+        json!({"id": 3, "command": "start", "download_id": download_id}),
+    )
+    .await;
+    let message = response(&mut socket, json!(3)).await;
+    assert_eq!(message["result"], Value::Null);
+    loop {
+        let message = receive(&mut socket).await;
+        if message["type"] != "updates" {
+            continue;
+        }
+        let updates: Vec<(Uuid, DownloadState)> =
+            serde_json::from_value(message["updates"].clone()).unwrap();
+        assert!(updates.iter().all(|(id, _)| *id == download_id));
+        if updates
+            .iter()
+            .any(|(_, state)| matches!(state, DownloadState::Complete))
+        {
+            break;
+        }
+    }
+
+    send(
+        &mut socket,
+        json!({"id": "unsubscribe", "command": "unsubscribe"}),
+    )
+    .await;
+    response(&mut socket, json!("unsubscribe")).await;
+    send(
+        &mut socket,
+        json!({"id": 4, "command": "stop", "download_id": Uuid::new_v4()}),
+    )
+    .await;
+    // Batches still queued for the subscription are not delivered anymore
+    let message = receive(&mut socket).await;
+    assert_eq!(message["type"], "response");
+    assert_eq!(message["id"], json!(4));
+    assert!(message["error"]
+        .as_str()
+        .unwrap()
+        .contains("does not exist"));
+
+    send(
+        &mut socket,
+        json!({"id": 5, "command": "delete", "download_id": download_id, "delete_file": true}),
+    )
+    .await;
+    let message = response(&mut socket, json!(5)).await;
+    assert_eq!(message["result"], Value::Null);
+    assert!(!data_dir.path().join("file.bin").exists());
+    socket.close(None).await.unwrap();
+}